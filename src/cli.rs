@@ -1,34 +1,300 @@
-use std::path::PathBuf;
+use structopt::clap::Shell;
 use structopt::StructOpt;
 
+use crate::cloud::UploadTarget;
+use crate::controller::LIST_FIELDS;
+use crate::fleet::TargetList;
+use crate::hook::Hook;
+use crate::http::HttpUrl;
+use crate::mavftp::{MavlinkFtpOpcode, MAX_DATA_SIZE};
+use crate::params::PARAM_PATH;
+use crate::receipt::VerifyMode;
+use crate::template::Template;
+use strum::IntoEnumIterator;
+
 #[derive(Debug, StructOpt)]
-#[structopt(name = "mavlink_ftp_cli", about = "Mavlink FTP CLI")]
+#[structopt(
+    name = "mavlink_ftp_cli",
+    about = "Mavlink FTP CLI",
+    after_help = "Several commands can run one after the other over the same connection, as in\n`list /APM get /APM/LOGS/1.BIN crc /APM/LOGS/1.BIN`; they stop at the first failure.\nA command starts at a command name once the one before it is complete, use ./list for a\npath named like one."
+)]
 pub struct Opt {
     #[structopt(
         long = "connection",
+        env = "MAVFTP_URL",
         default_value = "tcpout:0.0.0.0:5760",
         help = "Connection string"
     )]
     pub connection: String,
 
+    #[structopt(
+        long = "link",
+        value_name = "link",
+        number_of_values = 1,
+        help = "Connection string of a redundant link, instead of --connection; with several, transfers move to another when the active one goes quiet"
+    )]
+    pub links: Vec<String>,
+
+    #[structopt(
+        long = "source-system",
+        env = "MAVFTP_SOURCE_SYSTEM",
+        default_value = "1",
+        help = "MAVLink system ID used by this tool"
+    )]
+    pub source_system: u8,
+
+    #[structopt(
+        long = "source-component",
+        env = "MAVFTP_SOURCE_COMPONENT",
+        default_value = "0",
+        help = "MAVLink component ID used by this tool"
+    )]
+    pub source_component: u8,
+
+    #[structopt(
+        long = "target-system",
+        env = "MAVFTP_TARGET_SYSTEM",
+        default_value = "1",
+        help = "MAVLink system ID of the vehicle"
+    )]
+    pub target_system: u8,
+
+    #[structopt(
+        long = "target-component",
+        env = "MAVFTP_TARGET_COMPONENT",
+        default_value = "1",
+        help = "MAVLink component ID of the autopilot"
+    )]
+    pub target_component: u8,
+
+    #[structopt(
+        long = "target-network",
+        env = "MAVFTP_TARGET_NETWORK",
+        default_value = "0",
+        help = "MAVLink network ID of the autopilot, replies to another network are ignored"
+    )]
+    pub target_network: u8,
+
+    #[structopt(
+        long = "connect-timeout",
+        env = "MAVFTP_CONNECT_TIMEOUT",
+        default_value = "10",
+        help = "Seconds to wait for the first heartbeat of the vehicle, 0 waits forever"
+    )]
+    pub connect_timeout: u64,
+
+    #[structopt(
+        long = "max-duration",
+        env = "MAVFTP_MAX_DURATION",
+        parse(try_from_str = parse_duration),
+        help = "Stop everything after this long, e.g. 90, 120s or 10m, and exit with an error"
+    )]
+    pub max_duration: Option<std::time::Duration>,
+
+    #[structopt(
+        long = "chunk-size",
+        env = "MAVFTP_CHUNK_SIZE",
+        default_value = "239",
+        parse(try_from_str = parse_chunk_size),
+        help = "Data bytes per FTP message for reads and writes (1-239)"
+    )]
+    pub chunk_size: usize,
+
+    #[structopt(
+        long = "receipt",
+        env = "MAVFTP_RECEIPT",
+        parse(from_os_str),
+        help = "Receipt file with the local and remote CRC32 of each transfer"
+    )]
+    pub receipt: Option<std::path::PathBuf>,
+
+    #[structopt(
+        long = "verify",
+        env = "MAVFTP_VERIFY",
+        possible_values = &["none", "size", "crc32"],
+        help = "Check each transferred file against the vehicle by its CRC32, only its size, or not at all; by default by its CRC32, or its size when the vehicle can't calculate it"
+    )]
+    pub verify: Option<VerifyMode>,
+
+    #[structopt(
+        long = "exec-after",
+        env = "MAVFTP_EXEC_AFTER",
+        help = "Command run after each transferred file is verified, without a shell; {local}, {remote}, {crc} and {sha256} are replaced in its words"
+    )]
+    pub exec_after: Option<Hook>,
+
+    #[structopt(
+        long = "upload-to",
+        env = "MAVFTP_UPLOAD_TO",
+        help = "Upload each verified download to s3://bucket/prefix or with a PUT to an https:// URL"
+    )]
+    pub upload_to: Option<UploadTarget>,
+
+    #[structopt(
+        long = "allow-http-upload",
+        requires = "upload-to",
+        help = "Let --upload-to go to an http:// endpoint, without TLS"
+    )]
+    pub allow_http_upload: bool,
+
+    #[structopt(
+        long = "remove-uploaded",
+        requires = "upload-to",
+        help = "Remove the local copy of each download once it's uploaded"
+    )]
+    pub remove_uploaded: bool,
+
+    #[structopt(
+        long = "write-checksums",
+        help = "Write a <file>.crc32 next to each download, uploads are checked against theirs"
+    )]
+    pub write_checksums: bool,
+
+    #[structopt(
+        long = "sha256",
+        help = "Also calculate the SHA-256 of each transferred file, for receipts and notifications"
+    )]
+    pub sha256: bool,
+
+    #[structopt(
+        long = "si",
+        conflicts_with_all = &["binary", "bytes"],
+        help = "Print sizes with powers of 1000, as kB and MB"
+    )]
+    pub si: bool,
+
+    #[structopt(
+        long = "binary",
+        conflicts_with = "bytes",
+        help = "Print sizes with powers of 1024, as KiB and MiB, the default"
+    )]
+    pub binary: bool,
+
+    #[structopt(long = "bytes", help = "Print sizes as a number of bytes")]
+    pub bytes: bool,
+
+    #[structopt(
+        long = "no-color",
+        help = "Don't color the listings, as when NO_COLOR is set or the output is not a terminal"
+    )]
+    pub no_color: bool,
+
+    #[structopt(
+        long = "notify-url",
+        env = "MAVFTP_NOTIFY_URL",
+        help = "Post JSON progress and completion events to this https:// or http:// URL"
+    )]
+    pub notify_url: Option<HttpUrl>,
+
+    #[structopt(
+        long = "no-flow-control",
+        help = "Don't slow the requests down when RADIO_STATUS or RADIO tells the radio buffer is filling up"
+    )]
+    pub no_flow_control: bool,
+
+    #[structopt(
+        long = "concurrent",
+        conflicts_with = "receipt",
+        help = "Start the chained commands together, those that open files still one at a time"
+    )]
+    pub concurrent: bool,
+
+    #[structopt(
+        long = "latency-stats",
+        help = "Print the round trip time distribution of the FTP requests when done"
+    )]
+    pub latency_stats: bool,
+
+    #[structopt(
+        long = "metrics-csv",
+        parse(from_os_str),
+        env = "MAVFTP_METRICS_CSV",
+        help = "Append a row per operation to this CSV file, with its size, duration and resends"
+    )]
+    pub metrics_csv: Option<std::path::PathBuf>,
+
+    #[structopt(
+        long = "targets",
+        help = "Run the commands against each of these system IDs, e.g. 1,2,5 or 1-12"
+    )]
+    pub targets: Option<TargetList>,
+
+    #[structopt(
+        long = "targets-file",
+        parse(from_os_str),
+        conflicts_with = "targets",
+        help = "File with the system IDs to run the commands against, one list per line"
+    )]
+    pub targets_file: Option<std::path::PathBuf>,
+
+    #[structopt(
+        long = "parallel",
+        help = "Work on every vehicle of --targets at the same time, the connection must allow it"
+    )]
+    pub parallel: bool,
+
     #[structopt(subcommand)]
     pub command: MavlinkFTPCommand,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, StructOpt)]
 pub enum MavlinkFTPCommand {
     /// List files in a directory
-    #[structopt(name = "list")]
+    #[structopt(name = "list", alias = "ls")]
     ListDirectory {
+        /// Show the full remote path of each entry instead of its name
+        #[structopt(long)]
+        full_path: bool,
+        /// Print the entries as JSON, with the raw bytes of names that are not UTF-8 in base64
+        #[structopt(long)]
+        json: bool,
+        /// Print the entries as each page of the listing arrives, unsorted, one JSON object per
+        /// line with --json
+        #[structopt(long)]
+        stream: bool,
+        /// Output format: table, json as --json, ndjson as --json --stream, or a template of each
+        /// line such as '{type}\t{size}\t{name}', with {type}, {name}, {path}, {size} and {bytes}
+        #[structopt(long, conflicts_with = "json")]
+        format: Option<ListFormat>,
         /// Directory path
         #[structopt(default_value = ".")]
         path: String,
     },
     /// Read a file
-    #[structopt(name = "read")]
+    #[structopt(name = "read", alias = "get")]
     ReadFile {
-        /// File path
-        path: String,
+        /// Directory where the file is saved, the current one by default
+        #[structopt(long, parse(from_os_str))]
+        output_dir: Option<std::path::PathBuf>,
+        /// Write the download through a memory map of the preallocated file
+        #[structopt(long)]
+        mmap: bool,
+        /// Sync the downloaded data to the disk every time this many bytes are written
+        #[structopt(long)]
+        fsync_every: Option<u32>,
+        /// Overwrite the local file if it already exists
+        #[structopt(short = "f", long = "force")]
+        force: bool,
+        /// Download directories with everything in them, keeping their tree in the output
+        /// directory
+        #[structopt(short = "r", long = "recursive")]
+        recursive: bool,
+        /// Go on with an interrupted download of the same directory or files, from the file and
+        /// offset it stopped at
+        #[structopt(long = "continue")]
+        continue_tree: bool,
+        /// Skip files whose local copy already has the size of the remote one, others are
+        /// downloaded again over the local copy
+        #[structopt(long)]
+        skip_existing: bool,
+        /// With --skip-existing, also compare the CRC32 of the vehicle with the one of the local
+        /// copy before skipping it
+        #[structopt(long, requires = "skip-existing")]
+        compare_crc: bool,
+        /// File paths, downloaded one after the other over the same connection
+        #[structopt(required = true, min_values = 1)]
+        paths: Vec<String>,
     },
     /// Create a file
     #[structopt(name = "create")]
@@ -36,37 +302,747 @@ pub enum MavlinkFTPCommand {
         /// File path
         path: String,
     },
-    /// Write to a file
-    #[structopt(name = "write")]
+    /// Upload local files to the vehicle
+    #[structopt(name = "write", alias = "put")]
     WriteFile {
-        /// File path
-        path: String,
+        /// Overwrite remote files that already exist
+        #[structopt(short = "f", long = "force")]
+        force: bool,
+        /// Reboot the vehicle once the files are uploaded, so new Lua scripts and parameter
+        /// files are loaded
+        #[structopt(long)]
+        reboot: bool,
+        /// Reboot without asking for confirmation on a terminal
+        #[structopt(short = "y", long = "yes", requires = "reboot")]
+        yes: bool,
+        /// Restart the Lua scripts once the files are uploaded, so new scripts run without a
+        /// reboot of the vehicle
+        #[structopt(long, conflicts_with = "reboot")]
+        restart_scripts: bool,
+        /// Local files followed by the remote path, a directory when uploading more than one file.
+        /// A local path of - uploads the standard input to the remote file
+        #[structopt(required = true, min_values = 2)]
+        paths: Vec<String>,
     },
     /// Remove a file
-    #[structopt(name = "remove")]
+    #[structopt(name = "remove", alias = "rm")]
     RemoveFile {
+        /// Remove a directory and everything inside it
+        #[structopt(short = "r", long = "recursive")]
+        recursive: bool,
+        /// Don't ask for confirmation on a terminal
+        #[structopt(short = "y", long = "yes")]
+        yes: bool,
         /// File path
         path: String,
     },
     /// Create a directory
     #[structopt(name = "mkdir")]
     CreateDirectory {
+        /// Create the parent directories as needed, existing ones are not an error
+        #[structopt(short = "p", long = "parents")]
+        parents: bool,
         /// Directory path
         path: String,
     },
     /// Remove a directory
     #[structopt(name = "rmdir")]
     RemoveDirectory {
+        /// Don't ask for confirmation on a terminal
+        #[structopt(short = "y", long = "yes")]
+        yes: bool,
         /// Directory path
         path: String,
     },
+    /// Copy a file to another path on the vehicle
+    #[structopt(name = "cp")]
+    Copy {
+        /// Source file path
+        source: String,
+        /// Destination file path
+        destination: String,
+    },
     /// Calculate CRC32 for a file
     #[structopt(name = "crc")]
     CalcFileCRC32 {
         /// File path
         path: String,
     },
+    /// Print the lines of a file that match a pattern
+    #[structopt(name = "grep")]
+    Grep {
+        /// Regular expression
+        pattern: regex::Regex,
+        /// File path
+        path: String,
+    },
+    /// Print the content of a file as hexadecimal and ASCII
+    #[structopt(name = "hexdump")]
+    Hexdump {
+        /// File path
+        path: String,
+        /// First byte to print
+        #[structopt(long, default_value = "0")]
+        offset: u32,
+        /// Number of bytes to print, everything until the end of the file by default
+        #[structopt(long)]
+        length: Option<u32>,
+    },
+    /// Work with the parameters of the vehicle, read at once from @PARAM/param.pck
+    #[structopt(name = "params")]
+    Params(ParamsCommand),
+    /// Download the flight logs of the vehicle
+    #[structopt(name = "logs")]
+    Logs(LogsCommand),
+    /// Keep downloading the new files of a remote directory, for a vehicle that stays on
+    #[structopt(name = "daemon")]
+    Daemon {
+        /// Remote directory and the local one it's mirrored in, keeping its tree as read -r does
+        #[structopt(long, required = true, number_of_values = 2, value_names = &["remote", "local"])]
+        sync: Vec<String>,
+        /// Time between two passes, e.g. 90, 120s, 10m or 1h
+        #[structopt(long, default_value = "10m", parse(try_from_str = parse_duration))]
+        every: std::time::Duration,
+        /// Also compare the CRC32 of files whose local copy has the remote size
+        #[structopt(long)]
+        compare_crc: bool,
+        /// File keeping the schedule between runs, .mavftp-sync in the local directory by default
+        #[structopt(long, parse(from_os_str))]
+        state: Option<std::path::PathBuf>,
+    },
     /// Reset sessions
     #[structopt(name = "reset")]
     Reset,
+    /// Send a hand built FTP request and print the reply
+    #[structopt(name = "raw")]
+    Raw {
+        /// Opcode number or name, e.g. 14 or CalcFileCRC32
+        #[structopt(long, parse(try_from_str = parse_opcode))]
+        opcode: u8,
+        /// Session id
+        #[structopt(long, default_value = "0")]
+        session: u8,
+        /// Offset field
+        #[structopt(long, default_value = "0")]
+        offset: u32,
+        /// Size field, the length of the data by default
+        #[structopt(long)]
+        size: Option<u8>,
+        /// Data as text, like a path
+        #[structopt(long, conflicts_with = "data-hex")]
+        data: Option<String>,
+        /// Data as hexadecimal bytes, e.g. 2f4150 or "2f 41 50"
+        #[structopt(long)]
+        data_hex: Option<HexBytes>,
+    },
+    /// Show if a path exists, its type, size and CRC32
+    #[structopt(name = "stat")]
+    Stat {
+        /// Print the result as JSON
+        #[structopt(long)]
+        json: bool,
+        /// File or directory path
+        path: String,
+    },
+    /// Exit with 0 if a path exists and 1 if not, without printing anything
+    #[structopt(name = "test", alias = "exists")]
+    Test {
+        /// The path must be a file
+        #[structopt(short = "f", conflicts_with = "directory")]
+        file: bool,
+        /// The path must be a directory
+        #[structopt(short = "d")]
+        directory: bool,
+        /// File or directory path
+        path: String,
+    },
+    /// Wait until a file or directory exists on the vehicle
+    #[structopt(name = "wait-for")]
+    WaitFor {
+        /// Give up after this long, e.g. 90, 120s, 5m or 1h
+        #[structopt(long, default_value = "60s", parse(try_from_str = parse_duration))]
+        timeout: std::time::Duration,
+        /// Also wait until the file has at least this many bytes
+        #[structopt(long)]
+        min_size: Option<u32>,
+        /// File or directory path
+        path: String,
+    },
+    /// Show autopilot version and FTP capabilities
+    #[structopt(name = "info")]
+    Info,
+    /// Probe the link and the FTP server, then tell what limits transfers and how to fix it
+    #[structopt(name = "doctor")]
+    Doctor,
+    /// Check the vehicle FTP server against the protocol and print a pass/fail matrix
+    #[structopt(name = "conformance")]
+    Conformance {
+        /// A file on the vehicle that can be read, e.g. @PARAM/param.pck
+        path: String,
+    },
+    /// Run a rhai script over the connection, its ls, get, put, crc and rm functions are
+    /// commands run on the vehicle
+    #[structopt(name = "script")]
+    Script {
+        /// Script file
+        #[structopt(parse(from_os_str))]
+        path: std::path::PathBuf,
+    },
+    /// Print the completion script of a shell
+    #[structopt(name = "completions")]
+    Completions {
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
+    },
+    /// Print the manual page, e.g. mavftp-cli manpage > mavftp-cli.1
+    #[structopt(name = "manpage")]
+    Manpage,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ParamsCommand {
+    /// Print the parameters of a saved file whose value on the vehicle differs, exit with 1 if any
+    #[structopt(name = "diff")]
+    Diff {
+        /// Parameter file, as saved by Mission Planner, MAVProxy or QGroundControl
+        #[structopt(parse(from_os_str))]
+        file: std::path::PathBuf,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+pub enum LogsCommand {
+    /// Download a log and verify it, latest as told by LASTLOG.TXT or the highest number
+    #[structopt(name = "get")]
+    Get {
+        /// Directory where the log is saved, the current one by default
+        #[structopt(long, parse(from_os_str))]
+        output_dir: Option<std::path::PathBuf>,
+        /// Overwrite the local file if it already exists
+        #[structopt(short = "f", long = "force")]
+        force: bool,
+        /// Directory of the logs on the vehicle
+        #[structopt(long, default_value = "/APM/LOGS")]
+        directory: String,
+        /// Log number, or latest
+        log: LogChoice,
+    },
+}
+
+#[derive(Debug)]
+pub enum LogChoice {
+    Latest,
+    Number(u32),
+}
+
+impl std::str::FromStr for LogChoice {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "latest" => Ok(LogChoice::Latest),
+            _ => value
+                .parse()
+                .map(LogChoice::Number)
+                .map_err(|_| format!("invalid log {}, use a number or latest", value)),
+        }
+    }
+}
+
+// Names and aliases of every command, the words where a queued command starts
+const COMMAND_NAMES: [&str; 31] = [
+    "list",
+    "ls",
+    "read",
+    "get",
+    "create",
+    "write",
+    "put",
+    "remove",
+    "rm",
+    "mkdir",
+    "rmdir",
+    "cp",
+    "crc",
+    "grep",
+    "hexdump",
+    "params",
+    "logs",
+    "daemon",
+    "reset",
+    "raw",
+    "info",
+    "doctor",
+    "conformance",
+    "script",
+    "stat",
+    "test",
+    "exists",
+    "wait-for",
+    "completions",
+    "manpage",
+    "help",
+];
+
+// Where each command starts, the first where the options are followed by a command and the
+// others once the command before them is complete, so a command name given as the value of an
+// option or as a positional argument doesn't start one
+pub fn command_starts(args: &[String]) -> Vec<usize> {
+    let is_name = |index: &usize| COMMAND_NAMES.contains(&args[*index].as_str());
+    // A command without arguments is taken as such only where the options expect one
+    let Some(first) = (1..args.len()).filter(is_name).find(|&index| {
+        let options = args[..index].iter().map(String::as_str);
+        Opt::clap()
+            .get_matches_from_safe(options.chain(["reset"]))
+            .is_ok()
+    }) else {
+        return Vec::new();
+    };
+    let mut starts = vec![first];
+    for index in (first + 1..args.len()).filter(is_name) {
+        let start = *starts.last().unwrap();
+        let previous = std::iter::once(&args[0]).chain(&args[start..index]);
+        if MavlinkFTPCommand::from_iter_safe(previous).is_ok() {
+            starts.push(index);
+        }
+    }
+    starts
+}
+
+// The options with the first command, followed by the commands queued after it
+pub fn parse_args() -> (Opt, Vec<MavlinkFTPCommand>) {
+    let args: Vec<String> = std::env::args().collect();
+    // The first command is parsed with the options
+    let mut starts: Vec<usize> = command_starts(&args).into_iter().skip(1).collect();
+    starts.push(args.len());
+
+    let mut opt = Opt::from_iter(&args[..starts[0]]);
+    let mut commands = split_reads(&mut opt.command);
+    for range in starts.windows(2) {
+        let mut command = MavlinkFTPCommand::from_iter(
+            std::iter::once(&args[0]).chain(&args[range[0]..range[1]]),
+        );
+        let reads = split_reads(&mut command);
+        commands.push(command);
+        commands.extend(reads);
+    }
+    // The passes of a daemon are invocations of their own, it can't be chained
+    let daemon = |command: &MavlinkFTPCommand| matches!(command, MavlinkFTPCommand::Daemon { .. });
+    if commands.iter().any(daemon) || (daemon(&opt.command) && !commands.is_empty()) {
+        structopt::clap::Error::with_description(
+            "daemon can only be given alone, as the first command",
+            structopt::clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    (opt, commands)
+}
+
+// A read -r of many trees keeps the first one, the others become reads queued after it; the
+// controller queues the files of a plain read itself, under a bar counting them
+fn split_reads(command: &mut MavlinkFTPCommand) -> Vec<MavlinkFTPCommand> {
+    let MavlinkFTPCommand::ReadFile {
+        output_dir,
+        mmap,
+        fsync_every,
+        force,
+        recursive,
+        continue_tree,
+        skip_existing,
+        compare_crc,
+        paths,
+    } = command
+    else {
+        return Vec::new();
+    };
+    if !*recursive {
+        return Vec::new();
+    }
+    paths
+        .split_off(1)
+        .into_iter()
+        .map(|path| MavlinkFTPCommand::ReadFile {
+            output_dir: output_dir.clone(),
+            mmap: *mmap,
+            fsync_every: *fsync_every,
+            force: *force,
+            recursive: *recursive,
+            continue_tree: *continue_tree,
+            skip_existing: *skip_existing,
+            compare_crc: *compare_crc,
+            paths: vec![path],
+        })
+        .collect()
+}
+
+impl MavlinkFTPCommand {
+    // Command name and the remote path it works on, for the metrics
+    pub fn operation(&self) -> (&'static str, &str) {
+        match self {
+            MavlinkFTPCommand::ListDirectory { path, .. } => ("list", path),
+            MavlinkFTPCommand::ReadFile { paths, .. } => ("read", &paths[0]),
+            MavlinkFTPCommand::CreateFile { path } => ("create", path),
+            MavlinkFTPCommand::WriteFile { paths, .. } => ("write", paths.last().unwrap()),
+            MavlinkFTPCommand::RemoveFile { path, .. } => ("remove", path),
+            MavlinkFTPCommand::CreateDirectory { path, .. } => ("mkdir", path),
+            MavlinkFTPCommand::RemoveDirectory { path, .. } => ("rmdir", path),
+            MavlinkFTPCommand::Copy { source, .. } => ("cp", source),
+            MavlinkFTPCommand::CalcFileCRC32 { path } => ("crc", path),
+            MavlinkFTPCommand::Grep { path, .. } => ("grep", path),
+            MavlinkFTPCommand::Hexdump { path, .. } => ("hexdump", path),
+            MavlinkFTPCommand::Params(ParamsCommand::Diff { .. }) => ("params diff", PARAM_PATH),
+            MavlinkFTPCommand::Logs(LogsCommand::Get { directory, .. }) => ("logs get", directory),
+            MavlinkFTPCommand::Daemon { sync, .. } => ("daemon", &sync[0]),
+            MavlinkFTPCommand::Reset => ("reset", ""),
+            MavlinkFTPCommand::Raw { .. } => ("raw", ""),
+            MavlinkFTPCommand::Stat { path, .. } => ("stat", path),
+            MavlinkFTPCommand::Test { path, .. } => ("test", path),
+            MavlinkFTPCommand::WaitFor { path, .. } => ("wait-for", path),
+            MavlinkFTPCommand::Info => ("info", ""),
+            MavlinkFTPCommand::Doctor => ("doctor", ""),
+            MavlinkFTPCommand::Conformance { path } => ("conformance", path),
+            // Each of its commands is an operation of its own
+            MavlinkFTPCommand::Script { .. } => ("script", ""),
+            MavlinkFTPCommand::Completions { .. } => ("completions", ""),
+            MavlinkFTPCommand::Manpage => ("manpage", ""),
+        }
+    }
+
+    // Vehicles have a single session, the commands that don't open a file can run alongside the
+    // one that does
+    pub fn needs_session(&self) -> bool {
+        !matches!(
+            self,
+            MavlinkFTPCommand::ListDirectory { .. }
+                | MavlinkFTPCommand::RemoveFile { .. }
+                | MavlinkFTPCommand::CreateDirectory { .. }
+                | MavlinkFTPCommand::RemoveDirectory { .. }
+                | MavlinkFTPCommand::CalcFileCRC32 { .. }
+                | MavlinkFTPCommand::Stat { .. }
+                | MavlinkFTPCommand::Test { .. }
+                | MavlinkFTPCommand::WaitFor { .. }
+        )
+    }
+}
+
+const BIN_NAME: &str = "mavftp-cli";
+
+// Remote paths, the words starting with / or @.../, are completed from a listing of their
+// directory, over the --connection and --link of the command line or MAVFTP_URL
+const BASH_REMOTE_PATHS: &str = r#"
+_mavftp-cli_remote() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    if [[ "$cur" != /* && "$cur" != @*/* ]]; then
+        _mavftp-cli "$@"
+        return
+    fi
+    # COMP_WORDS splits connection strings at their colons
+    local words args=() i dir="${cur%/*}"
+    read -ra words <<< "${COMP_LINE:0:COMP_POINT}"
+    for ((i = 1; i < ${#words[@]}; i++)); do
+        case "${words[i]}" in
+            --connection | --link) args+=("${words[i]}" "${words[i + 1]}") ;;
+            --connection=* | --link=*) args+=("${words[i]}") ;;
+        esac
+    done
+    local IFS=$'\n'
+    COMPREPLY=($(compgen -W "$(mavftp-cli "${args[@]}" --connect-timeout 2 list --format '{type}{path}' "${dir:-/}" 2>/dev/null | sed -n -e 's|^D\(.*\)|\1/|p' -e 's|^F||p')" -- "$cur"))
+    [[ "${COMPREPLY[*]}" == */ ]] && compopt -o nospace
+}
+
+complete -F _mavftp-cli_remote -o bashdefault -o default mavftp-cli
+"#;
+
+const ZSH_REMOTE_PATHS: &str = r#"
+_mavftp-cli_remote() {
+    if [[ "$PREFIX" != /* && "$PREFIX" != @*/* ]]; then
+        _mavftp-cli "$@"
+        return
+    fi
+    local -a args entries
+    local i
+    for ((i = 2; i < CURRENT; i++)); do
+        case "${words[i]}" in
+            --connection | --link) args+=("${words[i]}" "${words[i + 1]}") ;;
+            --connection=* | --link=*) args+=("${words[i]}") ;;
+        esac
+    done
+    entries=(${(f)"$(mavftp-cli "${args[@]}" --connect-timeout 2 list --format '{type}{path}' "${${PREFIX%/*}:-/}" 2>/dev/null)"})
+    compadd -S '' -- ${^${${(M)entries:#D*}#D}}/
+    compadd -- ${${(M)entries:#F*}#F}
+}
+
+_mavftp-cli_remote "$@"
+"#;
+
+const FISH_REMOTE_PATHS: &str = r#"
+function __mavftp_cli_remote
+    set -l current (commandline -ct)
+    string match -qr '^(/|@.*/)' -- $current; or return
+    set -l words (commandline -opc)
+    set -l args
+    for i in (seq 2 (count $words))
+        switch $words[$i]
+            case --connection --link
+                set -a args $words[$i] $words[(math $i + 1)]
+            case '--connection=*' '--link=*'
+                set -a args $words[$i]
+        end
+    end
+    set -l dir (string replace -r '/[^/]*$' '' -- $current)
+    test -n "$dir"; or set dir /
+    mavftp-cli $args --connect-timeout 2 list --format '{type}{path}' $dir 2>/dev/null | string replace -r '^D(.*)' '$1/' | string replace -r '^F' ''
+end
+
+complete -c mavftp-cli -a '(__mavftp_cli_remote)'
+"#;
+
+// Completion scripts and the manual page, true if the command is one of them
+pub fn generate(command: &MavlinkFTPCommand) -> bool {
+    match command {
+        MavlinkFTPCommand::Completions { shell } => print!("{}", completions(*shell)),
+        MavlinkFTPCommand::Manpage => print!("{}", manpage(Opt::clap().bin_name(BIN_NAME))),
+        _ => return false,
+    }
+    true
+}
+
+// The static completion of clap, PowerShell and elvish don't complete remote paths
+fn completions(shell: Shell) -> String {
+    let mut script = Vec::new();
+    Opt::clap()
+        .bin_name(BIN_NAME)
+        .gen_completions_to(BIN_NAME, shell, &mut script);
+    let script = String::from_utf8_lossy(&script);
+    match shell {
+        Shell::Bash => format!("{}{}", script, BASH_REMOTE_PATHS),
+        // The script ends calling the function it defines, the wrapper is called instead
+        Shell::Zsh => format!(
+            "{}{}",
+            script.trim_end().trim_end_matches("_mavftp-cli \"$@\""),
+            ZSH_REMOTE_PATHS
+        ),
+        Shell::Fish => format!("{}{}", script, FISH_REMOTE_PATHS),
+        _ => script.into_owned(),
+    }
+}
+
+// The help of the options and of each command, as it is printed
+fn manpage(mut app: structopt::clap::App) -> String {
+    let mut help = Vec::new();
+    let _ = app.write_long_help(&mut help);
+    let mut page = format!(
+        ".TH MAVFTP-CLI 1 \"\" \"{} {}\"\n.SH NAME\n{} \\- Mavlink FTP CLI\n",
+        BIN_NAME,
+        env!("CARGO_PKG_VERSION"),
+        BIN_NAME
+    );
+    page.push_str(&format!(
+        ".SH SYNOPSIS\n.B {}\n[OPTIONS] <command> [ARGS]... [<command> [ARGS]...]...\n",
+        BIN_NAME
+    ));
+    page.push_str(&format!(
+        ".SH DESCRIPTION\n.nf\n{}\n.fi\n.SH COMMANDS\n",
+        roff_escape(&String::from_utf8_lossy(&help))
+    ));
+
+    // Aliases print the help of their command, it's only added once
+    let mut helps: Vec<String> = Vec::new();
+    for name in COMMAND_NAMES.iter().filter(|&&name| name != "help") {
+        let Err(error) = app
+            .clone()
+            .get_matches_from_safe([BIN_NAME, name, "--help"])
+        else {
+            continue;
+        };
+        if error.kind != structopt::clap::ErrorKind::HelpDisplayed || helps.contains(&error.message)
+        {
+            continue;
+        }
+        page.push_str(&format!(
+            ".SS {}\n.nf\n{}\n.fi\n",
+            name,
+            roff_escape(error.message.trim_end())
+        ));
+        helps.push(error.message);
+    }
+    page
+}
+
+fn roff_escape(text: &str) -> String {
+    text.replace('\\', "\\e")
+        .lines()
+        .map(|line| match line.starts_with(['.', '\'']) {
+            true => format!("\\&{}", line),
+            false => line.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn parse_opcode(value: &str) -> Result<u8, String> {
+    if let Ok(opcode) = value.parse() {
+        return Ok(opcode);
+    }
+    MavlinkFtpOpcode::iter()
+        .find(|opcode| format!("{:?}", opcode).eq_ignore_ascii_case(value))
+        .map(|opcode| opcode as u8)
+        .ok_or_else(|| format!("unknown opcode {}", value))
+}
+
+// Seconds when there is no unit
+fn parse_duration(value: &str) -> Result<std::time::Duration, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration {}", value))?;
+    let seconds = match unit.trim() {
+        "" | "s" => Some(number),
+        "m" => number.checked_mul(60),
+        "h" => number.checked_mul(60 * 60),
+        _ => return Err(format!("unknown unit in {}, use s, m or h", value)),
+    };
+    // It's added to the current time for deadlines
+    seconds
+        .map(std::time::Duration::from_secs)
+        .filter(|duration| {
+            std::time::SystemTime::now()
+                .checked_add(*duration)
+                .is_some()
+                && std::time::Instant::now().checked_add(*duration).is_some()
+        })
+        .ok_or_else(|| format!("duration {} is too long", value))
+}
+
+// How list prints the entries
+#[derive(Debug)]
+pub enum ListFormat {
+    Table,
+    Json,
+    Ndjson,
+    Template(Template),
+}
+
+impl std::str::FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "table" => Ok(ListFormat::Table),
+            "json" => Ok(ListFormat::Json),
+            "ndjson" => Ok(ListFormat::Ndjson),
+            // Without a placeholder it is more likely a misspelled format than a template
+            _ if value.contains('{') => Template::parse(value, &LIST_FIELDS).map(ListFormat::Template),
+            _ => Err(format!(
+                "unknown format {}, use table, json, ndjson or a template such as '{{name}}\\t{{size}}'",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HexBytes(pub Vec<u8>);
+
+impl std::str::FromStr for HexBytes {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let digits: String = value
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != ':')
+            .collect();
+        // Slicing by two bytes below only works on ASCII
+        if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+            return Err(format!("invalid hexadecimal digit {}", c));
+        }
+        if !digits.len().is_multiple_of(2) {
+            return Err("odd number of hexadecimal digits".into());
+        }
+        (0..digits.len())
+            .step_by(2)
+            .map(|index| {
+                u8::from_str_radix(&digits[index..index + 2], 16)
+                    .map_err(|_| format!("invalid byte {}", &digits[index..index + 2]))
+            })
+            .collect::<Result<Vec<u8>, String>>()
+            .map(HexBytes)
+    }
+}
+
+fn parse_chunk_size(value: &str) -> Result<usize, String> {
+    let size: usize = value.parse().map_err(|error| format!("{}", error))?;
+    if size == 0 || size > MAX_DATA_SIZE {
+        return Err(format!("must be between 1 and {}", MAX_DATA_SIZE));
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn starts(line: &str) -> Vec<usize> {
+        let args: Vec<String> = line.split_whitespace().map(String::from).collect();
+        command_starts(&args)
+    }
+
+    #[test]
+    fn splits_commands_only_where_one_can_start() {
+        assert_eq!(starts("mavftp-cli list / read a.bin crc a.bin"), [1, 3, 5]);
+        // A pattern, an option value or a nested command named like a command
+        assert_eq!(starts("mavftp-cli grep info file.txt list /"), [1, 4]);
+        assert_eq!(starts("mavftp-cli --receipt list read a.bin"), [3]);
+
+        assert_eq!(starts("mavftp-cli logs get latest read a.bin"), [1, 4]);
+        assert_eq!(
+            starts("mavftp-cli read --output-dir info a.bin info"),
+            [1, 5]
+        );
+        assert!(starts("mavftp-cli --help").is_empty());
+    }
+
+    #[test]
+    fn completes_remote_paths_after_the_static_completion() {
+        let zsh = completions(Shell::Zsh);
+        assert!(zsh.starts_with("#compdef mavftp-cli"));
+        assert!(zsh.trim_end().ends_with("_mavftp-cli_remote \"$@\""));
+        assert_eq!(zsh.matches("\n_mavftp-cli \"$@\"").count(), 0);
+        let bash = completions(Shell::Bash);
+        assert!(bash
+            .trim_end()
+            .ends_with("complete -F _mavftp-cli_remote -o bashdefault -o default mavftp-cli"));
+        assert!(!completions(Shell::PowerShell).contains("_remote"));
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(std::time::Duration::from_secs(90)));
+        assert_eq!(
+            parse_duration("10m"),
+            Ok(std::time::Duration::from_secs(600))
+        );
+        assert_eq!(
+            parse_duration("2h"),
+            Ok(std::time::Duration::from_secs(7200))
+        );
+        assert!(parse_duration("10d").is_err());
+        // Too long to multiply or to add to the current time
+        assert!(parse_duration(&format!("{}h", u64::MAX / 60)).is_err());
+        assert!(parse_duration(&u64::MAX.to_string()).is_err());
+    }
+
+    #[test]
+    fn parses_hex_bytes() {
+        let bytes: HexBytes = "de:ad be EF".parse().unwrap();
+        assert_eq!(bytes.0, [0xde, 0xad, 0xbe, 0xef]);
+        assert!("abc".parse::<HexBytes>().is_err());
+        assert!("zz".parse::<HexBytes>().is_err());
+        // Multibyte characters are rejected instead of splitting them
+        assert!("aé".parse::<HexBytes>().is_err());
+        assert!("éa".parse::<HexBytes>().is_err());
+    }
 }
@@ -0,0 +1,529 @@
+use std::time::{Duration, Instant};
+
+use crate::mavftp::*;
+use num_traits::FromPrimitive;
+
+// Time a check waits for its reply before failing, or for the next reply of a burst
+const REPLY_TIMEOUT: Duration = Duration::from_secs(3);
+
+// No firmware is expected to have it
+const MISSING_PATH: &str = "/mavftp-cli-conformance-missing";
+
+// Not assigned to any command by the protocol
+const UNKNOWN_OPCODE: u8 = 200;
+
+// Past the end of any directory listing
+const LIST_PAST_END_OFFSET: u32 = 0xffff;
+
+#[derive(Clone, Copy)]
+enum Check {
+    ResetSessions,
+    ReadWithoutSession,
+    OpenMissing,
+    Open,
+    Read,
+    ReadPastEof,
+    BurstRead,
+    Terminate,
+    ReadAfterTerminate,
+    Crc,
+    List,
+    ListPastEnd,
+    UnknownOpcode,
+}
+
+const CHECKS: [Check; 13] = [
+    Check::ResetSessions,
+    Check::ReadWithoutSession,
+    Check::OpenMissing,
+    Check::Open,
+    Check::Read,
+    Check::ReadPastEof,
+    Check::BurstRead,
+    Check::Terminate,
+    Check::ReadAfterTerminate,
+    Check::Crc,
+    Check::List,
+    Check::ListPastEnd,
+    Check::UnknownOpcode,
+];
+
+impl Check {
+    fn name(&self) -> &'static str {
+        match self {
+            Check::ResetSessions => "reset sessions",
+            Check::ReadWithoutSession => "read without a session",
+            Check::OpenMissing => "open a missing file",
+            Check::Open => "open the file",
+            Check::Read => "read the first chunk",
+            Check::ReadPastEof => "read past the end of file",
+            Check::BurstRead => "burst read the file",
+            Check::Terminate => "terminate the session",
+            Check::ReadAfterTerminate => "read after terminating",
+            Check::Crc => "crc32 of the file",
+            Check::List => "list the root directory",
+            Check::ListPastEnd => "list past the last entry",
+            Check::UnknownOpcode => "unknown opcode",
+        }
+    }
+}
+
+// The reply header, read from the bytes so unknown opcodes can be checked too
+struct Reply<'a> {
+    seq_number: u16,
+    session: u8,
+    opcode: u8,
+    req_opcode: u8,
+    burst_complete: bool,
+    offset: u32,
+    data: &'a [u8],
+}
+
+impl<'a> Reply<'a> {
+    fn parse(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE {
+            return None;
+        }
+        let size = (bytes[4] as usize).min(bytes.len() - HEADER_SIZE);
+        Some(Self {
+            seq_number: u16::from_le_bytes([bytes[0], bytes[1]]),
+            session: bytes[2],
+            opcode: bytes[3],
+            req_opcode: bytes[5],
+            burst_complete: bytes[6] != 0,
+            offset: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            data: &bytes[HEADER_SIZE..HEADER_SIZE + size],
+        })
+    }
+
+    fn is_ack(&self) -> bool {
+        self.opcode == MavlinkFtpOpcode::Ack as u8
+    }
+
+    fn nak(&self) -> Option<u8> {
+        if self.opcode == MavlinkFtpOpcode::Nak as u8 {
+            self.data.first().copied()
+        } else {
+            None
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self.nak() {
+            Some(code) => match MavlinkFtpNak::from_u8(code) {
+                Some(nak) => format!("Nak {:?}", nak),
+                None => format!("Nak {}", code),
+            },
+            None if self.is_ack() => "Ack".to_string(),
+            None => format!("opcode {}", self.opcode),
+        }
+    }
+
+    fn expect_ack(&self) -> Result<(), String> {
+        if self.is_ack() {
+            Ok(())
+        } else {
+            Err(format!("{}, expected Ack", self.describe()))
+        }
+    }
+
+    fn expect_nak(&self, expected: MavlinkFtpNak) -> Result<(), String> {
+        if self.nak() == Some(expected as u8) {
+            Ok(())
+        } else {
+            Err(format!("{}, expected Nak {:?}", self.describe(), expected))
+        }
+    }
+}
+
+enum Outcome {
+    Pass(String),
+    Fail(String),
+    // More replies belong to the same request, as in a burst
+    Wait,
+    // The check goes on with a new request, as when a burst ends before the end of file
+    Again,
+}
+
+impl From<Result<(), String>> for Outcome {
+    fn from(result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => Outcome::Pass(String::new()),
+            Err(detail) => Outcome::Fail(detail),
+        }
+    }
+}
+
+// Runs every check in order against the vehicle, one request at a time
+pub struct Conformance {
+    path: String,
+    index: usize,
+    seq_number: u16,
+    sent: Option<(Instant, u8)>,
+    session: u8,
+    file_size: Option<u32>,
+    // Content received by the burst read, compared with the vehicle CRC32
+    content: Vec<u8>,
+    results: Vec<(&'static str, Result<String, String>)>,
+}
+
+impl Conformance {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            index: 0,
+            seq_number: 0,
+            sent: None,
+            session: 0,
+            file_size: None,
+            content: Vec::new(),
+            results: Vec::new(),
+        }
+    }
+
+    // The request of the current check, None while its reply is awaited
+    pub fn poll(&mut self) -> Option<Vec<u8>> {
+        if let Some((sent_at, _)) = self.sent {
+            if sent_at.elapsed() < REPLY_TIMEOUT {
+                return None;
+            }
+            self.finish_check(Outcome::Fail("no reply".into()));
+        }
+
+        let check = *CHECKS.get(self.index)?;
+        let (opcode, session, offset, size, data) = self.request(check);
+        self.seq_number = self.seq_number.wrapping_add(1);
+
+        let mut request = Vec::new();
+        request.extend_from_slice(&self.seq_number.to_le_bytes());
+        request.push(session);
+        request.push(opcode);
+        request.push(size);
+        // req_opcode, burst_complete and padding
+        request.extend_from_slice(&[0, 0, 0]);
+        request.extend_from_slice(&offset.to_le_bytes());
+        request.extend_from_slice(&data);
+
+        self.sent = Some((Instant::now(), opcode));
+        Some(request)
+    }
+
+    // Checks a reply and returns the next request, if it can go out already
+    pub fn reply(&mut self, bytes: &[u8]) -> Option<Vec<u8>> {
+        let (_, opcode) = self.sent?;
+        let reply = Reply::parse(bytes)?;
+        if reply.req_opcode != opcode {
+            return None;
+        }
+
+        let expected_seq = self.seq_number.wrapping_add(1);
+        let outcome = if reply.seq_number != expected_seq && !self.bursting() {
+            Outcome::Fail(format!(
+                "seq_number {}, expected {}",
+                reply.seq_number, expected_seq
+            ))
+        } else {
+            self.check(CHECKS[self.index], &reply)
+        };
+
+        match outcome {
+            Outcome::Wait => {
+                self.sent = Some((Instant::now(), opcode));
+                return None;
+            }
+            Outcome::Again => self.sent = None,
+            outcome => self.finish_check(outcome),
+        }
+        self.poll()
+    }
+
+    // Time left for the reply of the current check
+    pub fn timeout(&self) -> Option<Duration> {
+        self.sent
+            .map(|(sent_at, _)| REPLY_TIMEOUT.saturating_sub(sent_at.elapsed()))
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.index >= CHECKS.len()
+    }
+
+    // Prints the pass/fail matrix and returns the exit code
+    pub fn report(&self) -> i32 {
+        let width = self
+            .results
+            .iter()
+            .map(|(name, _)| name.len())
+            .max()
+            .unwrap_or_default();
+        for (name, result) in &self.results {
+            let (status, detail) = match result {
+                Ok(detail) => ("pass", detail),
+                Err(detail) => ("FAIL", detail),
+            };
+            println!("{:<width$}  {}  {}", name, status, detail, width = width);
+        }
+
+        let failed = self
+            .results
+            .iter()
+            .filter(|(_, result)| result.is_err())
+            .count();
+        println!("{} passed, {} failed", self.results.len() - failed, failed);
+        if failed == 0 {
+            0
+        } else {
+            1
+        }
+    }
+
+    fn bursting(&self) -> bool {
+        matches!(CHECKS.get(self.index), Some(Check::BurstRead))
+    }
+
+    fn finish_check(&mut self, outcome: Outcome) {
+        let result = match outcome {
+            Outcome::Pass(detail) => Ok(detail),
+            Outcome::Fail(detail) => Err(detail),
+            Outcome::Wait | Outcome::Again => return,
+        };
+        self.results.push((CHECKS[self.index].name(), result));
+        self.sent = None;
+        self.index += 1;
+    }
+
+    fn request(&self, check: Check) -> (u8, u8, u32, u8, Vec<u8>) {
+        let path = self.path.clone().into_bytes();
+        let file_size = self.file_size.unwrap_or_default();
+        match check {
+            Check::ResetSessions => (MavlinkFtpOpcode::ResetSessions as u8, 0, 0, 0, vec![]),
+            Check::ReadWithoutSession | Check::ReadAfterTerminate => (
+                MavlinkFtpOpcode::ReadFile as u8,
+                self.session,
+                0,
+                MAX_DATA_SIZE as u8,
+                vec![],
+            ),
+            Check::OpenMissing => {
+                let path = MISSING_PATH.as_bytes().to_vec();
+                (
+                    MavlinkFtpOpcode::OpenFileRO as u8,
+                    0,
+                    0,
+                    path.len() as u8,
+                    path,
+                )
+            }
+            Check::Open => (
+                MavlinkFtpOpcode::OpenFileRO as u8,
+                0,
+                0,
+                path.len() as u8,
+                path,
+            ),
+            Check::Read => (
+                MavlinkFtpOpcode::ReadFile as u8,
+                self.session,
+                0,
+                MAX_DATA_SIZE as u8,
+                vec![],
+            ),
+            Check::ReadPastEof => (
+                MavlinkFtpOpcode::ReadFile as u8,
+                self.session,
+                file_size,
+                MAX_DATA_SIZE as u8,
+                vec![],
+            ),
+            Check::BurstRead => (
+                MavlinkFtpOpcode::BurstReadFile as u8,
+                self.session,
+                self.content.len() as u32,
+                MAX_DATA_SIZE as u8,
+                vec![],
+            ),
+            Check::Terminate => (
+                MavlinkFtpOpcode::TerminateSession as u8,
+                self.session,
+                0,
+                0,
+                vec![],
+            ),
+            Check::Crc => (
+                MavlinkFtpOpcode::CalcFileCRC32 as u8,
+                0,
+                0,
+                path.len() as u8,
+                path,
+            ),
+            Check::List | Check::ListPastEnd => {
+                let offset = match check {
+                    Check::List => 0,
+                    _ => LIST_PAST_END_OFFSET,
+                };
+                (
+                    MavlinkFtpOpcode::ListDirectory as u8,
+                    0,
+                    offset,
+                    1,
+                    b"/".to_vec(),
+                )
+            }
+            Check::UnknownOpcode => (UNKNOWN_OPCODE, 0, 0, 0, vec![]),
+        }
+    }
+
+    fn check(&mut self, check: Check, reply: &Reply) -> Outcome {
+        match check {
+            Check::ResetSessions | Check::Terminate => reply.expect_ack().into(),
+            Check::ReadWithoutSession | Check::ReadAfterTerminate => {
+                reply.expect_nak(MavlinkFtpNak::InvalidSession).into()
+            }
+            Check::OpenMissing => reply.expect_nak(MavlinkFtpNak::FileNotFound).into(),
+            Check::Open => {
+                if let Err(detail) = reply.expect_ack() {
+                    return Outcome::Fail(detail);
+                }
+                let Ok(size) = <[u8; 4]>::try_from(reply.data) else {
+                    return Outcome::Fail(format!(
+                        "{} bytes of data, expected the 4 bytes of the file size",
+                        reply.data.len()
+                    ));
+                };
+                let size = u32::from_le_bytes(size);
+                self.session = reply.session;
+                self.file_size = Some(size);
+                Outcome::Pass(format!("session {}, {} bytes", reply.session, size))
+            }
+            Check::Read => {
+                let Some(file_size) = self.file_size else {
+                    return Outcome::Fail("the file could not be opened".into());
+                };
+                if file_size == 0 {
+                    return reply.expect_nak(MavlinkFtpNak::Eof).into();
+                }
+                if let Err(detail) = reply.expect_ack() {
+                    return Outcome::Fail(detail);
+                }
+                let expected = (file_size as usize).min(MAX_DATA_SIZE);
+                if reply.offset != 0 {
+                    Outcome::Fail(format!("offset {}, expected 0", reply.offset))
+                } else if reply.data.len() != expected {
+                    Outcome::Fail(format!("{} bytes, expected {}", reply.data.len(), expected))
+                } else {
+                    Outcome::Pass(format!("{} bytes", expected))
+                }
+            }
+            Check::ReadPastEof => {
+                if self.file_size.is_none() {
+                    return Outcome::Fail("the file could not be opened".into());
+                }
+                reply.expect_nak(MavlinkFtpNak::Eof).into()
+            }
+            Check::BurstRead => self.check_burst(reply),
+            Check::Crc => {
+                if let Err(detail) = reply.expect_ack() {
+                    return Outcome::Fail(detail);
+                }
+                let Ok(crc) = <[u8; 4]>::try_from(reply.data) else {
+                    return Outcome::Fail(format!(
+                        "{} bytes of data, expected the 4 bytes of the crc32",
+                        reply.data.len()
+                    ));
+                };
+                let crc = u32::from_le_bytes(crc);
+                // Only a complete burst read can be compared
+                if Some(self.content.len() as u32) != self.file_size {
+                    return Outcome::Pass(format!("0x{:08x}, not compared", crc));
+                }
+                let local = mavlink_crc32(&self.content);
+                if crc == local {
+                    Outcome::Pass(format!("0x{:08x}, matches the burst read", crc))
+                } else {
+                    Outcome::Fail(format!(
+                        "0x{:08x}, the burst read content has 0x{:08x}",
+                        crc, local
+                    ))
+                }
+            }
+            Check::List => {
+                if let Err(detail) = reply.expect_ack() {
+                    return Outcome::Fail(detail);
+                }
+                let mut count = 0;
+                for entry in reply.data.split(|&byte| byte == 0) {
+                    if entry.is_empty() {
+                        continue;
+                    }
+                    if let Err(error) = parse_directory_entry(entry) {
+                        return Outcome::Fail(error.to_string());
+                    }
+                    count += 1;
+                }
+                Outcome::Pass(format!("{} entries", count))
+            }
+            Check::ListPastEnd => reply.expect_nak(MavlinkFtpNak::Eof).into(),
+            Check::UnknownOpcode => reply.expect_nak(MavlinkFtpNak::UnknownCommand).into(),
+        }
+    }
+
+    // Burst replies must be contiguous and end with burst_complete or an EOF Nak
+    fn check_burst(&mut self, reply: &Reply) -> Outcome {
+        let Some(file_size) = self.file_size else {
+            return Outcome::Fail("the file could not be opened".into());
+        };
+        let received = self.content.len() as u32;
+
+        if reply.nak() == Some(MavlinkFtpNak::Eof as u8) {
+            return if received == file_size {
+                Outcome::Pass(format!("{} bytes", received))
+            } else {
+                Outcome::Fail(format!("EOF at {} of {} bytes", received, file_size))
+            };
+        }
+        if let Err(detail) = reply.expect_ack() {
+            return Outcome::Fail(detail);
+        }
+        if reply.offset != received {
+            return Outcome::Fail(format!("offset {}, expected {}", reply.offset, received));
+        }
+        self.content.extend_from_slice(reply.data);
+
+        if !reply.burst_complete {
+            Outcome::Wait
+        } else if self.content.len() as u32 == file_size {
+            Outcome::Pass(format!("{} bytes", file_size))
+        } else {
+            Outcome::Again
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_again_for_each_reply_of_a_burst() {
+        let mut conformance = Conformance::new("/test.bin".into());
+        conformance.index = CHECKS
+            .iter()
+            .position(|check| matches!(check, Check::BurstRead))
+            .unwrap();
+        conformance.file_size = Some(1000);
+        let opcode = MavlinkFtpOpcode::BurstReadFile as u8;
+        conformance.sent = Some((
+            Instant::now() - REPLY_TIMEOUT + Duration::from_millis(1),
+            opcode,
+        ));
+
+        let mut reply = vec![0; HEADER_SIZE + 100];
+        reply[3] = MavlinkFtpOpcode::Ack as u8;
+        reply[4] = 100;
+        reply[5] = opcode;
+        assert!(conformance.reply(&reply).is_none());
+
+        // A long burst doesn't run out of the time given to its request
+        assert!(conformance.timeout().unwrap() > REPLY_TIMEOUT - Duration::from_secs(1));
+        assert!(conformance.results.is_empty());
+    }
+}
@@ -1,14 +1,34 @@
-use std::io::Write;
-use std::process::exit;
-use std::time::SystemTime;
+use crate::cloud;
+use crate::conformance::Conformance;
+use crate::disk;
+use crate::exit;
+use std::collections::VecDeque;
+use std::io::{IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::grep::GrepMatcher;
+use crate::hexdump::HexDumper;
+use crate::hook::{self, Hook};
+use crate::json;
+use crate::link::Message;
 use crate::mavftp::*;
+use crate::metrics;
+use crate::notify;
+use crate::params::*;
+use crate::queue::{Partial, QueueFile};
+use crate::receipt::*;
+use crate::script;
+use crate::sha256::{self, Sha256};
+use crate::sidecar;
+use crate::template::Template;
 use num_traits::FromPrimitive;
 
-use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressState, ProgressStyle};
 
 use std::fs::OpenOptions;
 use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 
 enum OperationStatus {
     ScanningFolder(ScanningFolderStatus),
@@ -16,16 +36,142 @@ enum OperationStatus {
     ReadingFile(ReadingFileStatus),
     Reset,
     CalcFileCRC32(CalcFileCRC32Status),
-    ClosingSession
+    ResumingFile(ResumingFileStatus),
+    ComparingLocal(ComparingLocalStatus),
+    ClosingSession(ClosingSessionStatus),
+    // Waiting for the vehicle to accept the reboot that follows an upload
+    Rebooting,
+    // Waiting for the vehicle to accept the restart of its Lua scripts
+    RestartingScripts,
+    Info(InfoStatus),
+    Copying(CopyStatus),
+    CreatingDirectory(CreatingDirectoryStatus),
+    Removing(RemovingStatus),
+    Extracting(ExtractingStatus),
+    FindingLog(FindingLogStatus),
+    Uploading(UploadStatus),
+    Verifying(VerifyingStatus),
+    Raw(RawStatus),
+    Conformance(Conformance),
+    Stat(StatStatus),
+}
+
+// Time given to the autopilot to bring its FTP service back after a reboot
+const REBOOT_SETTLE_TIME: Duration = Duration::from_secs(2);
+
+// Time without any reply before the last request is sent again
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+// Time between two listings of wait-for
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Times the same request is sent again before giving up
+const MAX_RESENDS: u32 = 5;
+
+// The CRC32 of a file of a tree is asked for while the next one is read, its replies are told
+// apart by sequence numbers of their own, counted from this one
+const OVERLAP_SEQUENCE: u16 = 0x2000;
+const OVERLAP_SEQUENCES: u16 = 0x1000;
+// Time given to the vehicle to calculate it before asking again
+const OVERLAP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The top bits of the sequence numbers sent tell which operation of the link a reply belongs to,
+// the controller itself only sees the bits below them
+const SLOT_SHIFT: u16 = 14;
+const SEQUENCE_MASK: u16 = (1 << SLOT_SHIFT) - 1;
+pub const MAX_SLOTS: usize = 1 << (16 - SLOT_SHIFT);
+
+pub fn slot_of(seq_number: u16) -> u8 {
+    (seq_number >> SLOT_SHIFT) as u8
+}
+
+// Sent sequence numbers wrap one short of the mask, so the reply to the last one, one more, still
+// has the slot of its request. Replies only lose their slot
+fn tag_sequence(seq_number: u16, slot: u8) -> u16 {
+    (seq_number % SEQUENCE_MASK) | ((slot as u16) << SLOT_SHIFT)
+}
+
+fn untag_sequence(seq_number: u16) -> u16 {
+    seq_number & SEQUENCE_MASK
 }
 
 struct ScanningFolderStatus {
     path: String,
-    offset: u8,
+    options: ListOptions,
+    // Entries received so far, the offset of the next page
+    offset: u32,
+    // Entries that could not be parsed, reported after the listing
+    skipped: Vec<DirectoryEntryError>,
+}
+
+#[derive(Default)]
+pub struct ListOptions {
+    pub full_path: bool,
+    pub json: bool,
+    // Entries are printed as their page arrives, in the order of the vehicle
+    pub stream: bool,
+    // A line per entry from --format, instead of the table
+    pub template: Option<Template>,
+}
+
+// What write asks of the vehicle once the files are uploaded, so they take effect
+#[derive(Debug, Clone, Copy)]
+pub enum AfterUpload {
+    // True when it's confirmed already
+    Reboot(bool),
+    RestartScripts,
 }
 
+// Placeholders of list --format
+pub const LIST_FIELDS: [&str; 5] = ["type", "name", "path", "size", "bytes"];
+
 struct OpeningFileStatus {
     path: String,
+    // None downloads the file to local_path
+    destination: Option<ReadDestination>,
+    offset: u32,
+    length: Option<u32>,
+    local_path: PathBuf,
+    options: ReadOptions,
+}
+
+// How a downloaded file is written
+#[derive(Default, Clone)]
+pub struct ReadOptions {
+    // Directory of the downloaded file, the current one by default
+    pub output_dir: Option<PathBuf>,
+    pub mmap: bool,
+    // Bytes between syncs of the output file
+    pub fsync_every: Option<u32>,
+    // A local file of the remote size is taken as downloaded already, optionally once the CRC32
+    // matches too
+    pub skip_existing: bool,
+    pub compare_crc: bool,
+}
+
+enum ReadDestination {
+    File(std::fs::File),
+    // The output file preallocated and mapped, chunks are copied at their offset
+    Mapped(memmap2::MmapMut),
+    Grep(GrepMatcher),
+    Hexdump(HexDumper),
+    Params(ParamDiff),
+    LastLog(LastLogReader),
+}
+
+impl ReadDestination {
+    // Makes sure the data written so far is on the disk
+    fn sync(&self) -> std::io::Result<()> {
+        match self {
+            ReadDestination::File(file) => file.sync_data(),
+            ReadDestination::Mapped(map) => map.flush(),
+            // The others aren't written to the disk
+            _ => Ok(()),
+        }
+    }
+
+    fn on_disk(&self) -> bool {
+        matches!(self, ReadDestination::File(_) | ReadDestination::Mapped(_))
+    }
 }
 
 struct CalcFileCRC32Status {
@@ -34,293 +180,3287 @@ struct CalcFileCRC32Status {
 
 struct ReadingFileStatus {
     path: String,
+    local_path: PathBuf,
+    offset: u32,
+    file_size: u32,
+    destination: ReadDestination,
+    // Bytes between syncs of the output file and offset of the last one
+    fsync_every: Option<u32>,
+    synced: u32,
+    // CRC32 of the bytes written so far, so the file isn't read back at the end
+    crc: u32,
+    // Only calculated with --sha256
+    sha256: Option<Sha256>,
+}
+
+impl ReadingFileStatus {
+    // The remote file changed while the vehicle was away, what was downloaded of it is thrown
+    // away
+    fn restart(&mut self, file_size: u32) -> std::io::Result<()> {
+        let mapped = matches!(self.destination, ReadDestination::Mapped(_));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.local_path)?;
+        self.destination = ReadDestination::File(file);
+        if mapped && file_size > 0 {
+            if let ReadDestination::File(file) = &self.destination {
+                file.set_len(file_size as u64)?;
+                // Safety: the old map was dropped and nothing else changes the size of the file
+                let map = unsafe { memmap2::MmapMut::map_mut(file) }?;
+                self.destination = ReadDestination::Mapped(map);
+            }
+        }
+        self.offset = 0;
+        self.file_size = file_size;
+        self.synced = 0;
+        self.crc = 0;
+        self.sha256 = self.sha256.as_ref().map(|_| Sha256::default());
+        Ok(())
+    }
+}
+
+struct ClosingSessionStatus {
+    exit_code: i32,
+    announce: bool,
+    // The downloaded file, for the receipt
+    transferred: Vec<ReceiptEntry>,
+}
+
+// A local file of the remote size, whose CRC32 is compared before skipping it
+struct ComparingLocalStatus {
+    path: String,
+    local_path: PathBuf,
+    options: ReadOptions,
+    // None when the local file can't be read, it's downloaded again then
+    local_crc: Option<u32>,
+    // The read session is closed before asking for the CRC32
+    open_session: Option<u8>,
+}
+
+struct ResumingFileStatus {
+    reading: ReadingFileStatus,
+    sessions_reset: bool,
+}
+
+#[derive(PartialEq)]
+enum CopyStage {
+    OpeningSource,
+    ReadingSource,
+    ClosingSource,
+    OpeningDestination,
+    WritingDestination,
+    ClosingDestination,
+}
+
+// Only one session can be open in most autopilots, so the file is moved in windows:
+// read to memory, close the source, write to the destination, close it and repeat
+struct CopyStatus {
+    source: String,
+    destination: String,
+    stage: CopyStage,
+    // Next byte to be read from the source
     offset: u32,
+    // Next byte to be writen to the destination
+    written: u32,
     file_size: u32,
-    file: std::fs::File,
+    buffer: Vec<u8>,
+}
+
+const COPY_WINDOW_SIZE: usize = 64 * 1024;
+
+struct CreatingDirectoryStatus {
+    // Directories to be created, parents first
+    paths: Vec<String>,
+    index: usize,
+    parents: bool,
+}
+
+struct RemovingStatus {
+    // Directory being listed, the tree is walked depth-first
+    scanning: Option<String>,
+    scan_offset: u32,
+    // Subdirectories of the one being listed and the ones still waiting to be listed
+    found: Vec<String>,
+    pending: Vec<String>,
+    // Directories are removed after all files, from the deepest one
+    directories: Vec<String>,
+    // Paths to be removed in order, with true for directories
+    queue: Vec<(String, bool)>,
+    index: usize,
+    failed: Vec<(String, String)>,
+    // Removal was confirmed with --yes
+    confirmed: bool,
+}
+
+impl InfoStatus {
+    // A probe of doctor got no reply, true if there is nothing left to probe
+    fn skip_probe(&mut self) -> bool {
+        match self.stage {
+            InfoStage::RequestingVersion => self.stage = InfoStage::ProbingList,
+            // Without FTP the other probes can't be answered either
+            InfoStage::ProbingList => return true,
+            InfoStage::ProbingBurst => {
+                self.unanswered.push("burst read");
+                self.stage = InfoStage::ProbingWrite;
+            }
+            InfoStage::ProbingWrite => {
+                self.unanswered.push("write");
+                if self.probe_file.is_none() {
+                    return true;
+                }
+                self.stage = InfoStage::OpeningProbeFile;
+            }
+            InfoStage::OpeningProbeFile | InfoStage::ResettingProbeSessions => {
+                self.unanswered.push("payload size");
+                return true;
+            }
+            InfoStage::ReadingProbeFile => {
+                self.unanswered.push("payload size");
+                self.stage = InfoStage::ClosingProbeFile;
+            }
+            InfoStage::ClosingProbeFile => return true,
+        }
+        false
+    }
+}
+
+impl RemovingStatus {
+    fn new(queue: Vec<(String, bool)>) -> Self {
+        Self {
+            scanning: None,
+            scan_offset: 0,
+            found: Vec::new(),
+            pending: Vec::new(),
+            directories: Vec::new(),
+            queue,
+            index: 0,
+            failed: Vec::new(),
+            confirmed: false,
+        }
+    }
+}
+
+// A tree is listed whole first, then its files are read one after the other
+struct ExtractingStatus {
+    root: String,
+    // Directory being listed and the ones still waiting to be listed
+    scanning: Option<String>,
+    scan_offset: u32,
+    pending: Vec<String>,
+    // Files with the size they were listed with
+    files: Vec<(String, u32)>,
+    options: ReadOptions,
+}
+
+// ArduPilot names its logs by number, as 00000042.BIN
+const LOG_EXTENSION: &str = ".BIN";
+// Holds the number of the last log, which is not the highest one once the numbers wrap around
+const LAST_LOG_NAME: &str = "LASTLOG.TXT";
+
+#[derive(Default)]
+struct FindingLogStatus {
+    directory: String,
+    // None for the latest log
+    number: Option<u32>,
+    scan_offset: u32,
+    logs: Vec<(u32, String)>,
+    has_last_log: bool,
+    options: ReadOptions,
+}
+
+// LASTLOG.TXT, kept in memory until the log it names is read
+struct LastLogReader {
+    data: Vec<u8>,
+    finding: FindingLogStatus,
+}
+
+// ArduPilot's files built into the firmware, they can be read but never changed
+const ROMFS_PATH: &str = "@ROMFS";
+
+#[derive(PartialEq)]
+enum UploadStage {
+    // Opening the remote file to know if it already exists
+    Checking,
+    ClosingExisting(ExistingFile),
+    Creating,
+    Writing,
+    Closing,
+}
+
+// What to do with a remote file that already exists, once it's closed
+#[derive(PartialEq)]
+enum ExistingFile {
+    Overwrite,
+    Skip,
+    Refuse,
+}
+
+struct UploadStatus {
+    // Local and remote path of each file
+    files: Vec<(PathBuf, String)>,
+    index: usize,
+    stage: UploadStage,
+    file: Option<std::fs::File>,
+    offset: u32,
+    // Data of the write request in flight
+    chunk: Vec<u8>,
+    // Progress of all files, when there are more than one
+    total: Option<ProgressBar>,
+    multi_progress: MultiProgress,
+    // Files that were not overwritten, they are left out of the receipt
+    skipped: Vec<usize>,
+    // Files checked while the next ones were uploaded
+    checked: Vec<usize>,
+    // Content of the standard input, uploaded when the local path is "-"
+    stdin: Option<Vec<u8>>,
+}
+
+const STDIN_PATH: &str = "-";
+
+enum StatStage {
+    // The type and size come from the entry in the parent directory
+    Listing,
+    Crc,
+}
+
+struct StatStatus {
+    path: String,
+    parent: String,
+    name: String,
+    stage: StatStage,
+    offset: u32,
+    entry: Option<EntryInfo>,
+    crc: Option<u32>,
+    json: bool,
+    test: Option<PathTest>,
+    wait: Option<WaitFor>,
+}
+
+// The path is listed again until it shows up or the deadline passes
+struct WaitFor {
+    deadline: SystemTime,
+    min_size: u32,
+}
+
+// What the test command checks, the result is only the exit code
+pub enum PathTest {
+    Exists,
+    File,
+    Directory,
+}
+
+// Remote CRC32 or size of each transferred file, requested before writing the receipt
+struct VerifyingStatus {
+    entries: Vec<ReceiptEntry>,
+    index: usize,
+    exit_code: i32,
+    // The size comes from opening the file, the session is closed before the next one
+    open_session: Option<u8>,
+    // The vehicle can't calculate the CRC32 of the file, its size is checked instead
+    size_fallback: bool,
+    // Not written to the vehicle, so --upload-to takes them
+    downloads: bool,
+}
+
+// A request built by hand, sent as it is
+struct RawStatus {
+    request: Vec<u8>,
+}
+
+enum InfoStage {
+    RequestingVersion,
+    ProbingList,
+    ProbingBurst,
+    ProbingWrite,
+    // Doctor only, a file of the root directory is read to measure the payload size
+    OpeningProbeFile,
+    // The file was opened but the reply got lost, its session is closed before opening it again
+    ResettingProbeSessions,
+    ReadingProbeFile,
+    ClosingProbeFile,
+}
+
+struct InfoStatus {
+    stage: InfoStage,
+    version: Option<mavlink::common::AUTOPILOT_VERSION_DATA>,
+    ftp_reachable: bool,
+    burst_support: bool,
+    write_support: bool,
+    // Probes that go unanswered are reported instead of ending the invocation
+    doctor: bool,
+    unanswered: Vec<&'static str>,
+    // The largest file of the root directory and its size
+    probe_file: Option<(String, u32)>,
+    probe_session: u8,
+    probe_refused: bool,
+    max_payload: Option<usize>,
+    round_trips: Vec<Duration>,
+    resends: u32,
 }
 
+const AUTOPILOT_VERSION_MESSAGE_ID: u32 = 148;
+
+// Session that is never opened, used to check if an opcode is supported without side effects
+const PROBE_SESSION: u8 = 0xff;
+
 pub struct Controller {
+    target_network: u8,
+    target_system: u8,
+    target_component: u8,
     session: u8,
+    chunk_size: usize,
+    // When the last request was sent or the last reply arrived
     last_time: SystemTime,
+    last_request: Option<Message>,
+    resends: u32,
+    // The reply is to a request that went out again, the reply to the first one may be lost
+    resent: bool,
+    // An open that went out again was refused for the session the first one took, they are
+    // reset and it's opened again
+    reopening: bool,
     entries: Vec<EntryInfo>,
     status: Option<OperationStatus>,
     waiting: bool,
     progress: Option<ProgressBar>,
+    resume_at: Option<SystemTime>,
+    receipt: Option<PathBuf>,
+    // Receipt entries of the operations already done, shared by those of the invocation
+    verified: Arc<Mutex<Vec<ReceiptEntry>>>,
+    // Files of a tree waiting for their CRC32, the first one asked for when overlap_sent is set
+    overlapped: Vec<ReceiptEntry>,
+    // Sequence number of the request of the first one and when it went out
+    overlap_sent: Option<(u16, SystemTime)>,
+    // Of the last file asked for, the next one takes the one after it
+    overlap_count: u16,
+    // One of them didn't pass, the tree fails once read
+    overlap_failed: bool,
+    exec_after: Option<Hook>,
+    // The script waiting for the outcome of the operation, which isn't printed then
+    script_reply: Option<script::Reply>,
+    last_crc: Option<u32>,
+    // Reads left of a tree, the next one last
+    queued_reads: Vec<(String, PathBuf, ReadOptions)>,
+    // Files read of a read of many, the bar of each file is shown under it
+    files_progress: Option<(MultiProgress, ProgressBar)>,
+    // Where the reads left of a tree are saved, for --continue
+    queue: Option<QueueFile>,
+    // The first path and output directory of a read of files, they are only saved if it's
+    // aborted, a queue of a tree there is left alone otherwise
+    read_root: Option<(String, Option<PathBuf>)>,
+    // Set by write --reboot and --restart-scripts
+    after_upload: Option<AfterUpload>,
+    verify: VerifyMode,
+    // --verify crc32 was asked for, a file without a CRC32 on the vehicle fails
+    crc_required: bool,
+    // Their CRC32 was refused while the next file was read, they are checked with the last one
+    crc_unavailable: Vec<ReceiptEntry>,
+    write_checksums: bool,
+    sha256: bool,
+    color: bool,
+    size_units: SizeUnits,
+    force: bool,
+    finished: bool,
+    // Of the operations running at the same time on the link, see Operations
+    slot: u8,
 }
 
 impl Controller {
-    pub fn new() -> Self {
+    pub fn new(target_system: u8, target_component: u8) -> Self {
         Self {
+            target_network: 0,
+            target_system,
+            target_component,
             session: 0,
+            chunk_size: MAX_DATA_SIZE,
             last_time: SystemTime::now(),
+            last_request: None,
+            resends: 0,
+            resent: false,
+            reopening: false,
             entries: Vec::new(),
             status: None,
             waiting: false,
             progress: None,
+            resume_at: None,
+            receipt: None,
+            verified: Arc::default(),
+            overlapped: Vec::new(),
+            overlap_sent: None,
+            overlap_count: 0,
+            overlap_failed: false,
+            exec_after: None,
+            script_reply: None,
+            last_crc: None,
+            queued_reads: Vec::new(),
+            files_progress: None,
+            queue: None,
+            read_root: None,
+            after_upload: None,
+            verify: VerifyMode::Crc32,
+            crc_required: false,
+            crc_unavailable: Vec::new(),
+            write_checksums: false,
+            sha256: false,
+            color: true,
+            size_units: SizeUnits::Binary,
+            force: false,
+            finished: false,
+            slot: 0,
         }
     }
 
-    pub fn list_directory(&mut self, path: String) {
+    pub fn set_target_network(&mut self, target_network: u8) {
+        self.target_network = target_network;
+    }
+
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size;
+    }
+
+    // Finished transfers are verified and written to a receipt file
+    pub fn set_receipt(&mut self, receipt: Option<PathBuf>) {
+        self.receipt = receipt;
+    }
+
+    pub fn set_exec_after(&mut self, exec_after: Option<Hook>) {
+        self.exec_after = exec_after;
+    }
+
+    pub fn set_script_reply(&mut self, reply: Option<script::Reply>) {
+        self.script_reply = reply;
+    }
+
+    pub fn script_waiting(&self) -> bool {
+        self.script_reply.is_some()
+    }
+
+    // None checks the CRC32, or the size when the vehicle can't calculate it
+    pub fn set_verify(&mut self, verify: Option<VerifyMode>) {
+        self.verify = verify.unwrap_or(VerifyMode::Crc32);
+        self.crc_required = verify == Some(VerifyMode::Crc32);
+    }
+
+    pub fn set_size_units(&mut self, size_units: SizeUnits) {
+        self.size_units = size_units;
+    }
+
+    // Listings are only colored on a terminal anyway
+    pub fn set_color(&mut self, color: bool) {
+        self.color = color;
+    }
+
+    // Sidecar files with the CRC32 of local copies
+    pub fn set_write_checksums(&mut self, write_checksums: bool) {
+        self.write_checksums = write_checksums;
+    }
+
+    pub fn set_sha256(&mut self, sha256: bool) {
+        self.sha256 = sha256;
+    }
+
+    // The entries of the receipt, for the operations run before this one to stay in it
+    pub fn set_verified(&mut self, verified: Arc<Mutex<Vec<ReceiptEntry>>>) {
+        self.verified = verified;
+    }
+
+    pub fn set_slot(&mut self, slot: u8) {
+        self.slot = slot;
+    }
+
+    // Existing files are overwritten without asking
+    pub fn set_force(&mut self, force: bool) {
+        self.force = force;
+    }
+
+    pub fn set_after_upload(&mut self, after_upload: Option<AfterUpload>) {
+        self.after_upload = after_upload;
+    }
+
+    pub fn list_directory(&mut self, path: String, options: ListOptions) {
         self.status = Some(OperationStatus::ScanningFolder(ScanningFolderStatus {
             path,
+            options,
             offset: 0,
+            skipped: Vec::new(),
         }))
     }
 
-    pub fn read_file(&mut self, path: String) {
-        self.status = Some(OperationStatus::OpeningFile(OpeningFileStatus { path }));
+    pub fn read_file(&mut self, path: String, options: ReadOptions) {
+        let local_path = local_read_path(&path, &options);
+        self.read_file_to(path, local_path, options);
     }
 
-    pub fn reset(&mut self) {
-        self.status = Some(OperationStatus::Reset);
+    // The files are read one after the other as those of a tree
+    pub fn read_files(&mut self, paths: Vec<String>, options: ReadOptions) {
+        self.read_root = Some((paths[0].clone(), options.output_dir.clone()));
+        if paths.len() == 1 {
+            return self.read_file(paths[0].clone(), options);
+        }
+        let multi_progress = MultiProgress::new();
+        let files = multi_progress.add(count_progress_bar(paths.len() as u64));
+        self.files_progress = Some((multi_progress, files));
+        self.queued_reads = paths
+            .into_iter()
+            .rev()
+            .map(|path| {
+                let local_path = local_read_path(&path, &options);
+                (path, local_path, options.clone())
+            })
+            .collect();
+        self.next_queued_read();
     }
 
-    pub fn crc(&mut self, path: String) {
-        self.status = Some(OperationStatus::CalcFileCRC32(CalcFileCRC32Status { path }));
+    fn read_file_to(&mut self, path: String, local_path: PathBuf, options: ReadOptions) {
+        // Skipping the local file or downloading over it is decided once the remote size is known
+        if !self.force && !options.skip_existing && local_path.exists() {
+            match confirm(&format!("overwrite {}?", local_path.display())) {
+                Some(true) => {}
+                Some(false) => {
+                    self.finish(0);
+                    return;
+                }
+                None => {
+                    println!(
+                        "read failed: {} already exists, use --force to overwrite it",
+                        local_path.display()
+                    );
+                    exit(1);
+                }
+            }
+        }
+
+        self.status = Some(OperationStatus::OpeningFile(OpeningFileStatus {
+            path,
+            destination: None,
+            offset: 0,
+            length: None,
+            local_path,
+            options,
+        }));
     }
 
-    pub fn run(&mut self) -> Option<MavlinkFtpPayload> {
-        if self.waiting {
+    // The tree goes into a directory named as its root, in the output directory
+    pub fn read_tree(&mut self, path: String, options: ReadOptions) {
+        self.queue = Some(QueueFile::new(options.output_dir.as_deref(), &path));
+        self.status = Some(OperationStatus::Extracting(ExtractingStatus {
+            root: path.clone(),
+            scanning: Some(path),
+            scan_offset: 0,
+            pending: Vec::new(),
+            files: Vec::new(),
+            options,
+        }));
+    }
+
+    // The log directory is listed first, to know which logs are there
+    pub fn get_log(&mut self, directory: String, number: Option<u32>, options: ReadOptions) {
+        self.status = Some(OperationStatus::FindingLog(FindingLogStatus {
+            directory,
+            number,
+            scan_offset: 0,
+            logs: Vec::new(),
+            has_last_log: false,
+            options,
+        }));
+    }
+
+    // Reads of a tree go one after the other, each as a read of its own
+    fn start_queued_read(&mut self) -> Option<Message> {
+        self.next_queued_read();
+        // Not overwriting the last file ends the tree
+        if self.finished {
             return None;
         }
-        self.waiting = true;
-        match &self.status {
-            Some(OperationStatus::Reset) => {
-                return Some(MavlinkFtpPayload::new_reset_sesions(1, self.session));
+        self.request_next()
+    }
+
+    fn next_queued_read(&mut self) {
+        let Some((path, local_path, options)) = self.queued_reads.pop() else {
+            return;
+        };
+        self.status = None;
+        let progress = self.progress.take();
+        if let Some((multi_progress, files)) = &self.files_progress {
+            if let Some(progress) = progress {
+                multi_progress.remove(&progress);
             }
-            Some(OperationStatus::ScanningFolder(status)) => {
-                return Some(MavlinkFtpPayload::new_list_directory(
-                    1,
-                    self.session,
-                    status.offset as u32,
-                    &status.path,
-                ));
+            let total = files.length().unwrap_or_default();
+            files.set_position(total - self.queued_reads.len() as u64 - 1);
+            files.set_message(path.clone());
+        }
+        if let Some(parent) = local_path.parent() {
+            if let Err(error) = std::fs::create_dir_all(parent) {
+                println!("read failed: {}: {}", parent.display(), error);
+                exit(1);
             }
-            Some(OperationStatus::OpeningFile(status)) => {
-                return Some(MavlinkFtpPayload::new_open_file(
-                    1,
-                    self.session,
-                    &status.path,
-                ));
+        }
+        self.read_file_to(path, local_path, options);
+        self.save_queue();
+    }
+
+    // The reads left by an interrupted read of the same tree or files, the file it stopped in is
+    // read from where it was
+    pub fn continue_read(&mut self, mut paths: Vec<String>, options: ReadOptions, recursive: bool) {
+        let path = paths[0].clone();
+        let queue_file = QueueFile::new(options.output_dir.as_deref(), &path);
+        let queue = match queue_file.load() {
+            Ok(Some(queue)) => queue,
+            Ok(None) => {
+                println!(
+                    "nothing to continue in {}, reading {} from the start",
+                    queue_file.path.display(),
+                    path
+                );
+                return match recursive {
+                    true => self.read_tree(paths.remove(0), options),
+                    false => self.read_files(paths, options),
+                };
             }
-            Some(OperationStatus::CalcFileCRC32(status)) => {
-                return Some(MavlinkFtpPayload::new_calc_file_crc32(
-                    1,
-                    self.session,
-                    &status.path,
-                ));
+            Err(error) => {
+                println!("read failed: {}: {}", queue_file.path.display(), error);
+                exit(1);
             }
-            Some(OperationStatus::ReadingFile(status)) => {
-                return Some(MavlinkFtpPayload::new_read_file(
-                    1,
-                    self.session,
-                    0,
-                    usize::MAX,
-                ));
+        };
+        self.queue = Some(queue_file);
+        self.queued_reads = queue
+            .pending
+            .into_iter()
+            .rev()
+            .map(|(remote_path, local_path)| (remote_path, local_path, options.clone()))
+            .collect();
+        println!(
+            "continuing the read of {}, {} files left",
+            path,
+            self.queued_reads.len() + queue.current.is_some() as usize
+        );
+        match queue.current {
+            Some(partial) => self.resume_partial(partial, options),
+            None if self.queued_reads.is_empty() => {
+                self.finish(0);
             }
-            _ => return None,
+            None => self.next_queued_read(),
         }
     }
 
-    pub fn parse_mavlink_message(
-        &mut self,
-        message: &mavlink::common::FILE_TRANSFER_PROTOCOL_DATA,
-    ) -> Option<mavlink::common::MavMessage> {
-        self.waiting = false;
-        let payload = MavlinkFtpPayload::from_bytes(&message.payload).unwrap();
-        match payload.opcode {
-            MavlinkFtpOpcode::Ack => {
-                match &mut self.status {
-                    Some(OperationStatus::Reset) => {
-                        if payload.req_opcode == MavlinkFtpOpcode::ResetSessions {
-                            self.waiting = false;
-                            self.status = None;
-                        }
-                    }
-                    Some(OperationStatus::ScanningFolder(status)) => {
-                        let entries: Vec<&[u8]> = payload.data.split(|&byte| byte == 0).collect();
+    fn resume_partial(&mut self, partial: Partial, options: ReadOptions) {
+        let offset = partial
+            .offset
+            .min(local_size(&partial.local_path).min(partial.size as u64) as u32);
+        // The local file is ours, it's written again without asking
+        let resumed = match offset {
+            0 => None,
+            _ => OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&partial.local_path)
+                .ok(),
+        };
+        let Some(mut file) = resumed else {
+            self.status = Some(OperationStatus::OpeningFile(OpeningFileStatus {
+                path: partial.remote_path,
+                destination: None,
+                offset: 0,
+                length: None,
+                local_path: partial.local_path,
+                options,
+            }));
+            self.save_queue();
+            return;
+        };
 
-                        if entries.is_empty() {
-                            return None;
-                        }
+        // The checksums of the whole file are calculated as it arrives, the part already read
+        // is read back for them
+        let mut head = vec![0; offset as usize];
+        if let Err(error) = file.read_exact(&mut head) {
+            println!("read failed: {}: {}", partial.local_path.display(), error);
+            exit(1);
+        }
+        let mut sha256 = self.sha256.then(Sha256::default);
+        if let Some(sha256) = &mut sha256 {
+            sha256.update(&head);
+        }
+        println!(
+            "continuing {} at {} of {} bytes",
+            partial.remote_path, offset, partial.size
+        );
+        let progress = progress_bar(partial.size as u64, self.size_units);
+        progress.set_position(offset as u64);
+        self.progress = Some(progress);
+        self.status = Some(OperationStatus::ResumingFile(ResumingFileStatus {
+            reading: ReadingFileStatus {
+                path: partial.remote_path,
+                local_path: partial.local_path,
+                offset,
+                file_size: partial.size,
+                destination: ReadDestination::File(file),
+                fsync_every: options.fsync_every,
+                synced: offset,
+                crc: mavlink_crc32(&head),
+                sha256,
+            },
+            sessions_reset: false,
+        }));
+        self.save_queue();
+    }
 
-                        for entry in entries {
-                            if entry.is_empty() {
-                                continue;
-                            }
-                            status.offset += 1;
+    // The file being read and the ones after it
+    fn save_queue(&mut self) {
+        let Some(queue) = &mut self.queue else {
+            return;
+        };
+        let mut current = None;
+        let mut next = None;
+        match &self.status {
+            Some(OperationStatus::ReadingFile(reading))
+            | Some(OperationStatus::ResumingFile(ResumingFileStatus { reading, .. })) => {
+                current = Some(Partial {
+                    remote_path: reading.path.clone(),
+                    local_path: reading.local_path.clone(),
+                    size: reading.file_size,
+                    offset: reading.offset,
+                })
+            }
+            Some(OperationStatus::OpeningFile(status)) => {
+                next = Some((status.path.as_str(), status.local_path.as_path()))
+            }
+            Some(OperationStatus::ComparingLocal(status)) => {
+                next = Some((status.path.as_str(), status.local_path.as_path()))
+            }
+            _ => {}
+        }
+        let pending = next.into_iter().chain(
+            self.queued_reads
+                .iter()
+                .rev()
+                .map(|(path, local_path, _)| (path.as_str(), local_path.as_path())),
+        );
+        if let Err(error) = queue.save(current.as_ref(), pending) {
+            // Once, the tree is read anyway
+            let line = format!("queue not saved: {}: {}", queue.path.display(), error);
+            self.queue = None;
+            self.println(&line);
+        }
+    }
 
-                            if let Ok(mut result) =
-                                parse_directory_entry(&String::from_utf8_lossy(entry))
-                            {
-                                result.name = format!("{}/{}", status.path, result.name);
-                                self.entries.push(result);
-                            }
-                        }
+    pub fn grep(&mut self, pattern: regex::Regex, path: String) {
+        self.status = Some(OperationStatus::OpeningFile(OpeningFileStatus {
+            path,
+            destination: Some(ReadDestination::Grep(GrepMatcher::new(pattern))),
+            offset: 0,
+            length: None,
+            local_path: PathBuf::new(),
+            options: ReadOptions::default(),
+        }));
+    }
 
-                        if status.offset != 0 {
-                            self.waiting = true;
-                            return Some(mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(
-                                mavlink::common::FILE_TRANSFER_PROTOCOL_DATA {
-                                    target_network: 0,
-                                    target_system: 1,
-                                    target_component: 1,
-                                    payload: MavlinkFtpPayload::new_list_directory(
-                                        1,
-                                        self.session,
-                                        status.offset as u32,
-                                        &status.path,
-                                    )
-                                    .to_bytes(),
-                                },
-                            ));
-                        }
-                    }
-                    Some(OperationStatus::OpeningFile(status)) => {
-                        if payload.size != 4 {
-                            panic!("Wrong size");
-                        }
-                        let file_size = u32::from_le_bytes([
-                            payload.data[0],
-                            payload.data[1],
-                            payload.data[2],
-                            payload.data[3],
-                        ]);
-
-                        self.progress = Some(ProgressBar::new(file_size as u64));
-                        if let Some(progress) = &mut self.progress {
-                            progress.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-                                .unwrap()
-                                .with_key("eta", |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
-                                .progress_chars("#>-")
-                            );
-                        }
+    pub fn hexdump(&mut self, path: String, offset: u32, length: Option<u32>) {
+        self.status = Some(OperationStatus::OpeningFile(OpeningFileStatus {
+            path,
+            destination: Some(ReadDestination::Hexdump(HexDumper::new(offset))),
+            offset,
+            length,
+            local_path: PathBuf::new(),
+            options: ReadOptions::default(),
+        }));
+    }
 
-                        self.status = Some(OperationStatus::ReadingFile(ReadingFileStatus {
-                            path: status.path.clone(),
-                            offset: 0,
-                            file_size,
-                            file: OpenOptions::new()
-                                .write(true)
-                                .create(true)
-                                .open(status.path.split('/').last().unwrap())
-                                .unwrap(),
-                        }));
+    pub fn params_diff(&mut self, saved: Vec<(String, String)>) {
+        self.status = Some(OperationStatus::OpeningFile(OpeningFileStatus {
+            path: PARAM_PATH.to_string(),
+            destination: Some(ReadDestination::Params(ParamDiff::new(saved))),
+            offset: 0,
+            length: None,
+            local_path: PathBuf::new(),
+            options: ReadOptions::default(),
+        }));
+    }
 
-                        return None;
-                    }
-                    Some(OperationStatus::CalcFileCRC32(status)) => {
-                        if payload.req_opcode == MavlinkFtpOpcode::CalcFileCRC32 {
-                            let crc = u32::from_le_bytes([
-                                payload.data[0],
-                                payload.data[1],
-                                payload.data[2],
-                                payload.data[3],
-                            ]);
-                            println!("crc: 0x{:x?}", crc);
-                            exit(0);
-                        }
-                    }
-                    Some(OperationStatus::ReadingFile(status)) => {
-                        let chunk = &payload.data;
-                        status
-                            .file
-                            .seek(SeekFrom::Start(payload.offset.into()))
-                            .unwrap();
-                        status.file.write_all(chunk).unwrap();
-                        status.offset = payload.offset + payload.size as u32;
-                        if let Some(progress) = &self.progress {
-                            progress.set_position(status.offset as u64);
-                        }
+    pub fn reset(&mut self) {
+        self.status = Some(OperationStatus::Reset);
+    }
 
-                        if status.offset < status.file_size {
-                            self.waiting = true;
-                            
-                            if payload.burst_complete == 1 {
-                                return Some(mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(
-                                    mavlink::common::FILE_TRANSFER_PROTOCOL_DATA {
-                                        target_network: 0,
-                                        target_system: 1,
-                                        target_component: 1,
-                                        payload: MavlinkFtpPayload::new_read_file(
-                                            payload.seq_number + 1,
-                                            self.session,
-                                            status.offset,
-                                            usize::MAX,
-                                        )
-                                        .to_bytes(),
-                                    },
-                                ));
-                            } else {
-                                return None;
-                            }
-                        } else {
-                            if let Some(progress) = &self.progress {
-                                progress.finish();
-                            }
+    pub fn crc(&mut self, path: String) {
+        self.status = Some(OperationStatus::CalcFileCRC32(CalcFileCRC32Status { path }));
+    }
 
-                            // Lets get the crc
-                            let mut buffer = Vec::new();
-                            let mut file = std::fs::File::open(status.path.split('/').last().unwrap()).unwrap();
-                            file.read_to_end(&mut buffer).unwrap();
-                            let crc = mavlink_crc32(&buffer);
-                            println!("calculated crc: 0x{:08x}", crc);
+    pub fn stat(&mut self, path: String, json: bool) {
+        self.start_stat(path, json, None, None);
+    }
 
-                            self.status = Some(OperationStatus::ClosingSession);
-                            self.waiting = true;
+    pub fn test(&mut self, path: String, test: PathTest) {
+        self.start_stat(path, false, Some(test), None);
+    }
 
-                            return Some(mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(
-                                mavlink::common::FILE_TRANSFER_PROTOCOL_DATA {
-                                    target_network: 0,
-                                    target_system: 1,
-                                    target_component: 1,
-                                    payload: MavlinkFtpPayload::new_terminate_session(
+    pub fn wait_for(&mut self, path: String, timeout: Duration, min_size: u32) {
+        let wait = WaitFor {
+            // Checked when the timeout was parsed
+            deadline: SystemTime::now()
+                .checked_add(timeout)
+                .unwrap_or(SystemTime::now()),
+            min_size,
+        };
+        self.start_stat(path, false, None, Some(wait));
+    }
+
+    fn start_stat(
+        &mut self,
+        path: String,
+        json: bool,
+        test: Option<PathTest>,
+        wait: Option<WaitFor>,
+    ) {
+        // The root has no parent, it is listed itself
+        let trimmed = path.trim_end_matches('/');
+        let (parent, name) = match trimmed.rsplit_once('/') {
+            _ if trimmed.is_empty() => (path.clone(), String::new()),
+            Some(("", name)) => ("/".to_string(), name.to_string()),
+            Some((parent, name)) => (parent.to_string(), name.to_string()),
+            None => (".".to_string(), trimmed.to_string()),
+        };
+        self.status = Some(OperationStatus::Stat(StatStatus {
+            path,
+            parent,
+            name,
+            stage: StatStage::Listing,
+            offset: 0,
+            entry: None,
+            crc: None,
+            json,
+            test,
+            wait,
+        }));
+    }
+
+    pub fn copy(&mut self, source: String, destination: String) {
+        check_writable("cp", &destination);
+        self.status = Some(OperationStatus::Copying(CopyStatus {
+            source,
+            destination,
+            stage: CopyStage::OpeningSource,
+            offset: 0,
+            written: 0,
+            file_size: 0,
+            buffer: Vec::new(),
+        }));
+    }
+
+    pub fn create_directory(&mut self, path: String, parents: bool) {
+        check_writable("mkdir", &path);
+        let paths = if parents {
+            let mut paths = Vec::new();
+            let mut current = String::new();
+            if path.starts_with('/') {
+                current.push('/');
+            }
+            for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+                if !current.is_empty() && !current.ends_with('/') {
+                    current.push('/');
+                }
+                current.push_str(component);
+                paths.push(current.clone());
+            }
+            paths
+        } else {
+            vec![path]
+        };
+
+        self.status = Some(OperationStatus::CreatingDirectory(
+            CreatingDirectoryStatus {
+                paths,
+                index: 0,
+                parents,
+            },
+        ));
+    }
+
+    // Without --yes a terminal is asked first, the entries of a tree once they are all known
+    pub fn remove(&mut self, path: String, recursive: bool, yes: bool) {
+        check_writable("remove", &path);
+        if !recursive {
+            if !yes && confirm(&format!("remove {}?", path)) == Some(false) {
+                declined("rm");
+            }
+            self.status = Some(OperationStatus::Removing(RemovingStatus::new(vec![(
+                path, false,
+            )])));
+            return;
+        }
+
+        let mut status = RemovingStatus::new(Vec::new());
+        status.scanning = Some(path.clone());
+        status.directories.push(path);
+        status.confirmed = yes;
+        self.status = Some(OperationStatus::Removing(status));
+    }
+
+    pub fn remove_directory(&mut self, path: String, yes: bool) {
+        check_writable("rmdir", &path);
+        if !yes && confirm(&format!("remove directory {}?", path)) == Some(false) {
+            declined("rmdir");
+        }
+        self.status = Some(OperationStatus::Removing(RemovingStatus::new(vec![(
+            path, true,
+        )])));
+    }
+
+    pub fn upload(&mut self, local_paths: Vec<PathBuf>, destination: String) {
+        check_writable("write", &destination);
+        // Like cp, the destination is a directory when there is more than one file
+        let into_directory = local_paths.len() > 1 || destination.ends_with('/');
+        let mut total_size = 0;
+        let mut files = Vec::new();
+        let mut stdin = None;
+        for local_path in local_paths {
+            // "-" is the standard input, read whole before anything is sent
+            if local_path.as_os_str() == STDIN_PATH {
+                if stdin.is_some() || into_directory {
+                    println!(
+                        "write failed: the standard input can only go to a single remote file"
+                    );
+                    exit(1);
+                }
+                let mut content = Vec::new();
+                if let Err(error) = std::io::stdin().read_to_end(&mut content) {
+                    println!("write failed: standard input: {}", error);
+                    exit(1);
+                }
+                total_size += content.len() as u64;
+                stdin = Some(content);
+                files.push((local_path, destination.clone()));
+                continue;
+            }
+            match std::fs::metadata(&local_path) {
+                Ok(metadata) if metadata.is_file() => total_size += metadata.len(),
+                _ => {
+                    println!("write failed: {} is not a file", local_path.display());
+                    exit(1);
+                }
+            }
+            if self.write_checksums {
+                if let Err(error) = sidecar::check(&local_path) {
+                    println!("write failed: {}", error);
+                    exit(1);
+                }
+            }
+
+            let remote_path = if into_directory {
+                let name = local_path.file_name().unwrap().to_string_lossy();
+                format!("{}/{}", destination.trim_end_matches('/'), name)
+            } else {
+                destination.clone()
+            };
+            files.push((local_path, remote_path));
+        }
+
+        let multi_progress = MultiProgress::new();
+        let total = (files.len() > 1)
+            .then(|| multi_progress.add(progress_bar(total_size, self.size_units)));
+        self.status = Some(OperationStatus::Uploading(UploadStatus {
+            files,
+            index: 0,
+            // Remote files are only checked when they may not be overwritten
+            stage: if self.force {
+                UploadStage::Creating
+            } else {
+                UploadStage::Checking
+            },
+            file: None,
+            offset: 0,
+            chunk: Vec::new(),
+            total,
+            multi_progress,
+            skipped: Vec::new(),
+            checked: Vec::new(),
+            stdin,
+        }));
+    }
+
+    pub fn raw(&mut self, opcode: u8, session: u8, offset: u32, size: Option<u8>, data: Vec<u8>) {
+        if data.len() > MAX_DATA_SIZE {
+            println!(
+                "raw failed: {} bytes of data, at most {}",
+                data.len(),
+                MAX_DATA_SIZE
+            );
+            exit(1);
+        }
+
+        let mut request = Vec::new();
+        request.extend_from_slice(&1u16.to_le_bytes());
+        request.push(session);
+        request.push(opcode);
+        request.push(size.unwrap_or(data.len() as u8));
+        // req_opcode, burst_complete and padding
+        request.extend_from_slice(&[0, 0, 0]);
+        request.extend_from_slice(&offset.to_le_bytes());
+        request.extend_from_slice(&data);
+        self.status = Some(OperationStatus::Raw(RawStatus { request }));
+    }
+
+    pub fn conformance(&mut self, path: String) {
+        self.status = Some(OperationStatus::Conformance(Conformance::new(path)));
+    }
+
+    pub fn info(&mut self) {
+        self.start_info(false);
+    }
+
+    // Same probes as info, and how well the link carries them
+    pub fn doctor(&mut self) {
+        self.start_info(true);
+    }
+
+    fn start_info(&mut self, doctor: bool) {
+        self.status = Some(OperationStatus::Info(InfoStatus {
+            stage: InfoStage::RequestingVersion,
+            version: None,
+            ftp_reachable: false,
+            burst_support: false,
+            write_support: false,
+            doctor,
+            unanswered: Vec::new(),
+            probe_file: None,
+            probe_session: 0,
+            probe_refused: false,
+            max_payload: None,
+            round_trips: Vec::new(),
+            resends: 0,
+        }));
+    }
+
+    // The vehicle went away and came back, every open session is gone
+    pub fn vehicle_rebooted(&mut self) {
+        self.waiting = false;
+        self.resume_at = Some(SystemTime::now() + REBOOT_SETTLE_TIME);
+
+        if let Some(OperationStatus::ReadingFile(reading)) = self.status.take() {
+            self.println(&format!(
+                "vehicle reboot detected, resuming {} from offset {}",
+                reading.path, reading.offset
+            ));
+            self.status = Some(OperationStatus::ResumingFile(ResumingFileStatus {
+                reading,
+                sessions_reset: false,
+            }));
+        } else if self.status.is_some() {
+            self.println("vehicle reboot detected, restarting operation");
+        }
+    }
+
+    // Another link to the same vehicle took over, sessions are still open there so what is
+    // waiting for a reply is asked again right away
+    pub fn link_changed(&mut self) -> Option<Message> {
+        self.resends = 0;
+        if !self.waiting {
+            return None;
+        }
+        let request = self.last_request.clone()?;
+        self.last_time = SystemTime::now();
+        Some(request)
+    }
+
+    // Out of time, the open session is closed and what is left of a transfer is told
+    pub fn abort(&mut self) -> Option<Message> {
+        if let Some(progress) = &self.progress {
+            progress.abandon();
+        }
+        match self.status.as_ref()? {
+            OperationStatus::ReadingFile(status) => println!(
+                "read of {} stopped at {} of {} bytes, the partial file is {}",
+                status.path,
+                status.offset,
+                status.file_size,
+                status.local_path.display()
+            ),
+            OperationStatus::Uploading(status) => {
+                if let Some((local_path, remote_path)) = status.files.get(status.index) {
+                    println!(
+                        "write of {} to {} stopped at {} bytes",
+                        local_path.display(),
+                        remote_path,
+                        status.offset
+                    );
+                }
+            }
+            OperationStatus::Copying(_) | OperationStatus::ClosingSession(_) => {}
+            // Nothing else keeps a session open
+            _ => {
+                self.save_reads_left();
+                return None;
+            }
+        }
+        self.save_reads_left();
+        let payload = MavlinkFtpPayload::new_terminate_session(1, self.session);
+        Some(self.ftp_message(payload))
+    }
+
+    // The queue of a read stopped before its end, as for a tree that was interrupted
+    fn save_reads_left(&mut self) {
+        if !matches!(
+            self.status,
+            Some(
+                OperationStatus::OpeningFile(_)
+                    | OperationStatus::ComparingLocal(_)
+                    | OperationStatus::ReadingFile(_)
+                    | OperationStatus::ResumingFile(_)
+            )
+        ) {
+            return;
+        }
+        if let Some((root, output_dir)) = self.read_root.take().filter(|_| self.queue.is_none()) {
+            self.queue = Some(QueueFile::new(output_dir.as_deref(), &root));
+        }
+        self.save_queue();
+        if let Some(queue) = &self.queue {
+            println!(
+                "what is left to read is saved in {}, --continue goes on from there",
+                queue.path.display()
+            );
+        }
+    }
+
+    // A failure ends the invocation, a success leaves the controller ready for the next operation
+    fn finish(&mut self, code: i32) -> Option<Message> {
+        if code != 0 {
+            exit(code);
+        }
+        if !self.queued_reads.is_empty() {
+            return self.start_queued_read();
+        }
+        // The last file of the tree wasn't downloaded, the others may still wait for their CRC32
+        if !self.overlapped.is_empty() || !self.crc_unavailable.is_empty() || self.overlap_failed {
+            return self.verify(Vec::new(), 0);
+        }
+        // The tree is complete, there is nothing left to continue
+        if let Some(queue) = self.queue.take() {
+            queue.remove();
+        }
+        match self.after_upload.take() {
+            Some(AfterUpload::Reboot(confirmed)) => return self.reboot(confirmed),
+            Some(AfterUpload::RestartScripts) => return self.restart_scripts(),
+            None => {}
+        }
+        metrics::finish(0);
+        if let Some(reply) = self.script_reply.take() {
+            let _ = reply.send(script::Outcome {
+                entries: std::mem::take(&mut self.entries),
+                crc: self.last_crc.take(),
+            });
+        }
+        if let Some((_, files)) = self.files_progress.take() {
+            files.finish_with_message("");
+        }
+        self.status = None;
+        self.waiting = false;
+        self.progress = None;
+        self.entries.clear();
+        self.finished = true;
+        None
+    }
+
+    // The autopilot loads scripts and parameter files when it starts
+    fn reboot(&mut self, confirmed: bool) -> Option<Message> {
+        if !confirmed {
+            match confirm("reboot the vehicle so the upload takes effect?") {
+                Some(true) => {}
+                Some(false) => return self.finish(0),
+                None => {
+                    println!("reboot failed: no terminal to confirm it, use --yes");
+                    exit(1);
+                }
+            }
+        }
+        self.status = Some(OperationStatus::Rebooting);
+        self.waiting = true;
+        let command =
+            mavlink::common::MavMessage::COMMAND_LONG(mavlink::common::COMMAND_LONG_DATA {
+                // Reboot the autopilot, leave the onboard computer alone
+                param1: 1.0,
+                command: mavlink::common::MavCmd::MAV_CMD_PREFLIGHT_REBOOT_SHUTDOWN,
+                target_system: self.target_system,
+                target_component: self.target_component,
+                ..Default::default()
+            });
+        Some(command.into())
+    }
+
+    // New Lua scripts are loaded once the scripting engine starts again, the vehicle needs no
+    // reboot for them
+    fn restart_scripts(&mut self) -> Option<Message> {
+        self.status = Some(OperationStatus::RestartingScripts);
+        self.waiting = true;
+        Some(Message::Command(
+            mavlink::ardupilotmega::MavCmd::MAV_CMD_SCRIPTING,
+            mavlink::common::COMMAND_LONG_DATA {
+                param1: mavlink::ardupilotmega::ScriptingCmd::SCRIPTING_CMD_STOP_AND_RESTART as u8
+                    as f32,
+                target_system: self.target_system,
+                target_component: self.target_component,
+                ..Default::default()
+            },
+        ))
+    }
+
+    // Burst replies carry sequence numbers of their own, they go to the operation reading one
+    pub fn bursting(&self) -> bool {
+        match &self.last_request {
+            Some(Message::Common(mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(request))) => {
+                request.payload.get(3) == Some(&(MavlinkFtpOpcode::BurstReadFile as u8))
+            }
+            _ => false,
+        }
+    }
+
+    // True once, when the current operation is done
+    pub fn take_finished(&mut self) -> bool {
+        std::mem::take(&mut self.finished)
+    }
+
+    // Time left until there is something to do even if no message arrives
+    pub fn timeout(&self) -> Option<Duration> {
+        let resume = self.resume_at.map(|resume_at| {
+            resume_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        });
+        let conformance = match &self.status {
+            Some(OperationStatus::Conformance(conformance)) => conformance.timeout(),
+            _ => None,
+        };
+        let stall = (self.waiting && self.last_request.is_some()).then(|| {
+            let elapsed = self.last_time.elapsed().unwrap_or_default();
+            STALL_TIMEOUT.saturating_sub(elapsed)
+        });
+        [resume, conformance, stall, self.overlap_timeout()]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    // Print without breaking the progress bar
+    pub fn println(&self, message: &str) {
+        // A carriage return would move the cursor back over the message
+        let message = message.trim_end_matches(['\r', '\n']);
+        // Hidden progress bars, as when the output is not a terminal, don't print anything
+        let files = self.files_progress.as_ref().map(|(_, files)| files);
+        match self.progress.as_ref().or(files) {
+            Some(progress) if !progress.is_hidden() => progress.println(message),
+            _ => println!("{}", message),
+        }
+    }
+
+    fn ftp_message(&self, payload: MavlinkFtpPayload) -> Message {
+        if let Err(error) = payload.validate() {
+            self.println(&format!("invalid {:?} request: {}", payload.opcode, error));
+            exit(1);
+        }
+
+        self.ftp_raw_message(payload.to_bytes())
+    }
+
+    fn ftp_raw_message(&self, mut payload: Vec<u8>) -> Message {
+        if let Some(seq_number) = payload.get_mut(..2) {
+            let seq = tag_sequence(
+                u16::from_le_bytes([seq_number[0], seq_number[1]]),
+                self.slot,
+            );
+            seq_number.copy_from_slice(&seq.to_le_bytes());
+        }
+        mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(
+            mavlink::common::FILE_TRANSFER_PROTOCOL_DATA {
+                target_network: self.target_network,
+                target_system: self.target_system,
+                target_component: self.target_component,
+                payload,
+            },
+        )
+        .into()
+    }
+
+    // The request of the operation, and the overlapped CRC32 once it's due
+    pub fn run(&mut self) -> Vec<Message> {
+        let request = self.next_request();
+        let request = self.track(request);
+        request.into_iter().chain(self.overlap_request()).collect()
+    }
+
+    // Sent beside the requests of the next file, with its own sequence number and timeout, so
+    // it's never resent as one of them
+    fn overlap_request(&mut self) -> Option<Message> {
+        let entry = self.overlapped.first()?;
+        let sequence = match self.overlap_sent {
+            Some((_, sent)) if sent.elapsed().unwrap_or_default() < OVERLAP_TIMEOUT => return None,
+            // Asked again as the same request, so a late reply still counts
+            Some((sequence, _)) => sequence,
+            None => {
+                self.overlap_count = (self.overlap_count + 1) % OVERLAP_SEQUENCES;
+                OVERLAP_SEQUENCE + self.overlap_count
+            }
+        };
+        self.overlap_sent = Some((sequence, SystemTime::now()));
+        // The CRC32 is calculated from the path, without a session
+        let payload = MavlinkFtpPayload::new_calc_file_crc32(sequence, 0, &entry.remote_path);
+        Some(self.ftp_message(payload))
+    }
+
+    // Time left until the overlapped CRC32 is due, right away for a file not asked for yet
+    fn overlap_timeout(&self) -> Option<Duration> {
+        self.overlapped.first()?;
+        Some(self.overlap_sent.map_or(Duration::ZERO, |(_, sent)| {
+            OVERLAP_TIMEOUT.saturating_sub(sent.elapsed().unwrap_or_default())
+        }))
+    }
+
+    fn is_overlap_reply(payload: &MavlinkFtpPayload) -> bool {
+        payload.req_opcode == MavlinkFtpOpcode::CalcFileCRC32
+            && (OVERLAP_SEQUENCE + 1..=OVERLAP_SEQUENCE + OVERLAP_SEQUENCES)
+                .contains(&payload.seq_number)
+    }
+
+    // A duplicate, or the late reply to a request that was sent again and answered already. The
+    // replies of a burst are numbered by the vehicle, their offsets tell where they go instead
+    fn is_stale_reply(&self, payload: &MavlinkFtpPayload) -> bool {
+        if self.bursting() {
+            return payload.req_opcode != MavlinkFtpOpcode::BurstReadFile;
+        }
+        let Some(Message::Common(mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(request))) =
+            &self.last_request
+        else {
+            return false;
+        };
+        let Some(&[low, high]) = request.payload.get(..2) else {
+            return false;
+        };
+        payload.seq_number != untag_sequence(u16::from_le_bytes([low, high])) + 1
+    }
+
+    fn parse_overlap_response(&mut self, payload: &MavlinkFtpPayload) {
+        // Late, for a file asked for before, or checked with the last one of the tree already
+        match self.overlap_sent {
+            Some((sequence, _)) if payload.seq_number == sequence + 1 => self.overlap_sent = None,
+            _ => return,
+        }
+        if self.overlapped.is_empty() {
+            return;
+        }
+        let mut entry = self.overlapped.remove(0);
+        entry.remote_crc = match payload.opcode {
+            MavlinkFtpOpcode::Ack => payload.data_as_u32(),
+            _ => None,
+        };
+        // Its size needs a session, which is taken by the next file
+        if entry.remote_crc.is_none() && !self.crc_required {
+            self.crc_unavailable.push(entry);
+            return;
+        }
+        notify::transferred(&entry);
+        if !entry.passed() {
+            self.println(&format!("crc {} on {}", entry.result(), entry.remote_path));
+            self.overlap_failed = true;
+        } else {
+            cloud::queue(&entry);
+            if let Some(command) = &self.exec_after {
+                if !hook::run(command, &entry) {
+                    self.overlap_failed = true;
+                }
+            }
+        }
+        if self.receipt.is_some() {
+            self.verified.lock().unwrap().push(entry);
+        }
+    }
+
+    pub fn parse_mavlink_message(&mut self, message: &Message) -> Option<Message> {
+        if let Message::Common(mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(_)) = message {
+            if let Some(OperationStatus::Info(status)) = &mut self.status {
+                status
+                    .round_trips
+                    .push(self.last_time.elapsed().unwrap_or_default());
+            }
+            self.last_time = SystemTime::now();
+            self.resent = self.resends > 0;
+            self.resends = 0;
+        }
+        let request = self.parse_message(message);
+        if self.queue.as_ref().is_some_and(QueueFile::due) {
+            self.save_queue();
+        }
+        self.track(request)
+    }
+
+    // Keeps what is sent, so it can go out again if it stalls
+    fn track(&mut self, request: Option<Message>) -> Option<Message> {
+        if let Some(request) = &request {
+            self.last_request = Some(request.clone());
+            self.last_time = SystemTime::now();
+        }
+        request
+    }
+
+    // The request or its reply got lost, the same request goes out again a few times
+    fn resend_stalled(&mut self) -> Option<Message> {
+        let request = self.last_request.clone()?;
+        let elapsed = self.last_time.elapsed().unwrap_or_default();
+        if elapsed < STALL_TIMEOUT {
+            return None;
+        }
+        if self.resends >= MAX_RESENDS {
+            if let Some(OperationStatus::Info(status)) = &mut self.status {
+                if status.doctor {
+                    self.resends = 0;
+                    if status.skip_probe() {
+                        return self.finish_info();
+                    }
+                    return self.request_next();
+                }
+            }
+            self.println(&format!(
+                "no reply from the vehicle, gave up after {} resends",
+                MAX_RESENDS
+            ));
+            exit(1);
+        }
+        self.resends += 1;
+        metrics::retry();
+        if let Some(OperationStatus::Info(status)) = &mut self.status {
+            status.resends += 1;
+        }
+        self.println(&format!(
+            "no reply in {:.1}s, resending ({}/{})",
+            elapsed.as_secs_f32(),
+            self.resends,
+            MAX_RESENDS
+        ));
+        Some(request)
+    }
+
+    fn next_request(&mut self) -> Option<Message> {
+        if let Some(resume_at) = self.resume_at {
+            if SystemTime::now() < resume_at {
+                return None;
+            }
+            self.resume_at = None;
+        }
+
+        // Checks time out on their own, so they don't go through waiting
+        if let Some(OperationStatus::Conformance(conformance)) = &mut self.status {
+            let request = conformance.poll();
+            return self.conformance_request(request);
+        }
+
+        if self.waiting {
+            return self.resend_stalled();
+        }
+
+        self.waiting = true;
+        if let Some(OperationStatus::Info(InfoStatus {
+            stage: InfoStage::RequestingVersion,
+            ..
+        })) = self.status
+        {
+            return Some(self.request_autopilot_version());
+        }
+        if let Some(OperationStatus::Raw(status)) = &self.status {
+            return Some(self.ftp_raw_message(status.request.clone()));
+        }
+        self.next_payload().map(|payload| self.ftp_message(payload))
+    }
+
+    fn next_payload(&self) -> Option<MavlinkFtpPayload> {
+        match &self.status {
+            Some(OperationStatus::Reset) => {
+                Some(MavlinkFtpPayload::new_reset_sesions(1, self.session))
+            }
+            Some(OperationStatus::ScanningFolder(status)) => Some(
+                MavlinkFtpPayload::new_list_directory(1, self.session, status.offset, &status.path),
+            ),
+            Some(OperationStatus::OpeningFile(status)) => Some(MavlinkFtpPayload::new_open_file(
+                1,
+                self.session,
+                &status.path,
+            )),
+            Some(OperationStatus::CalcFileCRC32(status)) => Some(
+                MavlinkFtpPayload::new_calc_file_crc32(1, self.session, &status.path),
+            ),
+            Some(OperationStatus::ReadingFile(status)) => Some(MavlinkFtpPayload::new_read_file(
+                1,
+                self.session,
+                status.offset,
+                self.chunk_size,
+            )),
+            Some(OperationStatus::ComparingLocal(status)) => Some(match status.open_session {
+                Some(session) => MavlinkFtpPayload::new_terminate_session(1, session),
+                None => MavlinkFtpPayload::new_calc_file_crc32(1, self.session, &status.path),
+            }),
+            Some(OperationStatus::ResumingFile(status)) => {
+                if status.sessions_reset {
+                    Some(MavlinkFtpPayload::new_open_file(
+                        1,
+                        self.session,
+                        &status.reading.path,
+                    ))
+                } else {
+                    Some(MavlinkFtpPayload::new_reset_sesions(1, self.session))
+                }
+            }
+            Some(OperationStatus::Copying(status)) => Some(match status.stage {
+                CopyStage::OpeningSource => {
+                    MavlinkFtpPayload::new_open_file(1, self.session, &status.source)
+                }
+                CopyStage::ReadingSource => MavlinkFtpPayload::new_read_file(
+                    1,
+                    self.session,
+                    status.offset,
+                    self.chunk_size,
+                ),
+                CopyStage::ClosingSource | CopyStage::ClosingDestination => {
+                    MavlinkFtpPayload::new_terminate_session(1, self.session)
+                }
+                CopyStage::OpeningDestination => {
+                    if status.written == 0 {
+                        MavlinkFtpPayload::new_create_file(1, self.session, &status.destination)
+                    } else {
+                        MavlinkFtpPayload::new_open_file_wo(1, self.session, &status.destination)
+                    }
+                }
+                CopyStage::WritingDestination => {
+                    let size = status.buffer.len().min(self.chunk_size);
+                    MavlinkFtpPayload::new_write_file(
+                        1,
+                        self.session,
+                        status.written,
+                        &status.buffer[..size],
+                    )
+                }
+            }),
+            Some(OperationStatus::CreatingDirectory(status)) => status
+                .paths
+                .get(status.index)
+                .map(|path| MavlinkFtpPayload::new_create_directory(1, self.session, path)),
+            Some(OperationStatus::Removing(status)) => match &status.scanning {
+                Some(path) => Some(MavlinkFtpPayload::new_list_directory(
+                    1,
+                    self.session,
+                    status.scan_offset,
+                    path,
+                )),
+                None => status.queue.get(status.index).map(|(path, directory)| {
+                    if *directory {
+                        MavlinkFtpPayload::new_remove_directory(1, self.session, path)
+                    } else {
+                        MavlinkFtpPayload::new_remove_file(1, self.session, path)
+                    }
+                }),
+            },
+            Some(OperationStatus::Extracting(status)) => status.scanning.as_ref().map(|path| {
+                MavlinkFtpPayload::new_list_directory(1, self.session, status.scan_offset, path)
+            }),
+            Some(OperationStatus::FindingLog(status)) => {
+                Some(MavlinkFtpPayload::new_list_directory(
+                    1,
+                    self.session,
+                    status.scan_offset,
+                    &status.directory,
+                ))
+            }
+            Some(OperationStatus::Uploading(status)) => Some(match status.stage {
+                UploadStage::Checking => {
+                    MavlinkFtpPayload::new_open_file(1, self.session, &status.files[status.index].1)
+                }
+                UploadStage::ClosingExisting(_) => {
+                    MavlinkFtpPayload::new_terminate_session(1, self.session)
+                }
+                UploadStage::Creating => MavlinkFtpPayload::new_create_file(
+                    1,
+                    self.session,
+                    &status.files[status.index].1,
+                ),
+                UploadStage::Writing => {
+                    MavlinkFtpPayload::new_write_file(1, self.session, status.offset, &status.chunk)
+                }
+                UploadStage::Closing => MavlinkFtpPayload::new_terminate_session(1, self.session),
+            }),
+            Some(OperationStatus::Stat(status)) => Some(match status.stage {
+                StatStage::Listing => MavlinkFtpPayload::new_list_directory(
+                    1,
+                    self.session,
+                    status.offset,
+                    &status.parent,
+                ),
+                StatStage::Crc => {
+                    MavlinkFtpPayload::new_calc_file_crc32(1, self.session, &status.path)
+                }
+            }),
+            Some(OperationStatus::Verifying(status)) => {
+                status.entries.get(status.index).map(|entry| {
+                    match (&self.verify, status.open_session) {
+                        (_, Some(session)) => MavlinkFtpPayload::new_terminate_session(1, session),
+                        (verify, None) if *verify == VerifyMode::Size || status.size_fallback => {
+                            MavlinkFtpPayload::new_open_file(1, self.session, &entry.remote_path)
+                        }
+                        _ => MavlinkFtpPayload::new_calc_file_crc32(
+                            1,
+                            self.session,
+                            &entry.remote_path,
+                        ),
+                    }
+                })
+            }
+            Some(OperationStatus::Info(status)) => match status.stage {
+                InfoStage::RequestingVersion => None,
+                InfoStage::ProbingList => Some(MavlinkFtpPayload::new_list_directory(
+                    1,
+                    self.session,
+                    0,
+                    "/",
+                )),
+                InfoStage::ProbingBurst => Some(MavlinkFtpPayload::new_read_file(
+                    1,
+                    PROBE_SESSION,
+                    0,
+                    MAX_DATA_SIZE,
+                )),
+                InfoStage::ProbingWrite => {
+                    Some(MavlinkFtpPayload::new_write_file(1, PROBE_SESSION, 0, &[]))
+                }
+                InfoStage::OpeningProbeFile => status
+                    .probe_file
+                    .as_ref()
+                    .map(|(path, _)| MavlinkFtpPayload::new_open_file(1, self.session, path)),
+                InfoStage::ResettingProbeSessions => {
+                    Some(MavlinkFtpPayload::new_reset_sesions(1, self.session))
+                }
+                InfoStage::ReadingProbeFile => Some(MavlinkFtpPayload::new_read_file_chunk(
+                    1,
+                    status.probe_session,
+                    0,
+                    MAX_DATA_SIZE,
+                )),
+                InfoStage::ClosingProbeFile => Some(MavlinkFtpPayload::new_terminate_session(
+                    1,
+                    status.probe_session,
+                )),
+            },
+            _ => None,
+        }
+    }
+
+    // Sends the request of the current operation right away, instead of waiting for run()
+    fn request_next(&mut self) -> Option<Message> {
+        self.waiting = true;
+        self.next_payload().map(|payload| self.ftp_message(payload))
+    }
+
+    fn request_autopilot_version(&self) -> Message {
+        mavlink::common::MavMessage::COMMAND_LONG(mavlink::common::COMMAND_LONG_DATA {
+            param1: AUTOPILOT_VERSION_MESSAGE_ID as f32,
+            command: mavlink::common::MavCmd::MAV_CMD_REQUEST_MESSAGE,
+            target_system: self.target_system,
+            target_component: self.target_component,
+            ..Default::default()
+        })
+        .into()
+    }
+
+    fn parse_message(&mut self, message: &Message) -> Option<Message> {
+        let message = match message {
+            Message::Common(message) => message,
+            Message::CommandAck(mavlink::ardupilotmega::MavCmd::MAV_CMD_SCRIPTING, result) => {
+                if !matches!(self.status, Some(OperationStatus::RestartingScripts)) {
+                    return None;
+                }
+                if *result != mavlink::common::MavResult::MAV_RESULT_ACCEPTED {
+                    println!("scripting restart failed: {}", result_name(*result));
+                    exit(1);
+                }
+                println!("scripts restarted");
+                return self.finish(0);
+            }
+            _ => return None,
+        };
+        match message {
+            mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(message) => {
+                self.parse_ftp_message(message)
+            }
+            mavlink::common::MavMessage::AUTOPILOT_VERSION(version) => {
+                if let Some(OperationStatus::Info(status)) = &mut self.status {
+                    if let InfoStage::RequestingVersion = status.stage {
+                        status.version = Some(version.clone());
+                        status.stage = InfoStage::ProbingList;
+                        self.waiting = false;
+                    }
+                }
+                None
+            }
+            mavlink::common::MavMessage::COMMAND_ACK(ack)
+                if ack.command == mavlink::common::MavCmd::MAV_CMD_PREFLIGHT_REBOOT_SHUTDOWN =>
+            {
+                if !matches!(self.status, Some(OperationStatus::Rebooting)) {
+                    return None;
+                }
+                // ArduPilot refuses it while armed
+                if ack.result != mavlink::common::MavResult::MAV_RESULT_ACCEPTED {
+                    println!("reboot failed: {}", result_name(ack.result));
+                    exit(1);
+                }
+                println!("vehicle rebooting");
+                self.finish(0)
+            }
+            mavlink::common::MavMessage::COMMAND_ACK(ack) => {
+                if ack.command != mavlink::common::MavCmd::MAV_CMD_REQUEST_MESSAGE
+                    || ack.result == mavlink::common::MavResult::MAV_RESULT_ACCEPTED
+                {
+                    return None;
+                }
+                // No version available, but the FTP capabilities can still be probed
+                if let Some(OperationStatus::Info(status)) = &mut self.status {
+                    if let InfoStage::RequestingVersion = status.stage {
+                        status.stage = InfoStage::ProbingList;
+                        self.waiting = false;
+                    }
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn parse_copy_response(&mut self, payload: &MavlinkFtpPayload) -> Option<Message> {
+        let Some(OperationStatus::Copying(status)) = &mut self.status else {
+            return None;
+        };
+
+        let expected_opcode = match status.stage {
+            CopyStage::OpeningSource => MavlinkFtpOpcode::OpenFileRO,
+            CopyStage::ReadingSource => MavlinkFtpOpcode::BurstReadFile,
+            CopyStage::ClosingSource | CopyStage::ClosingDestination => {
+                MavlinkFtpOpcode::TerminateSession
+            }
+            CopyStage::OpeningDestination if status.written == 0 => MavlinkFtpOpcode::CreateFile,
+            CopyStage::OpeningDestination => MavlinkFtpOpcode::OpenFileWO,
+            CopyStage::WritingDestination => MavlinkFtpOpcode::WriteFile,
+        };
+        if payload.req_opcode != expected_opcode {
+            // Leftovers of a burst that was interrupted when the window got full
+            self.waiting = true;
+            return None;
+        }
+
+        if payload.opcode == MavlinkFtpOpcode::Nak {
+            let nak = payload.nak();
+            let path = match status.stage {
+                CopyStage::OpeningSource | CopyStage::ReadingSource => status.source.clone(),
+                _ => status.destination.clone(),
+            };
+            self.println(&format!(
+                "cp failed on {}: {}",
+                path,
+                nak.map_or("Invalid NAK".to_string(), |nak| nak.to_string())
+            ));
+            exit(1);
+        }
+
+        match status.stage {
+            CopyStage::OpeningSource => {
+                self.session = payload.session;
+                if let (0, Some(file_size)) = (status.written, payload.data_as_u32()) {
+                    status.file_size = file_size;
+                    self.progress = Some(progress_bar(status.file_size as u64, self.size_units));
+                }
+                status.stage = if status.offset < status.file_size {
+                    CopyStage::ReadingSource
+                } else {
+                    CopyStage::ClosingSource
+                };
+            }
+            CopyStage::ReadingSource => {
+                if payload.offset == status.offset {
+                    let remaining = (status.file_size - status.offset) as usize;
+                    let chunk = &payload.data[..payload.data.len().min(remaining)];
+                    status.buffer.extend_from_slice(chunk);
+                    status.offset += chunk.len() as u32;
+                }
+
+                if status.offset >= status.file_size || status.buffer.len() >= COPY_WINDOW_SIZE {
+                    status.stage = CopyStage::ClosingSource;
+                } else if payload.burst_complete == 0 {
+                    self.waiting = true;
+                    return None;
+                }
+            }
+            CopyStage::ClosingSource => status.stage = CopyStage::OpeningDestination,
+            CopyStage::OpeningDestination => {
+                self.session = payload.session;
+                status.stage = if status.buffer.is_empty() {
+                    CopyStage::ClosingDestination
+                } else {
+                    CopyStage::WritingDestination
+                };
+            }
+            CopyStage::WritingDestination => {
+                let size = status.buffer.len().min(self.chunk_size);
+                status.buffer.drain(..size);
+                status.written += size as u32;
+                if let Some(progress) = &self.progress {
+                    progress.set_position(status.written as u64);
+                }
+                if status.buffer.is_empty() {
+                    status.stage = CopyStage::ClosingDestination;
+                }
+            }
+            CopyStage::ClosingDestination => {
+                if status.written >= status.file_size {
+                    if let Some(progress) = &self.progress {
+                        progress.finish();
+                    }
+                    println!(
+                        "copied {} to {} ({})",
+                        status.source,
+                        status.destination,
+                        format_size(status.file_size as u64, self.size_units)
+                    );
+                    return self.finish(0);
+                }
+                status.stage = CopyStage::OpeningSource;
+            }
+        }
+
+        self.request_next()
+    }
+
+    fn parse_create_directory_response(&mut self, payload: &MavlinkFtpPayload) -> Option<Message> {
+        let Some(OperationStatus::CreatingDirectory(status)) = &mut self.status else {
+            return None;
+        };
+
+        if payload.req_opcode != MavlinkFtpOpcode::CreateDirectory {
+            return None;
+        }
+
+        if payload.opcode == MavlinkFtpOpcode::Nak {
+            let nak = payload.nak();
+            // With --parents, existing directories are part of the path
+            if !(status.parents && nak == Some(MavlinkFtpNak::FileExists)) {
+                println!(
+                    "mkdir failed on {}: {}",
+                    status.paths[status.index],
+                    nak.map_or("Invalid NAK".to_string(), |nak| nak.to_string())
+                );
+                exit(1);
+            }
+        }
+
+        status.index += 1;
+        if status.index >= status.paths.len() {
+            return self.finish(0);
+        }
+
+        self.request_next()
+    }
+
+    fn parse_remove_response(&mut self, payload: &MavlinkFtpPayload) -> Option<Message> {
+        let Some(OperationStatus::Removing(status)) = &mut self.status else {
+            return None;
+        };
+        let nak = payload.nak();
+
+        if payload.req_opcode == MavlinkFtpOpcode::ListDirectory {
+            let directory = status.scanning.clone()?;
+
+            if payload.opcode == MavlinkFtpOpcode::Ack {
+                let (count, entries) = walk_page(&directory, &payload.data);
+                status.scan_offset += count;
+                for (path, entry_type, _) in entries {
+                    match entry_type {
+                        EntryType::File => status.queue.push((path, false)),
+                        EntryType::Directory => status.found.push(path),
+                        EntryType::Skip => {}
+                    }
+                }
+                return self.request_next();
+            }
+
+            if nak != Some(MavlinkFtpNak::Eof) {
+                let reason = nak.map_or("Invalid NAK".to_string(), |nak| nak.to_string());
+                if status.directories.len() == 1 && status.scan_offset == 0 {
+                    // The path is not a directory, remove it as a file
+                    status.directories.clear();
+                    status.queue.push((directory, false));
+                } else {
+                    status
+                        .failed
+                        .push((directory, format!("listing failed, {}", reason)));
+                }
+            }
+
+            status.directories.append(&mut status.found.clone());
+            status.pending.extend(status.found.drain(..).rev());
+            status.scanning = status.pending.pop();
+            status.scan_offset = 0;
+
+            if status.scanning.is_none() {
+                let directories = std::mem::take(&mut status.directories);
+                status
+                    .queue
+                    .extend(directories.into_iter().rev().map(|path| (path, true)));
+                if !status.confirmed && std::io::stdin().is_terminal() {
+                    for (path, directory) in &status.queue {
+                        println!("{} {}", if *directory { "D" } else { "F" }, path);
+                    }
+                    let question = format!("remove these {} entries?", status.queue.len());
+                    if confirm(&question) == Some(false) {
+                        declined("rm");
+                    }
+                }
+                self.progress = Some(count_progress_bar(status.queue.len() as u64));
+            }
+
+            return self.request_next();
+        }
+
+        if !matches!(
+            payload.req_opcode,
+            MavlinkFtpOpcode::RemoveFile | MavlinkFtpOpcode::RemoveDirectory
+        ) {
+            return None;
+        }
+
+        let (path, _) = status.queue.get(status.index)?;
+        if payload.opcode == MavlinkFtpOpcode::Nak {
+            status.failed.push((
+                path.clone(),
+                nak.map_or("Invalid NAK".to_string(), |nak| nak.to_string()),
+            ));
+        }
+        status.index += 1;
+        if let Some(progress) = &self.progress {
+            progress.set_position(status.index as u64);
+            progress.set_message(path.clone());
+        }
+
+        if status.index < status.queue.len() {
+            return self.request_next();
+        }
+
+        if let Some(progress) = &self.progress {
+            progress.finish_and_clear();
+        }
+        if status.queue.len() > 1 {
+            let removed = status.queue.len() - status.failed.len();
+            println!("removed {} of {} entries", removed, status.queue.len());
+        }
+        for (path, reason) in &status.failed {
+            println!("could not remove {}: {}", path, reason);
+        }
+        let code = if status.failed.is_empty() { 0 } else { 1 };
+        self.finish(code)
+    }
+
+    fn parse_extract_response(&mut self, payload: &MavlinkFtpPayload) -> Option<Message> {
+        let Some(OperationStatus::Extracting(status)) = &mut self.status else {
+            return None;
+        };
+        if payload.req_opcode != MavlinkFtpOpcode::ListDirectory {
+            return None;
+        }
+        let directory = status.scanning.clone()?;
+
+        if payload.opcode == MavlinkFtpOpcode::Ack {
+            // A page received twice, as after a resend, is counted once
+            if payload.offset != status.scan_offset {
+                return None;
+            }
+            let (count, entries) = walk_page(&directory, &payload.data);
+            status.scan_offset += count;
+            for (path, entry_type, size) in entries {
+                match entry_type {
+                    EntryType::File => status.files.push((path, size)),
+                    EntryType::Directory => status.pending.push(path),
+                    EntryType::Skip => {}
+                }
+            }
+            return self.request_next();
+        }
+
+        let nak = payload.nak();
+        if nak != Some(MavlinkFtpNak::Eof) {
+            let reason = nak.map_or("Invalid NAK".to_string(), |nak| nak.to_string());
+            if directory == status.root && status.scan_offset == 0 {
+                // The path is not a directory, read it as a file
+                // Its size is only known once it's opened
+                status.files.push((directory, 0));
+            } else {
+                println!("skipping {}: listing failed, {}", directory, reason);
+            }
+        }
+        status.scanning = status.pending.pop();
+        status.scan_offset = 0;
+        if status.scanning.is_some() {
+            return self.request_next();
+        }
+
+        let root = status.root.trim_end_matches('/');
+        let mut base = status.options.output_dir.clone().unwrap_or_default();
+        if let Some(name) = local_file_name(root) {
+            base.push(name);
+        }
+        let mut files = std::mem::take(&mut status.files);
+        files.sort();
+        if files.len() != 1 || files[0].0 != status.root {
+            println!("reading {} files of {}", files.len(), status.root);
+        }
+        let mut reads = Vec::new();
+        let mut needed = 0;
+        for (path, size) in files {
+            let mut local_path = base.clone();
+            for component in path[root.len()..].split('/').filter(|c| !c.is_empty()) {
+                local_path.push(local_file_name(component).unwrap_or_default());
+            }
+            needed += (size as u64).saturating_sub(local_size(&local_path));
+            reads.push((path, local_path, status.options.clone()));
+        }
+        if let Err(error) = check_space(&base, needed, self.size_units) {
+            println!("read failed: {}: {}", status.root, error);
+            exit(1);
+        }
+        reads.reverse();
+        self.queued_reads = reads;
+        match self.queued_reads.is_empty() {
+            true => self.finish(0),
+            false => self.start_queued_read(),
+        }
+    }
+
+    fn parse_find_log_response(&mut self, payload: &MavlinkFtpPayload) -> Option<Message> {
+        let Some(OperationStatus::FindingLog(status)) = &mut self.status else {
+            return None;
+        };
+        if payload.req_opcode != MavlinkFtpOpcode::ListDirectory {
+            return None;
+        }
+
+        if payload.opcode == MavlinkFtpOpcode::Ack {
+            // A page received twice, as after a resend, is counted once
+            if payload.offset != status.scan_offset {
+                return None;
+            }
+            let (count, entries) = walk_page(&status.directory, &payload.data);
+            status.scan_offset += count;
+            for (path, entry_type, _) in entries {
+                let name = path.rsplit('/').next().unwrap_or_default();
+                if !matches!(entry_type, EntryType::File) {
+                    continue;
+                }
+                if name.eq_ignore_ascii_case(LAST_LOG_NAME) {
+                    status.has_last_log = true;
+                } else if let Some(number) = log_number(name) {
+                    status.logs.push((number, name.to_string()));
+                }
+            }
+            return self.request_next();
+        }
+
+        let nak = payload.nak();
+        if nak != Some(MavlinkFtpNak::Eof) {
+            println!(
+                "logs failed: {}: {}",
+                status.directory,
+                nak.map_or("Invalid NAK".to_string(), |nak| nak.to_string())
+            );
+            exit(1);
+        }
+        let Some(OperationStatus::FindingLog(status)) = self.status.take() else {
+            return None;
+        };
+        if status.number.is_none() && status.has_last_log {
+            let path = format!(
+                "{}/{}",
+                status.directory.trim_end_matches('/'),
+                LAST_LOG_NAME
+            );
+            self.status = Some(OperationStatus::OpeningFile(OpeningFileStatus {
+                path,
+                destination: Some(ReadDestination::LastLog(LastLogReader {
+                    data: Vec::new(),
+                    finding: status,
+                })),
+                offset: 0,
+                length: None,
+                local_path: PathBuf::new(),
+                options: ReadOptions::default(),
+            }));
+            return self.request_next();
+        }
+        self.queued_reads = vec![log_read(status, None)];
+        self.start_queued_read()
+    }
+
+    fn parse_upload_response(&mut self, payload: &MavlinkFtpPayload) -> Option<Message> {
+        let Some(OperationStatus::Uploading(status)) = &mut self.status else {
+            return None;
+        };
+
+        let expected_opcode = match status.stage {
+            UploadStage::Checking => MavlinkFtpOpcode::OpenFileRO,
+            UploadStage::Creating => MavlinkFtpOpcode::CreateFile,
+            UploadStage::Writing => MavlinkFtpOpcode::WriteFile,
+            UploadStage::Closing | UploadStage::ClosingExisting(_) => {
+                MavlinkFtpOpcode::TerminateSession
+            }
+        };
+        if payload.req_opcode != expected_opcode {
+            return None;
+        }
+
+        let remote_path = status.files[status.index].1.clone();
+        if status.stage == UploadStage::Checking
+            && payload.nak() == Some(MavlinkFtpNak::FileNotFound)
+        {
+            status.stage = UploadStage::Creating;
+            return self.request_next();
+        }
+
+        if payload.opcode == MavlinkFtpOpcode::Nak {
+            let nak = payload.nak();
+            self.println(&format!(
+                "write failed on {}: {}",
+                remote_path,
+                nak.map_or("Invalid NAK".to_string(), |nak| nak.to_string())
+            ));
+            exit(1);
+        }
+
+        match status.stage {
+            UploadStage::Checking => {
+                self.session = payload.session;
+                let question = format!("overwrite {} on the vehicle?", remote_path);
+                let existing = match status.multi_progress.suspend(|| confirm(&question)) {
+                    Some(true) => ExistingFile::Overwrite,
+                    Some(false) => ExistingFile::Skip,
+                    None => ExistingFile::Refuse,
+                };
+                // The file is closed first, only one session may be available
+                status.stage = UploadStage::ClosingExisting(existing);
+                return self.request_next();
+            }
+            UploadStage::ClosingExisting(ExistingFile::Overwrite) => {
+                status.stage = UploadStage::Creating;
+                return self.request_next();
+            }
+            UploadStage::ClosingExisting(ExistingFile::Refuse) => {
+                self.println(&format!(
+                    "write failed on {}: file already exists, use --force to overwrite it",
+                    remote_path
+                ));
+                exit(1);
+            }
+            // Handled as a finished file
+            UploadStage::ClosingExisting(ExistingFile::Skip) | UploadStage::Closing => {}
+            UploadStage::Creating => {
+                self.session = payload.session;
+                let local_path = &status.files[status.index].0;
+                let size = match &status.stdin {
+                    Some(content) if local_path.as_os_str() == STDIN_PATH => content.len() as u64,
+                    _ => {
+                        let file = std::fs::File::open(local_path).unwrap();
+                        let size = file.metadata().unwrap().len();
+                        status.file = Some(file);
+                        size
+                    }
+                };
+                let progress = status
+                    .multi_progress
+                    .add(progress_bar(size, self.size_units));
+                progress.set_message(remote_path.clone());
+                self.progress = Some(progress);
+                status.offset = 0;
+            }
+            UploadStage::Writing => {
+                status.offset += status.chunk.len() as u32;
+                metrics::transferred(status.chunk.len() as u64);
+                if let Some(progress) = &self.progress {
+                    progress.set_position(status.offset as u64);
+                    let total = progress.length().unwrap_or_default();
+                    notify::progress("write", &remote_path, status.offset as u64, total);
+                }
+                if let Some(total) = &status.total {
+                    total.inc(status.chunk.len() as u64);
+                }
+            }
+        }
+
+        if status.stage == UploadStage::ClosingExisting(ExistingFile::Skip) {
+            status
+                .multi_progress
+                .suspend(|| println!("skipped {}", remote_path));
+            if let Some(total) = &status.total {
+                let size = std::fs::metadata(&status.files[status.index].0).map_or(0, |m| m.len());
+                total.dec_length(size);
+            }
+            status.skipped.push(status.index);
+        }
+
+        if matches!(
+            status.stage,
+            UploadStage::Closing | UploadStage::ClosingExisting(_)
+        ) {
+            if let Some(progress) = &self.progress {
+                progress.finish();
+            }
+            // Checked while the next one is uploaded, so --exec-after runs on each file as soon
+            // as it's verified, a check of the size needs the session and waits for the last one
+            let early = self.verify == VerifyMode::Crc32
+                || self.verify == VerifyMode::None && self.exec_after.is_some();
+            if status.stage == UploadStage::Closing
+                && status.index + 1 < status.files.len()
+                && early
+            {
+                let (local_path, remote_path) = status.files[status.index].clone();
+                let entry = upload_entry(&status.stdin, local_path, remote_path, self.sha256);
+                status.checked.push(status.index);
+                if self.verify == VerifyMode::Crc32 {
+                    self.overlapped.push(entry);
+                } else {
+                    notify::transferred(&entry);
+                    if let Some(hook) = &self.exec_after {
+                        if !hook::run(hook, &entry) {
+                            self.overlap_failed = true;
+                        }
+                    }
+                    if self.receipt.is_some() {
+                        self.verified.lock().unwrap().push(entry);
+                    }
+                }
+            }
+            status.index += 1;
+            if status.index >= status.files.len() {
+                if let Some(total) = &status.total {
+                    total.finish();
+                }
+                let skipped = std::mem::take(&mut status.skipped);
+                let checked = std::mem::take(&mut status.checked);
+                let files: Vec<_> = std::mem::take(&mut status.files)
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(index, _)| !skipped.contains(index))
+                    .collect();
+                // Nothing changed on the vehicle
+                if files.is_empty() {
+                    self.after_upload = None;
+                }
+                if self.verify == VerifyMode::None
+                    && self.receipt.is_none()
+                    && self.exec_after.is_none()
+                {
+                    return self.finish(0);
+                }
+                // Uploaded files are read back for their CRC32
+                let entries = files
+                    .into_iter()
+                    .filter(|(index, _)| !checked.contains(index))
+                    .map(|(_, (local_path, remote_path))| {
+                        upload_entry(&status.stdin, local_path, remote_path, self.sha256)
+                    })
+                    .collect();
+                return self.verify(entries, 0);
+            }
+            status.stage = if self.force {
+                UploadStage::Creating
+            } else {
+                UploadStage::Checking
+            };
+            return self.request_next();
+        }
+
+        status.chunk = match (&mut status.file, &status.stdin) {
+            (Some(file), _) => read_chunk(file, status.offset, self.chunk_size),
+            (None, Some(content)) => {
+                let start = (status.offset as usize).min(content.len());
+                content[start..(start + self.chunk_size).min(content.len())].to_vec()
+            }
+            (None, None) => unreachable!("upload without a source"),
+        };
+        status.stage = if status.chunk.is_empty() {
+            UploadStage::Closing
+        } else {
+            UploadStage::Writing
+        };
+        self.request_next()
+    }
+
+    // Transferred files are checked against the vehicle, unless --verify none
+    fn verify(&mut self, mut entries: Vec<ReceiptEntry>, mut exit_code: i32) -> Option<Message> {
+        // More files of the tree to read, waiting for the CRC32 would leave the link idle
+        if self.verify == VerifyMode::Crc32 && !self.queued_reads.is_empty() && exit_code == 0 {
+            self.overlapped.append(&mut entries);
+            return self.finish(0);
+        }
+        // The files without a reply yet are checked with the last one
+        self.overlap_sent = None;
+        self.overlapped.append(&mut entries);
+        entries = std::mem::take(&mut self.overlapped);
+        entries.splice(0..0, self.crc_unavailable.drain(..));
+        if std::mem::take(&mut self.overlap_failed) {
+            exit_code = 1;
+        }
+
+        if entries.is_empty() {
+            return self.finish(exit_code);
+        }
+
+        let downloads = !matches!(self.status, Some(OperationStatus::Uploading(_)));
+        if self.verify == VerifyMode::None {
+            for entry in &entries {
+                notify::transferred(entry);
+                if downloads {
+                    cloud::queue(entry);
+                }
+                if let Some(command) = &self.exec_after {
+                    if !hook::run(command, entry) {
+                        exit_code = 1;
+                    }
+                }
+            }
+            return self.write_receipt(&mut entries, exit_code);
+        }
+
+        self.status = Some(OperationStatus::Verifying(VerifyingStatus {
+            entries,
+            index: 0,
+            exit_code,
+            open_session: None,
+            size_fallback: false,
+            downloads,
+        }));
+        self.request_next()
+    }
+
+    // Every transfer of the invocation ends up in the same receipt
+    fn write_receipt(
+        &mut self,
+        entries: &mut Vec<ReceiptEntry>,
+        exit_code: i32,
+    ) -> Option<Message> {
+        let Some(receipt) = &self.receipt else {
+            return self.finish(exit_code);
+        };
+        let mut verified = self.verified.lock().unwrap();
+        verified.append(entries);
+        if let Err(error) = write_receipt(
+            receipt,
+            self.target_system,
+            self.target_component,
+            &verified,
+        ) {
+            println!("receipt failed: {}: {}", receipt.display(), error);
+            exit(1);
+        }
+        println!("receipt written to {}", receipt.display());
+        // Written again with where they were uploaded, once the uploads are done
+        cloud::keep_receipt(
+            receipt,
+            self.target_system,
+            self.target_component,
+            &verified,
+        );
+        drop(verified);
+        self.finish(exit_code)
+    }
+
+    fn parse_verify_response(&mut self, payload: &MavlinkFtpPayload) -> Option<Message> {
+        let Some(OperationStatus::Verifying(status)) = &mut self.status else {
+            return None;
+        };
+
+        let entry = &mut status.entries[status.index];
+        match payload.req_opcode {
+            MavlinkFtpOpcode::CalcFileCRC32 if payload.opcode == MavlinkFtpOpcode::Ack => {
+                entry.remote_crc = payload.data_as_u32();
+            }
+            MavlinkFtpOpcode::CalcFileCRC32 if !self.crc_required => {
+                println!(
+                    "warning: no crc of {} from the vehicle, checking its size",
+                    entry.remote_path
+                );
+                status.size_fallback = true;
+                return self.request_next();
+            }
+            MavlinkFtpOpcode::CalcFileCRC32 => {}
+            MavlinkFtpOpcode::OpenFileRO if payload.opcode == MavlinkFtpOpcode::Ack => {
+                entry.remote_size = payload.data_as_u32().map(u64::from);
+                status.open_session = Some(payload.session);
+                return self.request_next();
+            }
+            MavlinkFtpOpcode::OpenFileRO => {}
+            MavlinkFtpOpcode::TerminateSession => status.open_session = None,
+            _ => return None,
+        }
+
+        notify::transferred(entry);
+        if !entry.passed() {
+            match (self.verify, status.size_fallback) {
+                (VerifyMode::Size, _) | (_, true) => {
+                    println!("{} on {}", entry.result(), entry.remote_path)
+                }
+                _ => println!("crc {} on {}", entry.result(), entry.remote_path),
+            }
+            status.exit_code = 1;
+        } else {
+            if status.downloads {
+                cloud::queue(entry);
+            }
+            if let Some(command) = &self.exec_after {
+                if !hook::run(command, entry) {
+                    status.exit_code = 1;
+                }
+            }
+        }
+
+        status.index += 1;
+        status.size_fallback = false;
+        if status.index < status.entries.len() {
+            return self.request_next();
+        }
+
+        let mut entries = std::mem::take(&mut status.entries);
+        let code = status.exit_code;
+        self.write_receipt(&mut entries, code)
+    }
+
+    fn parse_compare_local_response(&mut self, payload: &MavlinkFtpPayload) -> Option<Message> {
+        let Some(OperationStatus::ComparingLocal(status)) = &mut self.status else {
+            return None;
+        };
+
+        match payload.req_opcode {
+            MavlinkFtpOpcode::TerminateSession => {
+                status.open_session = None;
+                return self.request_next();
+            }
+            MavlinkFtpOpcode::CalcFileCRC32 => {}
+            _ => return None,
+        }
+
+        let remote_crc = match payload.opcode {
+            MavlinkFtpOpcode::Ack => payload.data_as_u32(),
+            _ => None,
+        };
+        let Some(OperationStatus::ComparingLocal(status)) = self.status.take() else {
+            return None;
+        };
+        if remote_crc.is_some() && remote_crc == status.local_crc {
+            println!(
+                "skipping {}, {} has the same size and crc",
+                status.path,
+                status.local_path.display()
+            );
+            return self.finish(0);
+        }
+        println!(
+            "{} differs from {}, downloading it again",
+            status.local_path.display(),
+            status.path
+        );
+        // Opened again without the check, the size would match again
+        self.status = Some(OperationStatus::OpeningFile(OpeningFileStatus {
+            path: status.path,
+            destination: None,
+            offset: 0,
+            length: None,
+            local_path: status.local_path,
+            options: ReadOptions {
+                skip_existing: false,
+                ..status.options
+            },
+        }));
+        self.request_next()
+    }
+
+    fn parse_stat_response(&mut self, payload: &MavlinkFtpPayload) -> Option<Message> {
+        let Some(OperationStatus::Stat(status)) = &mut self.status else {
+            return None;
+        };
+        let nak = payload.nak();
+
+        match status.stage {
+            StatStage::Listing => {
+                if payload.req_opcode != MavlinkFtpOpcode::ListDirectory
+                    || payload.offset != status.offset
+                {
+                    return None;
+                }
+                if payload.opcode == MavlinkFtpOpcode::Ack {
+                    if status.name.is_empty() {
+                        status.entry = Some(root_entry(&status.path));
+                    }
+                    for entry in payload.data.split(|&byte| byte == 0) {
+                        if entry.is_empty() || status.entry.is_some() {
+                            continue;
+                        }
+                        status.offset += 1;
+                        match parse_directory_entry(entry) {
+                            Ok(entry) if entry.name == status.name => status.entry = Some(entry),
+                            _ => {}
+                        }
+                    }
+                    if status.entry.is_none() {
+                        return self.request_next();
+                    }
+                } else if status.name.is_empty() && nak == Some(MavlinkFtpNak::Eof) {
+                    // An empty root
+                    status.entry = Some(root_entry(&status.path));
+                } else if !matches!(
+                    nak,
+                    Some(MavlinkFtpNak::Eof) | Some(MavlinkFtpNak::FileNotFound)
+                ) {
+                    println!(
+                        "stat failed on {}: {}",
+                        status.parent,
+                        nak.map_or("Invalid NAK".to_string(), |nak| nak.to_string())
+                    );
+                    exit(1);
+                }
+
+                // Only stat itself prints the CRC
+                if let (
+                    Some(EntryInfo {
+                        entry_type: EntryType::File,
+                        ..
+                    }),
+                    None,
+                    None,
+                ) = (&status.entry, &status.test, &status.wait)
+                {
+                    status.stage = StatStage::Crc;
+                    return self.request_next();
+                }
+            }
+            StatStage::Crc => {
+                if payload.req_opcode != MavlinkFtpOpcode::CalcFileCRC32 {
+                    return None;
+                }
+                status.crc = payload.data_as_u32().filter(|_| nak.is_none());
+            }
+        }
+
+        let found = match (&status.test, &status.entry) {
+            (_, None) => false,
+            (None | Some(PathTest::Exists), Some(_)) => true,
+            (Some(PathTest::File), Some(entry)) => matches!(entry.entry_type, EntryType::File),
+            (Some(PathTest::Directory), Some(entry)) => {
+                matches!(entry.entry_type, EntryType::Directory)
+            }
+        };
+        if let Some(wait) = &status.wait {
+            let size = status.entry.as_ref().map(|entry| entry.size);
+            if size.is_some_and(|size| size >= wait.min_size) {
+                println!(
+                    "{} is ready ({} bytes)",
+                    status.path,
+                    size.unwrap_or_default()
+                );
+                return self.finish(0);
+            }
+            if SystemTime::now() >= wait.deadline {
+                match size {
+                    Some(size) => println!(
+                        "timed out waiting for {}, it has {} of {} bytes",
+                        status.path, size, wait.min_size
+                    ),
+                    None => println!("timed out waiting for {}", status.path),
+                }
+                exit(1);
+            }
+            status.entry = None;
+            status.offset = 0;
+            self.resume_at = Some(SystemTime::now() + WAIT_POLL_INTERVAL);
+            return None;
+        }
+        if status.test.is_none() {
+            print_stat(status, self.size_units);
+        }
+        let code = if found { 0 } else { 1 };
+        self.finish(code)
+    }
+
+    fn parse_info_response(status: &mut InfoStatus, payload: &MavlinkFtpPayload) -> bool {
+        // Any NAK other than UnknownCommand means that the opcode is implemented
+        let supported = payload.opcode == MavlinkFtpOpcode::Ack
+            || payload.nak() != Some(MavlinkFtpNak::UnknownCommand);
+
+        let ack = payload.opcode == MavlinkFtpOpcode::Ack;
+        match (&status.stage, payload.req_opcode) {
+            (InfoStage::ProbingList, MavlinkFtpOpcode::ListDirectory) => {
+                status.ftp_reachable = true;
+                if ack {
+                    status.probe_file = largest_file("/", &payload.data);
+                }
+                status.stage = InfoStage::ProbingBurst;
+            }
+            (InfoStage::ProbingBurst, MavlinkFtpOpcode::BurstReadFile) => {
+                status.burst_support = supported;
+                status.stage = InfoStage::ProbingWrite;
+            }
+            (InfoStage::ProbingWrite, MavlinkFtpOpcode::WriteFile) => {
+                status.write_support = supported;
+                if !status.doctor || status.probe_file.is_none() {
+                    return true;
+                }
+                status.stage = InfoStage::OpeningProbeFile;
+            }
+            (InfoStage::OpeningProbeFile, MavlinkFtpOpcode::OpenFileRO) => {
+                if ack {
+                    status.probe_session = payload.session;
+                    status.stage = InfoStage::ReadingProbeFile;
+                } else if status.resends > 0 && !status.probe_refused {
+                    status.probe_refused = true;
+                    status.stage = InfoStage::ResettingProbeSessions;
+                } else {
+                    status.probe_refused = true;
+                    return true;
+                }
+            }
+            (InfoStage::ResettingProbeSessions, MavlinkFtpOpcode::ResetSessions) => {
+                status.stage = InfoStage::OpeningProbeFile;
+            }
+            (InfoStage::ReadingProbeFile, MavlinkFtpOpcode::ReadFile) => {
+                if ack {
+                    status.max_payload = Some(payload.data.len());
+                }
+                status.stage = InfoStage::ClosingProbeFile;
+            }
+            (InfoStage::ClosingProbeFile, MavlinkFtpOpcode::TerminateSession) => return true,
+            _ => {}
+        }
+
+        false
+    }
+
+    fn finish_info(&mut self) -> Option<Message> {
+        let Some(OperationStatus::Info(status)) = &self.status else {
+            return None;
+        };
+        print_info(status);
+        let code = match status.doctor {
+            true => print_findings(status, self.target_component),
+            false => 0,
+        };
+        self.finish(code)
+    }
+
+    fn conformance_request(&mut self, request: Option<Vec<u8>>) -> Option<Message> {
+        if let Some(OperationStatus::Conformance(conformance)) = &self.status {
+            if conformance.is_finished() {
+                let code = conformance.report();
+                return self.finish(code);
+            }
+        }
+        request.map(|request| self.ftp_raw_message(request))
+    }
+
+    fn parse_ftp_message(
+        &mut self,
+        message: &mavlink::common::FILE_TRANSFER_PROTOCOL_DATA,
+    ) -> Option<Message> {
+        let mut message = message.clone();
+        if let Some(seq_number) = message.payload.get_mut(..2) {
+            let seq = untag_sequence(u16::from_le_bytes([seq_number[0], seq_number[1]]));
+            seq_number.copy_from_slice(&seq.to_le_bytes());
+        }
+        let message = &message;
+        if let Some(OperationStatus::Conformance(conformance)) = &mut self.status {
+            let request = conformance.reply(&message.payload);
+            return self.conformance_request(request);
+        }
+
+        // Replies to raw requests may not be valid payloads, they are printed as they are
+        if let Some(OperationStatus::Raw(status)) = &self.status {
+            let opcode = status.request[3];
+            let reply = &message.payload;
+            if reply.len() < 12 || reply[5] != opcode {
+                return None;
+            }
+            print_raw_reply(reply);
+            // A burst goes on until the reply marked as its last one
+            if opcode == MavlinkFtpOpcode::BurstReadFile as u8 && reply[6] == 0 {
+                return None;
+            }
+            let code = if reply[3] == MavlinkFtpOpcode::Nak as u8 {
+                1
+            } else {
+                0
+            };
+            return self.finish(code);
+        }
+
+        let payload = match MavlinkFtpPayload::from_bytes(&message.payload) {
+            Ok(payload) => payload,
+            Err(error) => {
+                self.println(&format!("ignoring malformed FTP message: {}", error));
+                return None;
+            }
+        };
+        if Self::is_overlap_reply(&payload) {
+            self.parse_overlap_response(&payload);
+            return None;
+        }
+        if self.is_stale_reply(&payload) {
+            return None;
+        }
+        self.waiting = false;
+
+        if let Some(OperationStatus::Copying(_)) = self.status {
+            return self.parse_copy_response(&payload);
+        }
+
+        if let Some(OperationStatus::CreatingDirectory(_)) = self.status {
+            return self.parse_create_directory_response(&payload);
+        }
+
+        if let Some(OperationStatus::Removing(_)) = self.status {
+            return self.parse_remove_response(&payload);
+        }
+
+        if let Some(OperationStatus::Extracting(_)) = self.status {
+            return self.parse_extract_response(&payload);
+        }
+
+        if let Some(OperationStatus::FindingLog(_)) = self.status {
+            return self.parse_find_log_response(&payload);
+        }
+
+        if let Some(OperationStatus::Uploading(_)) = self.status {
+            return self.parse_upload_response(&payload);
+        }
+
+        if let Some(OperationStatus::Verifying(_)) = self.status {
+            return self.parse_verify_response(&payload);
+        }
+
+        if let Some(OperationStatus::Stat(_)) = self.status {
+            return self.parse_stat_response(&payload);
+        }
+
+        if let Some(OperationStatus::ComparingLocal(_)) = self.status {
+            return self.parse_compare_local_response(&payload);
+        }
+
+        if let Some(OperationStatus::Info(status)) = &mut self.status {
+            if Self::parse_info_response(status, &payload) {
+                return self.finish_info();
+            }
+            return None;
+        }
+
+        match payload.opcode {
+            MavlinkFtpOpcode::Ack => {
+                if self.reopening && payload.req_opcode == MavlinkFtpOpcode::ResetSessions {
+                    self.reopening = false;
+                    return self.next_payload().map(|payload| self.ftp_message(payload));
+                }
+                match &mut self.status {
+                    Some(OperationStatus::Reset) => {
+                        if payload.req_opcode == MavlinkFtpOpcode::ResetSessions {
+                            return self.finish(0);
+                        }
+                    }
+                    Some(OperationStatus::ScanningFolder(status)) => {
+                        let entries: Vec<&[u8]> = payload.data.split(|&byte| byte == 0).collect();
+
+                        // A page received twice, as after a resend, is counted once
+                        if entries.is_empty() || payload.offset != status.offset {
+                            return None;
+                        }
+
+                        let page_offset = status.offset;
+                        let first_entry = self.entries.len();
+                        for entry in entries {
+                            if entry.is_empty() {
+                                continue;
+                            }
+                            status.offset += 1;
+
+                            match parse_directory_entry(entry) {
+                                Ok(result) => self.entries.push(result),
+                                Err(error) => status.skipped.push(error),
+                            }
+                        }
+                        if let (true, Some(template)) =
+                            (status.options.stream, &status.options.template)
+                        {
+                            print_listing_template(
+                                &self.entries[first_entry..],
+                                &status.path,
+                                template,
+                                self.size_units,
+                            );
+                        } else if status.options.stream && status.options.json {
+                            for entry in &self.entries[first_entry..] {
+                                println!("{}", entry_json(entry, &status.path));
+                            }
+                        } else if status.options.stream {
+                            print_listing_stream(
+                                &self.entries[first_entry..],
+                                &status.path,
+                                status.options.full_path,
+                                self.color,
+                                self.size_units,
+                                page_offset == 0,
+                            );
+                        }
+
+                        if status.offset != page_offset {
+                            self.waiting = true;
+                            let payload = MavlinkFtpPayload::new_list_directory(
+                                1,
+                                self.session,
+                                status.offset,
+                                &status.path,
+                            );
+                            return Some(self.ftp_message(payload));
+                        }
+                    }
+                    Some(OperationStatus::OpeningFile(status)) => {
+                        let Some(file_size) = payload.data_as_u32() else {
+                            println!("read failed on {}: response without file size", status.path);
+                            exit(1);
+                        };
+
+                        // Left by an earlier run of the same download
+                        if status.destination.is_none()
+                            && status.options.skip_existing
+                            && status.local_path.exists()
+                            && local_size(&status.local_path) == file_size as u64
+                        {
+                            self.session = payload.session;
+                            self.waiting = true;
+                            if status.options.compare_crc {
+                                let local_crc = std::fs::read(&status.local_path)
+                                    .ok()
+                                    .map(|data| mavlink_crc32(&data));
+                                self.status =
+                                    Some(OperationStatus::ComparingLocal(ComparingLocalStatus {
+                                        path: status.path.clone(),
+                                        local_path: status.local_path.clone(),
+                                        options: status.options.clone(),
+                                        local_crc,
+                                        open_session: Some(payload.session),
+                                    }));
+                            } else {
+                                println!(
+                                    "skipping {}, {} has the same size",
+                                    status.path,
+                                    status.local_path.display()
+                                );
+                                self.status =
+                                    Some(OperationStatus::ClosingSession(ClosingSessionStatus {
+                                        exit_code: 0,
+                                        announce: false,
+                                        transferred: Vec::new(),
+                                    }));
+                            }
+                            let payload = MavlinkFtpPayload::new_terminate_session(
+                                payload.seq_number + 1,
+                                self.session,
+                            );
+                            return Some(self.ftp_message(payload));
+                        }
+
+                        let destination = match status.destination.take() {
+                            Some(destination) => destination,
+                            None => {
+                                // The session is open, it's closed before failing
+                                let directory = status.local_path.parent().unwrap_or(Path::new(""));
+                                let needed = (file_size as u64)
+                                    .saturating_sub(local_size(&status.local_path));
+                                if let Err(error) = check_space(directory, needed, self.size_units)
+                                {
+                                    println!("read failed: {}: {}", status.path, error);
+                                    self.session = payload.session;
+                                    self.status = Some(OperationStatus::ClosingSession(
+                                        ClosingSessionStatus {
+                                            exit_code: 1,
+                                            announce: false,
+                                            transferred: Vec::new(),
+                                        },
+                                    ));
+                                    self.waiting = true;
+                                    let payload = MavlinkFtpPayload::new_terminate_session(
                                         payload.seq_number + 1,
                                         self.session,
-                                    )
-                                    .to_bytes(),
+                                    );
+                                    return Some(self.ftp_message(payload));
+                                }
+                                self.progress = Some(file_progress_bar(
+                                    &self.files_progress,
+                                    file_size as u64,
+                                    self.size_units,
+                                ));
+
+                                let file = OpenOptions::new()
+                                    .read(true)
+                                    .write(true)
+                                    .create(true)
+                                    .truncate(true)
+                                    .open(&status.local_path)
+                                    .unwrap();
+                                // Empty files can't be mapped
+                                if status.options.mmap && file_size > 0 {
+                                    file.set_len(file_size as u64).unwrap();
+                                    // Safety: the file was just created and nothing else changes
+                                    // its size while it's mapped
+                                    ReadDestination::Mapped(unsafe {
+                                        memmap2::MmapMut::map_mut(&file).unwrap()
+                                    })
+                                } else {
+                                    ReadDestination::File(file)
+                                }
+                            }
+                        };
+
+                        self.session = payload.session;
+                        self.status = Some(OperationStatus::ReadingFile(ReadingFileStatus {
+                            path: status.path.clone(),
+                            local_path: status.local_path.clone(),
+                            offset: status.offset.min(file_size),
+                            file_size: match status.length {
+                                Some(length) => file_size.min(status.offset.saturating_add(length)),
+                                None => file_size,
+                            },
+                            destination,
+                            fsync_every: status.options.fsync_every,
+                            synced: status.offset,
+                            crc: 0,
+                            sha256: self.sha256.then(Sha256::default),
+                        }));
+
+                        return None;
+                    }
+                    Some(OperationStatus::ResumingFile(status)) => {
+                        match payload.req_opcode {
+                            MavlinkFtpOpcode::ResetSessions => status.sessions_reset = true,
+                            MavlinkFtpOpcode::OpenFileRO => {
+                                self.session = payload.session;
+                                let file_size = payload.data_as_u32();
+                                if let Some(OperationStatus::ResumingFile(status)) =
+                                    self.status.take()
+                                {
+                                    let mut reading = status.reading;
+                                    if let Some(file_size) = file_size.filter(|size| {
+                                        reading.destination.on_disk() && *size != reading.file_size
+                                    }) {
+                                        if let Err(error) = reading.restart(file_size) {
+                                            self.println(&format!(
+                                                "Failed to resume {}: {}",
+                                                reading.path, error
+                                            ));
+                                            exit(1);
+                                        }
+                                        self.println(&format!(
+                                            "{} changed on the vehicle, reading it again",
+                                            reading.path
+                                        ));
+                                        self.progress = Some(file_progress_bar(
+                                            &self.files_progress,
+                                            file_size as u64,
+                                            self.size_units,
+                                        ));
+                                    }
+                                    self.status = Some(OperationStatus::ReadingFile(reading));
+                                }
+                            }
+                            _ => {}
+                        }
+                        return None;
+                    }
+                    Some(OperationStatus::CalcFileCRC32(_status)) => {
+                        if payload.req_opcode == MavlinkFtpOpcode::CalcFileCRC32 {
+                            let Some(crc) = payload.data_as_u32() else {
+                                println!("crc failed: response without crc");
+                                exit(1);
+                            };
+                            println!("crc: 0x{:x?}", crc);
+                            self.last_crc = Some(crc);
+                            return self.finish(0);
+                        }
+                    }
+                    Some(OperationStatus::ReadingFile(status)) => {
+                        // Chunks after a lost one are dropped, the next burst starts from the gap
+                        if payload.offset == status.offset {
+                            let remaining = (status.file_size - status.offset) as usize;
+                            let chunk = &payload.data[..payload.data.len().min(remaining)];
+                            match &mut status.destination {
+                                ReadDestination::File(file) => {
+                                    file.seek(SeekFrom::Start(payload.offset.into())).unwrap();
+                                    file.write_all(chunk).unwrap();
+                                }
+                                ReadDestination::Mapped(map) => {
+                                    let start = payload.offset as usize;
+                                    map[start..start + chunk.len()].copy_from_slice(chunk);
+                                }
+                                ReadDestination::Grep(matcher) => matcher.feed(chunk),
+                                ReadDestination::Hexdump(dumper) => dumper.feed(chunk),
+                                ReadDestination::Params(diff) => diff.feed(chunk),
+                                ReadDestination::LastLog(reader) => {
+                                    reader.data.extend_from_slice(chunk)
+                                }
+                            }
+                            status.crc = mavlink_crc32_update(status.crc, chunk);
+                            if let Some(sha256) = &mut status.sha256 {
+                                sha256.update(chunk);
+                            }
+                            status.offset = payload.offset + chunk.len() as u32;
+                            metrics::transferred(chunk.len() as u64);
+
+                            if let Some(fsync_every) = status.fsync_every {
+                                if status.offset - status.synced >= fsync_every {
+                                    status.destination.sync().unwrap();
+                                    status.synced = status.offset;
+                                }
+                            }
+                        }
+                        if let Some(progress) = &self.progress {
+                            progress.set_position(status.offset as u64);
+                        }
+                        if let ReadDestination::File(_) | ReadDestination::Mapped(_) =
+                            status.destination
+                        {
+                            notify::progress(
+                                "read",
+                                &status.path,
+                                status.offset as u64,
+                                status.file_size as u64,
+                            );
+                        }
+
+                        if status.offset < status.file_size {
+                            self.waiting = true;
+
+                            if payload.burst_complete == 1 {
+                                let payload = MavlinkFtpPayload::new_read_file(
+                                    payload.seq_number + 1,
+                                    self.session,
+                                    status.offset,
+                                    self.chunk_size,
+                                );
+                                return Some(self.ftp_message(payload));
+                            } else {
+                                return None;
+                            }
+                        } else {
+                            if let Some(progress) = &self.progress {
+                                progress.finish();
+                            }
+
+                            let closing = match &mut status.destination {
+                                ReadDestination::File(_) | ReadDestination::Mapped(_) => {
+                                    if let ReadDestination::Mapped(map) = &status.destination {
+                                        map.flush().unwrap();
+                                    }
+                                    println!("calculated crc: 0x{:08x}", status.crc);
+                                    let sha256 = status.sha256.take().map(Sha256::finish);
+                                    if let Some(sha256) = &sha256 {
+                                        println!("calculated sha256: {}", sha256::hex(sha256));
+                                    }
+                                    if self.write_checksums {
+                                        if let Err(error) =
+                                            sidecar::write(&status.local_path, status.crc)
+                                        {
+                                            println!(
+                                                "read failed: {}: {}",
+                                                status.local_path.display(),
+                                                error
+                                            );
+                                            exit(1);
+                                        }
+                                    }
+                                    ClosingSessionStatus {
+                                        exit_code: 0,
+                                        announce: true,
+                                        transferred: vec![ReceiptEntry {
+                                            sha256,
+                                            ..ReceiptEntry::with_crc(
+                                                status.local_path.clone(),
+                                                status.path.clone(),
+                                                status.offset as u64,
+                                                status.crc,
+                                            )
+                                        }],
+                                    }
+                                }
+                                ReadDestination::Hexdump(dumper) => {
+                                    dumper.finish();
+                                    ClosingSessionStatus {
+                                        exit_code: 0,
+                                        announce: false,
+                                        transferred: Vec::new(),
+                                    }
+                                }
+                                // The log is read once the session of LASTLOG.TXT is closed
+                                ReadDestination::LastLog(reader) => {
+                                    let number =
+                                        String::from_utf8_lossy(&reader.data).trim().parse().ok();
+                                    self.queued_reads =
+                                        vec![log_read(std::mem::take(&mut reader.finding), number)];
+                                    ClosingSessionStatus {
+                                        exit_code: 0,
+                                        announce: false,
+                                        transferred: Vec::new(),
+                                    }
+                                }
+                                ReadDestination::Params(diff) => ClosingSessionStatus {
+                                    exit_code: print_params_diff(diff, self.color),
+                                    announce: false,
+                                    transferred: Vec::new(),
                                 },
-                            ));
+                                // Same as grep, no matches is a failure
+                                ReadDestination::Grep(matcher) => ClosingSessionStatus {
+                                    exit_code: if matcher.finish() > 0 { 0 } else { 1 },
+                                    announce: false,
+                                    transferred: Vec::new(),
+                                },
+                            };
+
+                            self.status = Some(OperationStatus::ClosingSession(closing));
+                            self.waiting = true;
+
+                            let payload = MavlinkFtpPayload::new_terminate_session(
+                                payload.seq_number + 1,
+                                self.session,
+                            );
+                            return Some(self.ftp_message(payload));
                         }
                     }
-                    Some(OperationStatus::ClosingSession) => {
-                        println!("session closed");
-                        exit(0);
+                    Some(OperationStatus::ClosingSession(status)) => {
+                        if status.announce {
+                            println!("session closed");
+                        }
+                        let transferred = std::mem::take(&mut status.transferred);
+                        let exit_code = status.exit_code;
+                        return self.verify(transferred, exit_code);
                     }
-                    None => return None,
+                    // Handled before, or nothing is running
+                    _ => return None,
                 }
             }
             MavlinkFtpOpcode::Nak => {
-                let nak_code = MavlinkFtpNak::from_u8(payload.data[0]).unwrap();
+                let nak_code = payload.nak()?;
 
                 match nak_code {
-                    MavlinkFtpNak::EOF => {
+                    // The first open got through but its reply was lost, the file is still open
+                    MavlinkFtpNak::NoSessionsAvailable
+                        if self.resent
+                            && matches!(
+                                payload.req_opcode,
+                                MavlinkFtpOpcode::OpenFileRO
+                                    | MavlinkFtpOpcode::OpenFileWO
+                                    | MavlinkFtpOpcode::CreateFile
+                            ) =>
+                    {
+                        self.println("the open got through before, resetting the sessions");
+                        self.reopening = true;
+                        let payload = MavlinkFtpPayload::new_reset_sesions(1, self.session);
+                        return Some(self.ftp_message(payload));
+                    }
+                    // The file ends before the size it was opened with, as empty files do, what
+                    // was read is all of it and it's closed as once the last chunk arrives
+                    MavlinkFtpNak::Eof
+                        if matches!(
+                            payload.req_opcode,
+                            MavlinkFtpOpcode::ReadFile | MavlinkFtpOpcode::BurstReadFile
+                        ) =>
+                    {
+                        let Some(OperationStatus::ReadingFile(status)) = &mut self.status else {
+                            return None;
+                        };
+                        status.file_size = status.offset;
+                        let end = MavlinkFtpPayload {
+                            opcode: MavlinkFtpOpcode::Ack,
+                            size: 0,
+                            burst_complete: 1,
+                            offset: status.offset,
+                            data: Vec::new(),
+                            ..payload.clone()
+                        };
+                        return self.parse_ftp_message(
+                            &mavlink::common::FILE_TRANSFER_PROTOCOL_DATA {
+                                target_network: 0,
+                                target_system: 0,
+                                target_component: 0,
+                                payload: end.to_bytes(),
+                            },
+                        );
+                    }
+                    MavlinkFtpNak::Eof => {
                         // We finished the current operation
-                        match &payload.req_opcode {
-                            MavlinkFtpOpcode::ListDirectory => {
-                                println!("{:<4} {:<30} {:<10}", "Type", "Name", "Size");
-                                println!("{}", "-".repeat(40));
-                                self.entries
-                                    .sort_by(|a, b| a.name.partial_cmp(&b.name).unwrap());
-                                for entry in &self.entries {
-                                    let item_type = match entry.entry_type {
-                                        EntryType::File => 'F',
-                                        EntryType::Directory => 'D',
-                                        EntryType::Skip => 'S',
-                                    };
-                                    println!(
-                                        "{:<4} {:<30} {:<10}",
-                                        item_type,
-                                        entry.name,
-                                        format_size(entry.size as u64)
-                                    );
+                        if payload.req_opcode == MavlinkFtpOpcode::ListDirectory {
+                            self.entries
+                                .sort_by(|a, b| a.name.partial_cmp(&b.name).unwrap());
+
+                            if let (Some(OperationStatus::ScanningFolder(status)), None) =
+                                (&self.status, &self.script_reply)
+                            {
+                                let mut warnings = Vec::new();
+                                if !status.skipped.is_empty() {
+                                    warnings.push(format!(
+                                        "warning: skipped {} entries that could not be parsed",
+                                        status.skipped.len()
+                                    ));
+                                }
+                                for error in &status.skipped {
+                                    warnings.push(format!("  {}", error));
+                                }
+
+                                // Warnings go to stderr, so the JSON document or the formatted lines stay parseable
+                                if let Some(template) = &status.options.template {
+                                    if !status.options.stream {
+                                        print_listing_template(
+                                            &self.entries,
+                                            &status.path,
+                                            template,
+                                            self.size_units,
+                                        );
+                                    }
+                                    for warning in warnings {
+                                        eprintln!("{}", warning);
+                                    }
+                                } else if status.options.json {
+                                    // Streamed entries were printed one per line already
+                                    if !status.options.stream {
+                                        print_listing_json(&self.entries, &status.path);
+                                    }
+                                    for warning in warnings {
+                                        eprintln!("{}", warning);
+                                    }
+                                } else {
+                                    if !status.options.stream {
+                                        print_listing(
+                                            &self.entries,
+                                            &status.path,
+                                            status.options.full_path,
+                                            self.color,
+                                            self.size_units,
+                                        );
+                                    } else if status.offset == 0 {
+                                        // An empty directory, no page printed the header
+                                        print_listing_stream(
+                                            &[],
+                                            "",
+                                            false,
+                                            false,
+                                            self.size_units,
+                                            true,
+                                        );
+                                    }
+                                    for warning in warnings {
+                                        println!("{}", warning);
+                                    }
                                 }
                             }
-                            _ => {}
                         }
-                        exit(0);
-                        self.status = None;
-                        return None;
+                        return self.finish(0);
                     }
                     MavlinkFtpNak::FailErrno => {
                         return None;
@@ -334,20 +3474,1121 @@ impl Controller {
             _ => {}
         }
 
+        None
+    }
+}
+
+// Paths of the files and directories in a page listed while walking a tree, with the number of
+// entries the page holds
+fn walk_page(directory: &str, data: &[u8]) -> (u32, Vec<(String, EntryType, u32)>) {
+    let mut count = 0;
+    let mut entries = Vec::new();
+    for entry in data.split(|&byte| byte == 0) {
+        if entry.is_empty() {
+            continue;
+        }
+        count += 1;
+
+        let entry = match parse_directory_entry(entry) {
+            Ok(entry) => entry,
+            Err(error) => {
+                println!("skipping entry of {}: {}", directory, error);
+                continue;
+            }
+        };
+        if entry.name == "." || entry.name == ".." {
+            continue;
+        }
+        // Paths are sent as text, a replaced name would point somewhere else
+        if std::str::from_utf8(&entry.raw_name).is_err() {
+            println!(
+                "skipping entry of {}: name is not UTF-8: {}",
+                directory,
+                escape_name(&entry.name)
+            );
+            continue;
+        }
+        let path = format!("{}/{}", directory.trim_end_matches('/'), entry.name);
+        entries.push((path, entry.entry_type, entry.size));
+    }
+    (count, entries)
+}
+
+// Downloads that can't fit fail before their first byte, instead of halfway through
+fn check_space(directory: &Path, needed: u64, units: SizeUnits) -> Result<(), String> {
+    match disk::available_space(directory) {
+        Some(available) if available < needed => Err(format!(
+            "{} needed, only {} free in {}",
+            format_size(needed, units),
+            format_size(available, units),
+            match directory.as_os_str().is_empty() {
+                true => Path::new("."),
+                false => directory,
+            }
+            .display()
+        )),
+        _ => Ok(()),
+    }
+}
+
+// A local file that is overwritten gives its space back
+fn local_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+// The largest file in a page of a listing, to probe reads with
+fn largest_file(directory: &str, data: &[u8]) -> Option<(String, u32)> {
+    data.split(|&byte| byte == 0)
+        .filter_map(|entry| parse_directory_entry(entry).ok())
+        .filter(|entry| matches!(entry.entry_type, EntryType::File))
+        .filter(|entry| std::str::from_utf8(&entry.raw_name).is_ok())
+        .max_by_key(|entry| entry.size)
+        .map(|entry| {
+            let path = format!("{}/{}", directory.trim_end_matches('/'), entry.name);
+            (path, entry.size)
+        })
+}
+
+// Changes to @ROMFS can only fail, so they are refused before anything is sent
+fn check_writable(operation: &str, path: &str) {
+    let relative = path.trim_start_matches('/');
+    if relative == ROMFS_PATH || relative.starts_with(&format!("{}/", ROMFS_PATH)) {
+        println!(
+            "{} failed: {} is in {}, which is read-only",
+            operation, path, ROMFS_PATH
+        );
+        exit(1);
+    }
+}
+
+// Asks a yes/no question, None when there is no terminal to ask
+fn confirm(question: &str) -> Option<bool> {
+    if !std::io::stdin().is_terminal() {
+        return None;
+    }
+
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush().ok()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).ok()?;
+    Some(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+// As MAV_RESULT_TEMPORARILY_REJECTED is told, "temporarily rejected"
+fn result_name(result: mavlink::common::MavResult) -> String {
+    format!("{:?}", result)
+        .trim_start_matches("MAV_RESULT_")
+        .to_lowercase()
+        .replace('_', " ")
+}
+
+// Nothing was removed, so a script or a chain of commands doesn't go on as if it was
+fn declined(operation: &str) -> ! {
+    println!("{} cancelled", operation);
+    exit(1)
+}
+
+// Name of a downloaded file, a single path component so it's never written out of the
+// output directory, None when the remote path has no file name
+fn local_file_name(remote_path: &str) -> Option<String> {
+    let name = remote_path.split('/').next_back().unwrap_or_default();
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    if !cfg!(windows) {
+        return Some(name.to_string());
+    }
+
+    // Characters and names that can't be used on NTFS
+    const RESERVED: [&str; 22] = [
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+    let mut name: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    name.truncate(name.trim_end_matches(['.', ' ']).len());
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if name.is_empty()
+        || RESERVED
+            .iter()
+            .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        name.insert(0, '_');
+    }
+    Some(name)
+}
+
+fn read_chunk(file: &mut std::fs::File, offset: u32, size: usize) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(size);
+    file.seek(SeekFrom::Start(offset.into())).unwrap();
+    file.take(size as u64).read_to_end(&mut chunk).unwrap();
+    chunk
+}
+
+// Rate over the last seconds only, so the pauses between bursts and the resends don't swing the ETA
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct RateWindow {
+    first: Option<(Instant, u64)>,
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RateWindow {
+    // Bytes per second over the window and since the first sample, with this one added
+    fn rates(&mut self, position: u64) -> (f64, f64) {
+        let now = Instant::now();
+        if self
+            .samples
+            .back()
+            .is_some_and(|&(_, last)| position < last)
+        {
+            self.samples.clear();
+        }
+        self.samples.push_back((now, position));
+        // The oldest sample is kept as the start of the window
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= RATE_WINDOW {
+            self.samples.pop_front();
+        }
+        let rate = |(start, from): (Instant, u64)| {
+            let elapsed = now.duration_since(start).as_secs_f64();
+            match elapsed > 0.0 {
+                true => position.saturating_sub(from) as f64 / elapsed,
+                false => 0.0,
+            }
+        };
+        let first = *self.first.get_or_insert((now, position));
+        (rate(self.samples[0]), rate(first))
+    }
+}
+
+// The local CRC32 of an uploaded file, to compare with the one on the vehicle
+fn upload_entry(
+    stdin: &Option<Vec<u8>>,
+    local_path: PathBuf,
+    remote_path: String,
+    sha256: bool,
+) -> ReceiptEntry {
+    if let (Some(content), true) = (stdin, local_path.as_os_str() == STDIN_PATH) {
+        let crc = mavlink_crc32(content);
+        let size = content.len() as u64;
+        return ReceiptEntry {
+            sha256: sha256.then(|| sha256::digest(content)),
+            ..ReceiptEntry::with_crc(local_path, remote_path, size, crc)
+        };
+    }
+    match ReceiptEntry::new(local_path.clone(), remote_path, sha256) {
+        Ok(entry) => entry,
+        Err(error) => {
+            println!("receipt failed: {}: {}", local_path.display(), error);
+            exit(1);
+        }
+    }
+}
+
+fn progress_bar(size: u64, units: SizeUnits) -> ProgressBar {
+    let window = Arc::new(Mutex::new(RateWindow::default()));
+    let progress = ProgressBar::new(size);
+    progress.set_style(ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {size}/{total_size} ({rates})")
+        .unwrap()
+        // Same units as everything else that is printed
+        .with_key("size", move |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{}", format_size(state.pos(), units)).unwrap())
+        .with_key("total_size", move |state: &ProgressState, w: &mut dyn std::fmt::Write| write!(w, "{}", format_size(state.len().unwrap_or_default(), units)).unwrap())
+        // The rate, average and time left come from the same sample of the window, taken once a tick
+        .with_key("rates", move |state: &ProgressState, w: &mut dyn std::fmt::Write| {
+            let (rate, average) = window.lock().unwrap().rates(state.pos());
+            let remaining = state.len().unwrap_or_default().saturating_sub(state.pos());
+            write!(w, "{}/s, avg {}/s, ", format_size(rate as u64, units), format_size(average as u64, units)).unwrap();
+            match rate {
+                rate if rate > 0.0 => write!(w, "{:.1}s", remaining as f64 / rate).unwrap(),
+                _ => write!(w, "-").unwrap(),
+            }
+        })
+        .progress_chars("#>-")
+    );
+    progress
+}
+
+// The bar of a downloaded file, under the one counting the files of a read of many
+fn file_progress_bar(
+    files_progress: &Option<(MultiProgress, ProgressBar)>,
+    size: u64,
+    units: SizeUnits,
+) -> ProgressBar {
+    let progress = progress_bar(size, units);
+    match files_progress {
+        Some((multi_progress, _)) => multi_progress.add(progress),
+        None => progress,
+    }
+}
+
+// The local path of a read, in --output-dir or the current directory
+fn local_read_path(path: &str, options: &ReadOptions) -> PathBuf {
+    let Some(name) = local_file_name(path) else {
+        println!("read failed: {} doesn't end with a file name", path);
+        exit(1);
+    };
+    match &options.output_dir {
+        Some(output_dir) => {
+            if let Err(error) = std::fs::create_dir_all(output_dir) {
+                println!("read failed: {}: {}", output_dir.display(), error);
+                exit(1);
+            }
+            output_dir.join(name)
+        }
+        None => PathBuf::from(name),
+    }
+}
+
+fn count_progress_bar(count: u64) -> ProgressBar {
+    let progress = ProgressBar::new(count);
+    progress.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {wide_msg}",
+        )
+        .unwrap()
+        .progress_chars("#>-"),
+    );
+    progress
+}
+
+fn print_raw_reply(reply: &[u8]) {
+    let opcode = |code: u8| match MavlinkFtpOpcode::from_u8(code) {
+        Some(opcode) => format!("{:?} ({})", opcode, code),
+        None => format!("unknown ({})", code),
+    };
+    let size = (reply[4] as usize).min(reply.len() - 12);
+    let data = &reply[12..12 + size];
+
+    println!(
+        "{:<16} {}",
+        "seq_number",
+        u16::from_le_bytes([reply[0], reply[1]])
+    );
+    println!("{:<16} {}", "session", reply[2]);
+    println!("{:<16} {}", "opcode", opcode(reply[3]));
+    println!("{:<16} {}", "size", reply[4]);
+    println!("{:<16} {}", "req_opcode", opcode(reply[5]));
+    println!("{:<16} {}", "burst_complete", reply[6]);
+    println!(
+        "{:<16} {}",
+        "offset",
+        u32::from_le_bytes([reply[8], reply[9], reply[10], reply[11]])
+    );
+    if reply[3] == MavlinkFtpOpcode::Nak as u8 {
+        if let Some(&code) = data.first() {
+            let nak =
+                MavlinkFtpNak::from_u8(code).map_or("unknown".to_string(), |nak| nak.to_string());
+            println!("{:<16} {} ({})", "nak", nak, code);
+        }
+        if let (Some(MavlinkFtpNak::FailErrno), Some(errno)) = (
+            data.first().and_then(|&code| MavlinkFtpNak::from_u8(code)),
+            data.get(1),
+        ) {
+            println!("{:<16} {}", "errno", errno);
+        }
+    }
+    if !data.is_empty() {
+        let mut dumper = HexDumper::new(0);
+        dumper.feed(data);
+        dumper.finish();
+    }
+}
+
+fn root_entry(path: &str) -> EntryInfo {
+    EntryInfo {
+        entry_type: EntryType::Directory,
+        name: path.to_string(),
+        raw_name: path.as_bytes().to_vec(),
+        size: 0,
+    }
+}
+
+fn print_stat(status: &StatStatus, units: SizeUnits) {
+    let entry_type = status.entry.as_ref().map(|entry| match entry.entry_type {
+        EntryType::File => "file",
+        EntryType::Directory => "directory",
+        EntryType::Skip => "other",
+    });
+
+    if status.json {
+        let mut line = format!("{{\"path\": \"{}\"", json::escape(&status.path));
+        match (&status.entry, entry_type) {
+            (Some(entry), Some(entry_type)) => {
+                line.push_str(&format!(
+                    ", \"exists\": true, \"type\": \"{}\", \"size\": {}",
+                    entry_type, entry.size
+                ));
+                if let Some(crc) = status.crc {
+                    line.push_str(&format!(", \"crc32\": {}", crc));
+                }
+            }
+            _ => line.push_str(", \"exists\": false"),
+        }
+        line.push('}');
+        println!("{}", line);
+        return;
+    }
+
+    let (Some(entry), Some(entry_type)) = (&status.entry, entry_type) else {
+        println!("{} does not exist", status.path);
+        return;
+    };
+    println!("{:<8} {}", "path", status.path);
+    println!("{:<8} {}", "type", entry_type);
+    if let EntryType::File = entry.entry_type {
+        let size = format_size(entry.size as u64, units);
+        match units {
+            SizeUnits::Bytes => println!("{:<8} {}", "size", size),
+            _ => println!("{:<8} {} ({} bytes)", "size", size, entry.size),
+        }
+        match status.crc {
+            Some(crc) => println!("{:<8} 0x{:08x}", "crc32", crc),
+            None => println!("{:<8} unavailable", "crc32"),
+        }
+    }
+}
+
+fn print_info(status: &InfoStatus) {
+    let yes_no = |value: bool| if value { "yes" } else { "no" };
+
+    match &status.version {
+        Some(version) => {
+            println!(
+                "{:<20} {} ({})",
+                "Firmware",
+                format_version(version.flight_sw_version),
+                format_custom_version(&version.flight_custom_version)
+            );
+            println!(
+                "{:<20} {}",
+                "Middleware",
+                format_version(version.middleware_sw_version)
+            );
+            println!("{:<20} {}", "OS", format_version(version.os_sw_version));
+            println!("{:<20} 0x{:08x}", "Board version", version.board_version);
+            println!(
+                "{:<20} 0x{:04x}/0x{:04x}",
+                "Vendor/Product", version.vendor_id, version.product_id
+            );
+            println!("{:<20} 0x{:016x}", "UID", version.uid);
+            println!("{:<20} {:?}", "Capabilities", version.capabilities);
+            println!(
+                "{:<20} {}",
+                "FTP advertised",
+                yes_no(
+                    version.capabilities.contains(
+                        mavlink::common::MavProtocolCapability::MAV_PROTOCOL_CAPABILITY_FTP
+                    )
+                )
+            );
+        }
+        None => println!("{:<20} unavailable", "Firmware"),
+    }
+
+    println!("{:<20} {}", "FTP reachable", yes_no(status.ftp_reachable));
+    println!("{:<20} {}", "Burst read", yes_no(status.burst_support));
+    println!("{:<20} {}", "Write", yes_no(status.write_support));
+    match (status.max_payload, status.doctor) {
+        (Some(max_payload), _) => println!("{:<20} {} B", "Max payload", max_payload),
+        (None, true) => println!("{:<20} unknown", "Max payload"),
+        (None, false) => println!("{:<20} {} B", "Max payload", MAX_DATA_SIZE),
+    }
+}
+
+// What doctor found wrong with the link, with what to do about it, 1 if FTP can't be used at all
+fn print_findings(status: &InfoStatus, target_component: u8) -> i32 {
+    let mut round_trips = status.round_trips.clone();
+    round_trips.sort();
+    if let (Some(min), Some(max)) = (round_trips.first(), round_trips.last()) {
+        println!(
+            "{:<20} min {:.0} ms, median {:.0} ms, max {:.0} ms",
+            "Round trip",
+            min.as_secs_f64() * 1000.0,
+            round_trips[round_trips.len() / 2].as_secs_f64() * 1000.0,
+            max.as_secs_f64() * 1000.0
+        );
+    }
+    println!("{:<20} {}", "Resends", status.resends);
+
+    let mut findings = Vec::new();
+    if !status.ftp_reachable {
+        findings.push(format!(
+            "no reply to FTP requests: check that the autopilot has MAVLink FTP enabled and that \
+             component {} is the one serving it (--target-component)",
+            target_component
+        ));
+    }
+    if status.version.is_none() {
+        findings.push(
+            "no AUTOPILOT_VERSION: the firmware didn't answer MAV_CMD_REQUEST_MESSAGE".to_string(),
+        );
+    }
+    for probe in &status.unanswered {
+        findings.push(format!(
+            "no reply to the {} probe, the vehicle may drop these requests",
+            probe
+        ));
+    }
+    if let (true, None, Some((path, _))) =
+        (status.probe_refused, status.max_payload, &status.probe_file)
+    {
+        findings.push(format!(
+            "{} could not be opened to measure the payload size",
+            path
+        ));
+    }
+    if status.ftp_reachable && !status.burst_support {
+        findings
+            .push("burst reads are not supported: read, get, grep and hexdump will stall".into());
+    }
+    if status.ftp_reachable && !status.write_support {
+        findings.push("writes are not supported: write, mkdir, rm and cp will fail".to_string());
+    }
+    if let (Some(max_payload), Some((_, size))) = (status.max_payload, &status.probe_file) {
+        if max_payload < MAX_DATA_SIZE.min(*size as usize) {
+            findings.push(format!(
+                "the vehicle sends at most {} bytes per reply: use --chunk-size {}",
+                max_payload, max_payload
+            ));
+        }
+    }
+    if let Some(median) = round_trips.get(round_trips.len() / 2) {
+        if *median >= STALL_TIMEOUT / 2 {
+            findings.push(format!(
+                "round trips of {:.0} ms are close to the {} s reply timeout: expect resends, \
+                 use a faster link or a lower telemetry rate",
+                median.as_secs_f64() * 1000.0,
+                STALL_TIMEOUT.as_secs()
+            ));
+        }
+    }
+    if status.ftp_reachable && status.resends > 0 {
+        findings.push(format!(
+            "{} requests had to be resent: the link is losing messages, check the radio and \
+             lower the telemetry rate of the other streams",
+            status.resends
+        ));
+    }
+
+    println!();
+    if findings.is_empty() {
+        println!("no problems found");
+    }
+    for finding in findings {
+        println!("- {}", finding);
+    }
+    if status.ftp_reachable {
+        0
+    } else {
+        1
+    }
+}
+
+fn format_version(version: u32) -> String {
+    let [kind, patch, minor, major] = version.to_le_bytes();
+    let kind = match kind {
+        0 => "dev",
+        64 => "alpha",
+        128 => "beta",
+        192 => "rc",
+        255 => "official",
+        _ => "unknown",
+    };
+    format!("{}.{}.{} {}", major, minor, patch, kind)
+}
+
+fn format_custom_version(version: &[u8; 8]) -> String {
+    if version
+        .iter()
+        .all(|byte| byte.is_ascii_graphic() || *byte == 0)
+    {
+        return version
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| byte as char)
+            .collect();
+    }
+    version.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Names longer than this are cut when printing to a terminal
+const MAX_NAME_WIDTH: usize = 60;
+
+// Files from this size on stand out in colored listings
+const HUGE_FILE_SIZE: u64 = 100 * 1024 * 1024;
+
+// ANSI escape codes of the listing colors
+const BLUE: Option<&str> = Some("\x1b[1;34m");
+const GRAY: Option<&str> = Some("\x1b[2m");
+const YELLOW: Option<&str> = Some("\x1b[33m");
+
+fn paint(text: &str, color: Option<&str>) -> String {
+    match color {
+        Some(color) if !text.is_empty() => format!("{}{}\x1b[0m", color, text),
+        _ => text.to_string(),
+    }
+}
+
+fn print_listing(
+    entries: &[EntryInfo],
+    directory: &str,
+    full_path: bool,
+    color: bool,
+    units: SizeUnits,
+) {
+    // Scripts reading the output get the whole names and no colors
+    let terminal = std::io::stdout().is_terminal();
+    let color = color && terminal;
+    let names: Vec<String> = entries
+        .iter()
+        .map(|entry| match full_path {
+            true => format!("{}/{}", directory.trim_end_matches('/'), entry.name),
+            false => entry.name.clone(),
+        })
+        .map(|name| escape_name(&name))
+        .map(|name| match terminal {
+            true => truncate_name(name, MAX_NAME_WIDTH),
+            false => name,
+        })
+        .collect();
+    // Empty sizes are left blank
+    let sizes: Vec<String> = entries
+        .iter()
+        .map(|entry| match entry.size {
+            0 => String::new(),
+            size => format_size(size as u64, units),
+        })
+        .collect();
+
+    let name_width = names
+        .iter()
+        .map(|name| name.chars().count())
+        .chain(["Name".len()])
+        .max()
+        .unwrap_or_default();
+    let size_width = sizes
+        .iter()
+        .map(|size| size.len())
+        .chain(["Size".len()])
+        .max()
+        .unwrap_or_default();
+
+    println!(
+        "{:<4} {:<name_width$} {:>size_width$}",
+        "Type",
+        "Name",
+        "Size",
+        name_width = name_width,
+        size_width = size_width
+    );
+    println!("{}", "-".repeat(4 + 1 + name_width + 1 + size_width));
+    for ((entry, name), size) in entries.iter().zip(&names).zip(&sizes) {
+        let item_type = match entry.entry_type {
+            EntryType::File => 'F',
+            EntryType::Directory => 'D',
+            EntryType::Skip => 'S',
+        };
+        let (name_color, size_color) = match entry.entry_type {
+            EntryType::Directory => (BLUE, None),
+            EntryType::Skip => (GRAY, None),
+            EntryType::File if entry.size as u64 >= HUGE_FILE_SIZE => (None, YELLOW),
+            EntryType::File => (None, None),
+        };
+        // Padding stays out of the colors, so trailing spaces can be trimmed
+        let line = format!(
+            "{:<4} {}{} {}{}",
+            item_type,
+            paint(name, name_color.filter(|_| color)),
+            " ".repeat(name_width - name.chars().count()),
+            " ".repeat(size_width - size.len()),
+            paint(size, size_color.filter(|_| color)),
+        );
+        println!("{}", line.trim_end());
+    }
+}
+
+// The widths of the names are not known yet, so they go last
+fn print_listing_stream(
+    entries: &[EntryInfo],
+    directory: &str,
+    full_path: bool,
+    color: bool,
+    units: SizeUnits,
+    header: bool,
+) {
+    const SIZE_WIDTH: usize = 9;
+    let color = color && std::io::stdout().is_terminal();
+    if header {
+        println!("{:<4} {:>SIZE_WIDTH$} Name", "Type", "Size");
+        println!("{}", "-".repeat(4 + 1 + SIZE_WIDTH + 1 + "Name".len()));
+    }
+    for entry in entries {
+        let name = escape_name(&match full_path {
+            true => format!("{}/{}", directory.trim_end_matches('/'), entry.name),
+            false => entry.name.clone(),
+        });
+        let (item_type, name_color, size_color) = match entry.entry_type {
+            EntryType::File if entry.size as u64 >= HUGE_FILE_SIZE => ('F', None, YELLOW),
+            EntryType::File => ('F', None, None),
+            EntryType::Directory => ('D', BLUE, None),
+            EntryType::Skip => ('S', GRAY, None),
+        };
+        let size = match entry.size {
+            0 => String::new(),
+            size => format_size(size as u64, units),
+        };
+        println!(
+            "{:<4} {}{} {}",
+            item_type,
+            " ".repeat(SIZE_WIDTH.saturating_sub(size.len())),
+            paint(&size, size_color.filter(|_| color)),
+            paint(&name, name_color.filter(|_| color)),
+        );
+    }
+}
+
+// The log asked for, else the one LASTLOG.TXT names, else the one with the highest number
+fn log_read(status: FindingLogStatus, last_log: Option<u32>) -> (String, PathBuf, ReadOptions) {
+    let log = match status.number {
+        Some(number) => status.logs.iter().find(|(log, _)| *log == number),
+        None => {
+            let last = last_log.and_then(|number| {
+                let log = status.logs.iter().find(|(log, _)| *log == number);
+                if log.is_none() {
+                    println!(
+                        "{} names log {}, which is not in {}, reading the highest one",
+                        LAST_LOG_NAME, number, status.directory
+                    );
+                }
+                log
+            });
+            last.or_else(|| status.logs.iter().max_by_key(|(log, _)| *log))
+        }
+    };
+    let Some((_, name)) = log else {
+        match status.number {
+            Some(number) => println!("logs failed: no log {} in {}", number, status.directory),
+            None => println!("logs failed: no logs in {}", status.directory),
+        }
+        exit(1);
+    };
+    if status.number.is_none() {
+        println!("latest log is {}", name);
+    }
+
+    let path = format!("{}/{}", status.directory.trim_end_matches('/'), name);
+    let mut local_path = status.options.output_dir.clone().unwrap_or_default();
+    local_path.push(name);
+    (path, local_path, status.options)
+}
+
+// The number of a log file name, None for the other files
+fn log_number(name: &str) -> Option<u32> {
+    let stem = name.get(..name.len().checked_sub(LOG_EXTENSION.len())?)?;
+    if !name[stem.len()..].eq_ignore_ascii_case(LOG_EXTENSION) {
         return None;
     }
+    stem.parse().ok()
+}
+
+// Same as diff, finding differences is a failure
+fn print_params_diff(diff: &ParamDiff, color: bool) -> i32 {
+    let (compared, differences) = match diff.finish() {
+        Ok(result) => result,
+        Err(error) => {
+            println!("params failed: {}: {}", PARAM_PATH, error);
+            return 1;
+        }
+    };
+    if differences.is_empty() {
+        println!("all {} parameters match", compared);
+        return 0;
+    }
+
+    let color = color && std::io::stdout().is_terminal();
+    let vehicle: Vec<String> = differences
+        .iter()
+        .map(|difference| match difference.vehicle {
+            Some(value) => value.to_string(),
+            None => "missing".to_string(),
+        })
+        .collect();
+    let name_width = differences
+        .iter()
+        .map(|difference| difference.name.len())
+        .chain(["Name".len()])
+        .max()
+        .unwrap_or_default();
+    let vehicle_width = vehicle
+        .iter()
+        .map(|value| value.len())
+        .chain(["Vehicle".len()])
+        .max()
+        .unwrap_or_default();
+
+    println!(
+        "{:<name_width$} {:<vehicle_width$} File",
+        "Name",
+        "Vehicle",
+        name_width = name_width,
+        vehicle_width = vehicle_width
+    );
+    println!(
+        "{}",
+        "-".repeat(name_width + 1 + vehicle_width + 1 + "File".len())
+    );
+    for (difference, vehicle) in differences.iter().zip(&vehicle) {
+        let vehicle_color = match difference.vehicle {
+            Some(_) => YELLOW,
+            None => GRAY,
+        };
+        println!(
+            "{:<name_width$} {}{} {}",
+            difference.name,
+            paint(vehicle, vehicle_color.filter(|_| color)),
+            " ".repeat(vehicle_width - vehicle.len()),
+            difference.saved,
+            name_width = name_width
+        );
+    }
+    println!("{} of {} parameters differ", differences.len(), compared);
+    1
+}
+
+// Meant for scripts, so there is no header and no colors
+fn print_listing_template(
+    entries: &[EntryInfo],
+    directory: &str,
+    template: &Template,
+    units: SizeUnits,
+) {
+    for entry in entries {
+        let line = template.render(|field| match field {
+            "type" => match entry.entry_type {
+                EntryType::File => "F",
+                EntryType::Directory => "D",
+                EntryType::Skip => "S",
+            }
+            .to_string(),
+            "name" => escape_name(&entry.name),
+            "path" => escape_name(&format!(
+                "{}/{}",
+                directory.trim_end_matches('/'),
+                entry.name
+            )),
+            "size" => match entry.size {
+                0 => String::new(),
+                size => format_size(size as u64, units),
+            },
+            "bytes" => entry.size.to_string(),
+            _ => unreachable!("{} is not in LIST_FIELDS", field),
+        });
+        println!("{}", line);
+    }
+}
+
+fn print_listing_json(entries: &[EntryInfo], directory: &str) {
+    println!("[");
+    for (index, entry) in entries.iter().enumerate() {
+        let separator = if index + 1 < entries.len() { "," } else { "" };
+        println!("  {}{}", entry_json(entry, directory), separator);
+    }
+    println!("]");
+}
+
+fn entry_json(entry: &EntryInfo, directory: &str) -> String {
+    let entry_type = match entry.entry_type {
+        EntryType::File => "file",
+        EntryType::Directory => "directory",
+        EntryType::Skip => "skip",
+    };
+    let path = format!("{}/{}", directory.trim_end_matches('/'), entry.name);
+    let mut object = format!(
+        "{{\"type\": \"{}\", \"name\": \"{}\", \"path\": \"{}\", \"size\": {}",
+        entry_type,
+        json::escape(&entry.name),
+        json::escape(&path),
+        entry.size
+    );
+    if std::str::from_utf8(&entry.raw_name).is_err() {
+        object.push_str(&format!(
+            ", \"name_base64\": \"{}\"",
+            json::base64(&entry.raw_name)
+        ));
+    }
+    object.push('}');
+    object
+}
+
+// Control characters would break the table, they are shown escaped as \n or \x1b
+fn escape_name(name: &str) -> String {
+    let mut escaped = String::new();
+    for c in name.chars() {
+        match c {
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn truncate_name(name: String, width: usize) -> String {
+    if name.chars().count() <= width {
+        return name;
+    }
+    let mut truncated: String = name.chars().take(width - 1).collect();
+    truncated.push('…');
+    truncated
+}
+
+// Units of the sizes that are printed, binary multipliers by default
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SizeUnits {
+    Binary,
+    Si,
+    Bytes,
+}
+
+fn format_size(size: u64, units: SizeUnits) -> String {
+    let (base, names) = match units {
+        SizeUnits::Binary => (1024.0, ["B", "KiB", "MiB", "GiB", "TiB"]),
+        SizeUnits::Si => (1000.0, ["B", "kB", "MB", "GB", "TB"]),
+        SizeUnits::Bytes => return size.to_string(),
+    };
+
+    // The next unit is used as soon as rounding would print a full one, 1023.96 KiB is 1.0 MiB
+    let mut value = size as f64;
+    let mut unit = 0;
+    while unit + 1 < names.len() && (value * 10.0).round() / 10.0 >= base {
+        value /= base;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", size),
+        _ => format!("{:.1} {}", value, names[unit]),
+    }
 }
 
-fn format_size(size: u64) -> String {
-    const KILO: u64 = 1024;
-    const MEGA: u64 = KILO * 1024;
-    const GIGA: u64 = MEGA * 1024;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ftp_message(payload: MavlinkFtpPayload) -> Message {
+        mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(
+            mavlink::common::FILE_TRANSFER_PROTOCOL_DATA {
+                target_network: 0,
+                target_system: 1,
+                target_component: 1,
+                payload: payload.to_bytes(),
+            },
+        )
+        .into()
+    }
+
+    fn request(message: Option<Message>) -> MavlinkFtpPayload {
+        match message {
+            Some(Message::Common(mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(message))) => {
+                MavlinkFtpPayload::from_bytes(&message.payload).unwrap()
+            }
+            message => panic!("expected an FTP request, got {:?}", message),
+        }
+    }
+
+    // The page of a directory with `count` files that starts at `offset`, as big as it fits
+    fn list_reply(offset: u32, count: u32) -> MavlinkFtpPayload {
+        let mut data = Vec::new();
+        for index in offset..count {
+            let entry = format!("FLOG{:05}.BIN\t{}\0", index, index * 10);
+            if data.len() + entry.len() > MAX_DATA_SIZE {
+                break;
+            }
+            data.extend_from_slice(entry.as_bytes());
+        }
+        MavlinkFtpPayload {
+            seq_number: 2,
+            session: 0,
+            opcode: MavlinkFtpOpcode::Ack,
+            size: data.len(),
+            req_opcode: MavlinkFtpOpcode::ListDirectory,
+            burst_complete: 0,
+            padding: 0,
+            offset,
+            data,
+        }
+    }
+
+    #[test]
+    fn lists_directories_with_thousands_of_entries() {
+        const COUNT: u32 = 3000;
+        let mut controller = Controller::new(1, 1);
+        controller.list_directory("/APM/LOGS".into(), ListOptions::default());
+
+        let mut next = request(controller.run().pop());
+        while next.offset < COUNT {
+            assert_eq!(next.opcode, MavlinkFtpOpcode::ListDirectory);
+            let reply = list_reply(next.offset, COUNT);
+            next = request(controller.parse_mavlink_message(&ftp_message(reply)));
+        }
+
+        assert_eq!(next.offset, COUNT);
+        assert_eq!(controller.entries.len(), COUNT as usize);
+        for (index, entry) in controller.entries.iter().enumerate() {
+            assert_eq!(entry.name, format!("LOG{:05}.BIN", index));
+            assert_eq!(entry.size, index as u32 * 10);
+        }
+    }
+
+    #[test]
+    fn restarts_a_download_whose_remote_size_changed() {
+        let local_path =
+            std::env::temp_dir().join(format!("mavftp-restart-{}", std::process::id()));
+        std::fs::write(&local_path, [1; 100]).unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&local_path)
+            .unwrap();
+        let mut status = ReadingFileStatus {
+            path: "/APM/LOGS/00000001.BIN".into(),
+            local_path: local_path.clone(),
+            offset: 100,
+            file_size: 200,
+            destination: ReadDestination::Mapped(unsafe {
+                memmap2::MmapMut::map_mut(&file).unwrap()
+            }),
+            fsync_every: None,
+            synced: 100,
+            crc: 1234,
+            sha256: Some(Sha256::default()),
+        };
+
+        status.restart(300).unwrap();
+        assert_eq!(status.offset, 0);
+        assert_eq!(status.file_size, 300);
+        assert_eq!(status.synced, 0);
+        assert_eq!(status.crc, 0);
+        match &status.destination {
+            ReadDestination::Mapped(map) => assert_eq!(map.iter().filter(|b| **b != 0).count(), 0),
+            _ => panic!("expected the file to be mapped again"),
+        }
+        assert_eq!(std::fs::metadata(&local_path).unwrap().len(), 300);
+        std::fs::remove_file(local_path).unwrap();
+    }
+
+    #[test]
+    fn tags_the_sequence_numbers_with_the_slot() {
+        let mut controller = Controller::new(1, 1);
+        controller.set_slot(2);
+        controller.list_directory("/APM/LOGS".into(), ListOptions::default());
+
+        let first = request(controller.run().pop());
+        assert_eq!(slot_of(first.seq_number), 2);
+
+        // The reply keeps the slot, the controller goes on as if it wasn't there
+        let mut reply = list_reply(0, 300);
+        reply.seq_number = first.seq_number + 1;
+        let next = request(controller.parse_mavlink_message(&ftp_message(reply)));
+        assert_eq!(slot_of(next.seq_number), 2);
+        assert_eq!(next.offset as usize, controller.entries.len());
+    }
+
+    #[test]
+    fn takes_the_overlapped_crc32_only_from_its_own_reply() {
+        let mut controller = Controller::new(1, 1);
+        for (name, crc) in [("a.BIN", 0x1234), ("b.BIN", 0x5678)] {
+            let entry = ReceiptEntry::with_crc(name.into(), format!("/APM/{}", name), 10, crc);
+            controller.overlapped.push(entry);
+        }
+        let reply = |request: &MavlinkFtpPayload, crc: u32| {
+            ftp_message(MavlinkFtpPayload {
+                seq_number: request.seq_number + 1,
+                session: 0,
+                opcode: MavlinkFtpOpcode::Ack,
+                size: 4,
+                req_opcode: MavlinkFtpOpcode::CalcFileCRC32,
+                burst_complete: 0,
+                padding: 0,
+                offset: 0,
+                data: crc.to_le_bytes().to_vec(),
+            })
+        };
+
+        let first = request(controller.run().pop());
+        assert_eq!(first.opcode, MavlinkFtpOpcode::CalcFileCRC32);
+        // Not asked again before its timeout
+        assert!(controller.run().is_empty());
+        assert!(controller
+            .parse_mavlink_message(&reply(&first, 0x1234))
+            .is_none());
+        assert_eq!(controller.overlapped.len(), 1);
+
+        let second = request(controller.run().pop());
+        assert_ne!(second.seq_number, first.seq_number);
+        // The first reply arriving once more isn't taken for the second file
+        controller.parse_mavlink_message(&reply(&first, 0x1234));
+        assert_eq!(controller.overlapped.len(), 1);
+        controller.parse_mavlink_message(&reply(&second, 0x5678));
+        assert!(controller.overlapped.is_empty());
+        assert!(!controller.overlap_failed);
+    }
+
+    #[test]
+    fn keeps_the_reply_to_the_last_sequence_number_in_its_slot() {
+        for slot in 0..MAX_SLOTS as u8 {
+            let last = tag_sequence(SEQUENCE_MASK - 1, slot);
+            let reply = last.wrapping_add(1);
+            assert_eq!(
+                (slot_of(reply), untag_sequence(reply)),
+                (slot, SEQUENCE_MASK)
+            );
+
+            // One more wraps to zero instead of running into the next slot
+            let wrapped = tag_sequence(SEQUENCE_MASK, slot);
+            assert_eq!((slot_of(wrapped), untag_sequence(wrapped)), (slot, 0));
+        }
+        assert_eq!(tag_sequence(u16::MAX, 0), u16::MAX % SEQUENCE_MASK);
+    }
+
+    #[test]
+    fn formats_sizes_at_unit_boundaries() {
+        assert_eq!(format_size(0, SizeUnits::Binary), "0 B");
+        assert_eq!(format_size(1023, SizeUnits::Binary), "1023 B");
+        assert_eq!(format_size(1024, SizeUnits::Binary), "1.0 KiB");
+        assert_eq!(format_size(1024 * 1024 - 1, SizeUnits::Binary), "1.0 MiB");
+        assert_eq!(
+            format_size(1024 * 1024 - 52, SizeUnits::Binary),
+            "1023.9 KiB"
+        );
+        assert_eq!(format_size(999, SizeUnits::Si), "999 B");
+        assert_eq!(format_size(999_960, SizeUnits::Si), "1.0 MB");
+        assert_eq!(format_size(13893, SizeUnits::Si), "13.9 kB");
+        assert_eq!(format_size(13893, SizeUnits::Bytes), "13893");
+    }
+
+    #[test]
+    fn ignores_a_page_received_twice() {
+        let mut controller = Controller::new(1, 1);
+        controller.list_directory("/APM/LOGS".into(), ListOptions::default());
+        request(controller.run().pop());
+
+        let first = request(controller.parse_mavlink_message(&ftp_message(list_reply(0, 300))));
+        let received = controller.entries.len();
+        assert_eq!(first.offset as usize, received);
 
-    match size {
-        0 => String::new(),
-        1..=KILO => format!("{} B", size),
-        KILO..=MEGA => format!("{:.1} KB", (size as f64) / (KILO as f64)),
-        MEGA..=GIGA => format!("{:.1} MB", (size as f64) / (MEGA as f64)),
-        _ => format!("{:.1} GB", (size as f64) / (GIGA as f64)),
+        assert!(controller
+            .parse_mavlink_message(&ftp_message(list_reply(0, 300)))
+            .is_none());
+        assert_eq!(controller.entries.len(), received);
     }
 }
@@ -0,0 +1,178 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cli;
+use crate::fleet;
+
+// Kept in the local directory unless --state says otherwise
+const STATE_NAME: &str = ".mavftp-sync";
+
+// The first retry after a failed pass, the delay doubles with every failure that follows
+const RETRY_DELAY: Duration = Duration::from_secs(15);
+
+// What is remembered between runs, so a restarted daemon keeps its schedule and its backoff
+#[derive(Default)]
+struct State {
+    // Seconds since 1970-01-01 UTC
+    last_attempt: Option<u64>,
+    last_success: Option<u64>,
+    failures: u32,
+}
+
+impl State {
+    // A missing or unreadable file starts from scratch
+    fn load(path: &Path) -> Self {
+        let mut state = State::default();
+        let Ok(content) = std::fs::read_to_string(path) else {
+            return state;
+        };
+        for line in content.lines().filter(|line| !line.starts_with('#')) {
+            let Some((key, value)) = line.split_once(' ') else {
+                continue;
+            };
+            match key {
+                "last_attempt" => state.last_attempt = value.parse().ok(),
+                "last_success" => state.last_success = value.parse().ok(),
+                "failures" => state.failures = value.parse().unwrap_or_default(),
+                _ => {}
+            }
+        }
+        state
+    }
+
+    // Written to a temporary file first, so an interrupted write doesn't lose the state
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut content = String::from("# mavftp-cli sync state\n");
+        if let Some(time) = self.last_attempt {
+            content.push_str(&format!("last_attempt {}\n", time));
+        }
+        if let Some(time) = self.last_success {
+            content.push_str(&format!("last_success {}\n", time));
+        }
+        content.push_str(&format!("failures {}\n", self.failures));
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, content)?;
+        std::fs::rename(&temporary, path)
+    }
+
+    // Failed passes are tried again sooner than the next one is due, but never more often than
+    // the backoff allows
+    fn delay(&self, every: Duration) -> Duration {
+        match self.failures {
+            0 => every,
+            failures => RETRY_DELAY
+                .saturating_mul(2u32.saturating_pow(failures - 1))
+                .min(every),
+        }
+    }
+
+    // Right away when the state holds a time too far away to add to
+    fn next_pass(&self, every: Duration) -> SystemTime {
+        self.last_attempt
+            .and_then(|time| UNIX_EPOCH.checked_add(Duration::from_secs(time)))
+            .and_then(|time| time.checked_add(self.delay(every)))
+            .unwrap_or(UNIX_EPOCH)
+    }
+}
+
+pub struct SyncOptions {
+    pub remote: String,
+    pub local: PathBuf,
+    pub every: Duration,
+    pub compare_crc: bool,
+    pub state: Option<PathBuf>,
+}
+
+// Every pass is a read --recursive --skip-existing of its own, in a new process with the same
+// connection options, so a failed pass only delays the next one
+pub fn run(options: SyncOptions) -> ! {
+    let program = std::env::current_exe().unwrap_or_else(|_| "mavftp-cli".into());
+    // The options before the daemon command, which is the first one
+    let args: Vec<String> = std::env::args().collect();
+    let global = &args[1..cli::command_starts(&args)[0]];
+    let local = fleet::output_dir(Some(options.local.clone())).unwrap_or_default();
+    if let Err(error) = std::fs::create_dir_all(&local) {
+        println!("daemon failed: {}: {}", local.display(), error);
+        crate::exit(1);
+    }
+    let state_path = options.state.unwrap_or_else(|| local.join(STATE_NAME));
+    let mut state = State::load(&state_path);
+
+    loop {
+        let wait = state
+            .next_pass(options.every)
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            println!("[sync] next pass in {}", format_duration(wait));
+            std::thread::sleep(wait);
+        }
+
+        let started = SystemTime::now();
+        state.last_attempt = Some(seconds(started));
+        println!("[sync] {} into {}", options.remote, local.display());
+        let mut command = Command::new(&program);
+        command
+            .args(global)
+            .args(["read", "--recursive", "--skip-existing"])
+            .arg("--output-dir")
+            .arg(&options.local);
+        if options.compare_crc {
+            command.arg("--compare-crc");
+        }
+        let result = command.arg(&options.remote).status();
+
+        let elapsed = started.elapsed().unwrap_or_default();
+        match result {
+            Ok(status) if status.success() => {
+                state.failures = 0;
+                state.last_success = Some(seconds(started));
+                println!(
+                    "[sync] pass done in {}",
+                    format_duration(Duration::from_secs(elapsed.as_secs()))
+                );
+            }
+            result => {
+                state.failures += 1;
+                let reason = match result {
+                    Ok(status) => match status.code() {
+                        Some(code) => format!("exit code {}", code),
+                        None => "killed".to_string(),
+                    },
+                    Err(error) => format!("failed to start: {}", error),
+                };
+                println!(
+                    "[sync] pass failed ({}), {} in a row, trying again in {}",
+                    reason,
+                    state.failures,
+                    format_duration(state.delay(options.every))
+                );
+            }
+        }
+        if let Err(error) = state.save(&state_path) {
+            println!(
+                "[sync] state not saved: {}: {}",
+                state_path.display(),
+                error
+            );
+        }
+    }
+}
+
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// As the durations of the command line, 1h30m or 45s
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs().max(1);
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    [(hours, "h"), (minutes, "m"), (seconds, "s")]
+        .iter()
+        .filter(|(value, _)| *value > 0)
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect()
+}
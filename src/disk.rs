@@ -0,0 +1,51 @@
+use std::path::Path;
+
+// Bytes that can still be written in the filesystem of a directory, None when it can't be known
+pub fn available_space(directory: &Path) -> Option<u64> {
+    // The directory may not exist yet, its closest existing parent is on the same filesystem
+    let directory = directory
+        .ancestors()
+        .map(|path| match path.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => path,
+        })
+        .find(|path| path.is_dir())?;
+    filesystem_available_space(directory)
+}
+
+#[cfg(unix)]
+fn filesystem_available_space(directory: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(directory.as_os_str().as_bytes()).ok()?;
+    // Safety: the path is a valid C string and statvfs only writes to stat
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    // Blocks left to unprivileged users, not the ones kept for root
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+fn filesystem_available_space(directory: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    let path: Vec<u16> = directory.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0u64;
+    // Safety: the path is NUL terminated and only available is written
+    let result = unsafe {
+        windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    (result != 0).then_some(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn filesystem_available_space(_directory: &Path) -> Option<u64> {
+    None
+}
@@ -0,0 +1,179 @@
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::exit;
+
+// Set for the process that runs the commands against one vehicle of the fleet
+const VEHICLE_ENV: &str = "MAVFTP_FLEET_VEHICLE";
+
+// System ids as "1,2,5" or ranges as "1-12", in the command line or a file
+#[derive(Debug)]
+pub struct TargetList(pub Vec<u8>);
+
+impl std::str::FromStr for TargetList {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parse = |id: &str| {
+            id.trim()
+                .parse::<u8>()
+                .map_err(|_| format!("invalid system id {}", id.trim()))
+        };
+        let mut targets = Vec::new();
+        for item in value.split(',').filter(|item| !item.trim().is_empty()) {
+            match item.split_once('-') {
+                Some((first, last)) => targets.extend(parse(first)?..=parse(last)?),
+                None => targets.push(parse(item)?),
+            }
+        }
+        if targets.is_empty() {
+            return Err("no system id".into());
+        }
+        Ok(TargetList(targets))
+    }
+}
+
+// One list per line, anything after a '#' is a comment
+pub fn read_targets_file(path: &Path) -> Result<Vec<u8>, String> {
+    let content =
+        std::fs::read_to_string(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+    let mut targets = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        if line.trim().is_empty() {
+            continue;
+        }
+        let list: TargetList = line
+            .parse()
+            .map_err(|error| format!("{}: {}", path.display(), error))?;
+        targets.extend(list.0);
+    }
+    Ok(targets)
+}
+
+// The vehicle this process works on, when started by the fleet mode
+pub fn vehicle() -> Option<u8> {
+    std::env::var(VEHICLE_ENV).ok()?.parse().ok()
+}
+
+// Downloads of each vehicle go to a directory of their own, so the same file of every vehicle can be kept
+pub fn output_dir(output_dir: Option<PathBuf>) -> Option<PathBuf> {
+    match vehicle() {
+        Some(id) => Some(output_dir.unwrap_or_default().join(vehicle_name(id))),
+        None => output_dir,
+    }
+}
+
+// receipt.txt becomes receipt-vehicle-5.txt
+pub fn receipt(receipt: Option<PathBuf>) -> Option<PathBuf> {
+    let (Some(receipt), Some(id)) = (&receipt, vehicle()) else {
+        return receipt;
+    };
+    let stem = receipt.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match receipt.extension() {
+        Some(extension) => format!(
+            "{}-{}.{}",
+            stem,
+            vehicle_name(id),
+            extension.to_string_lossy()
+        ),
+        None => format!("{}-{}", stem, vehicle_name(id)),
+    };
+    Some(receipt.with_file_name(name))
+}
+
+fn vehicle_name(id: u8) -> String {
+    format!("vehicle-{}", id)
+}
+
+// Every vehicle gets the same command line in a process of its own, then the results are summarized
+pub fn run(targets: &[u8], parallel: bool) -> ! {
+    let program = std::env::current_exe().unwrap_or_else(|_| "mavftp-cli".into());
+    let args: Vec<_> = std::env::args_os().skip(1).collect();
+    let command = |id: u8| {
+        let mut command = Command::new(&program);
+        command
+            .args(&args)
+            .env(VEHICLE_ENV, id.to_string())
+            .env("MAVFTP_TARGET_SYSTEM", id.to_string());
+        command
+    };
+
+    let mut results = Vec::new();
+    if parallel {
+        // Output is prefixed with the system id, the lines of many vehicles get mixed together
+        let mut children = Vec::new();
+        for &id in targets {
+            let child = command(id)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn();
+            match child {
+                Ok(mut child) => {
+                    let printers = [
+                        prefix_lines(id, child.stdout.take()),
+                        prefix_lines(id, child.stderr.take()),
+                    ];
+                    children.push((id, child, printers));
+                }
+                Err(error) => results.push((id, Err(error.to_string()))),
+            }
+        }
+        for (id, mut child, printers) in children {
+            let status = child.wait();
+            for printer in printers {
+                let _ = printer.join();
+            }
+            results.push((id, status.map_err(|error| error.to_string())));
+        }
+        results.sort_by_key(|(id, _)| *id);
+    } else {
+        for &id in targets {
+            println!("== {} ==", vehicle_name(id));
+            let status = command(id).status();
+            results.push((id, status.map_err(|error| error.to_string())));
+        }
+    }
+
+    println!();
+    let mut failed = 0;
+    for (id, result) in &results {
+        let result = match result {
+            Ok(status) if status.success() => "ok".to_string(),
+            Ok(status) => {
+                failed += 1;
+                match status.code() {
+                    Some(code) => format!("failed (exit code {})", code),
+                    None => "failed (killed)".to_string(),
+                }
+            }
+            Err(error) => {
+                failed += 1;
+                format!("failed to start: {}", error)
+            }
+        };
+        println!("{:<12} {}", vehicle_name(*id), result);
+    }
+    println!(
+        "{} of {} vehicles succeeded",
+        results.len() - failed,
+        results.len()
+    );
+    exit(if failed == 0 { 0 } else { 1 })
+}
+
+fn prefix_lines<R: std::io::Read + Send + 'static>(
+    id: u8,
+    output: Option<R>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || {
+        let Some(output) = output else {
+            return;
+        };
+        for line in BufReader::new(output).lines().map_while(Result::ok) {
+            println!("[{}] {}", id, line);
+        }
+    })
+}
@@ -0,0 +1,52 @@
+use regex::Regex;
+
+// Prints the lines matching a pattern while the file content arrives in chunks
+pub struct GrepMatcher {
+    pattern: Regex,
+    line: Vec<u8>,
+    line_number: usize,
+    matches: usize,
+}
+
+impl GrepMatcher {
+    pub fn new(pattern: Regex) -> Self {
+        Self {
+            pattern,
+            line: Vec::new(),
+            line_number: 0,
+            matches: 0,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        for &byte in data {
+            if byte == b'\n' {
+                self.check_line();
+            } else {
+                self.line.push(byte);
+            }
+        }
+    }
+
+    // Checks the last line if the file doesn't end with a new line, returns the number of matches
+    pub fn finish(&mut self) -> usize {
+        if !self.line.is_empty() {
+            self.check_line();
+        }
+        self.matches
+    }
+
+    fn check_line(&mut self) {
+        self.line_number += 1;
+        if self.line.last() == Some(&b'\r') {
+            self.line.pop();
+        }
+
+        let line = String::from_utf8_lossy(&self.line);
+        if self.pattern.is_match(&line) {
+            self.matches += 1;
+            println!("{}:{}", self.line_number, line);
+        }
+        self.line.clear();
+    }
+}
@@ -0,0 +1,77 @@
+const LINE_SIZE: usize = 16;
+
+// Prints data in the canonical hex+ASCII format, the same as `hexdump -C`
+pub struct HexDumper {
+    offset: u32,
+    line: Vec<u8>,
+    last_line: Option<Vec<u8>>,
+    repeating: bool,
+}
+
+impl HexDumper {
+    pub fn new(offset: u32) -> Self {
+        Self {
+            offset,
+            line: Vec::with_capacity(LINE_SIZE),
+            last_line: None,
+            repeating: false,
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.line.push(byte);
+            if self.line.len() == LINE_SIZE {
+                self.print_line();
+            }
+        }
+    }
+
+    pub fn finish(&mut self) {
+        if !self.line.is_empty() {
+            self.print_line();
+        }
+        println!("{:08x}", self.offset);
+    }
+
+    fn print_line(&mut self) {
+        // Repeated lines are collapsed into a single '*'
+        if self.last_line.as_ref() == Some(&self.line) {
+            if !self.repeating {
+                println!("*");
+                self.repeating = true;
+            }
+        } else {
+            println!("{}", format_line(self.offset, &self.line));
+            self.repeating = false;
+        }
+
+        self.offset += self.line.len() as u32;
+        self.last_line = Some(std::mem::take(&mut self.line));
+    }
+}
+
+fn format_line(offset: u32, bytes: &[u8]) -> String {
+    let mut line = format!("{:08x} ", offset);
+    for index in 0..LINE_SIZE {
+        if index % 8 == 0 {
+            line.push(' ');
+        }
+        match bytes.get(index) {
+            Some(byte) => line.push_str(&format!("{:02x} ", byte)),
+            None => line.push_str("   "),
+        }
+    }
+
+    let ascii: String = bytes
+        .iter()
+        .map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    format!("{} |{}|", line, ascii)
+}
@@ -0,0 +1,115 @@
+use std::process::Command;
+
+use crate::receipt::ReceiptEntry;
+use crate::sha256;
+
+// The --exec-after command, split in words when parsed and run without a shell, so paths need no
+// quoting on any platform
+#[derive(Debug, Clone)]
+pub struct Hook(Vec<String>);
+
+impl std::str::FromStr for Hook {
+    type Err = String;
+
+    // Words are separated by spaces, single or double quotes keep one together
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut words = Vec::new();
+        let mut word: Option<String> = None;
+        let mut quote = None;
+        for c in value.chars() {
+            match (quote, c) {
+                (Some(open), c) if c == open => quote = None,
+                (Some(_), c) => word.get_or_insert_with(String::new).push(c),
+                (None, '\'' | '"') => {
+                    quote = Some(c);
+                    word.get_or_insert_with(String::new);
+                }
+                (None, c) if c.is_whitespace() => words.extend(word.take()),
+                (None, c) => word.get_or_insert_with(String::new).push(c),
+            }
+        }
+        if let Some(quote) = quote {
+            return Err(format!("unterminated {} quote", quote));
+        }
+        words.extend(word);
+        if words.is_empty() {
+            return Err("empty command".into());
+        }
+        Ok(Hook(words))
+    }
+}
+
+impl Hook {
+    // The program and its arguments for a transferred file
+    fn command(&self, entry: &ReceiptEntry) -> Vec<String> {
+        self.0
+            .iter()
+            .map(|word| {
+                word.replace("{local}", &entry.local_path.to_string_lossy())
+                    .replace("{remote}", &entry.remote_path)
+                    .replace("{crc}", &format!("0x{:08x}", entry.local_crc))
+                    .replace(
+                        "{sha256}",
+                        &entry.sha256.as_ref().map_or(String::new(), sha256::hex),
+                    )
+            })
+            .collect()
+    }
+}
+
+// Runs the --exec-after command of a transferred file, returns false if it failed
+pub fn run(hook: &Hook, entry: &ReceiptEntry) -> bool {
+    let command = hook.command(entry);
+    let status = Command::new(&command[0]).args(&command[1..]).status();
+    match status {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            match status.code() {
+                Some(code) => println!(
+                    "exec-after failed on {}: exit code {}",
+                    entry.remote_path, code
+                ),
+                None => println!("exec-after failed on {}: killed", entry.remote_path),
+            }
+            false
+        }
+        Err(error) => {
+            println!(
+                "exec-after failed on {}: {}: {}",
+                entry.remote_path, command[0], error
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_the_command_and_replaces_each_word() {
+        let hook: Hook = "upload --file={local} 'remote path' \"{remote}\" {crc}"
+            .parse()
+            .unwrap();
+        let entry = ReceiptEntry::with_crc(
+            "/tmp/my log.bin".into(),
+            "/APM/LOGS/it's.BIN".into(),
+            10,
+            0xdead,
+        );
+        assert_eq!(
+            hook.command(&entry),
+            [
+                "upload",
+                "--file=/tmp/my log.bin",
+                "remote path",
+                "/APM/LOGS/it's.BIN",
+                "0x0000dead"
+            ]
+        );
+        assert!("upload 'remote".parse::<Hook>().is_err());
+        assert!(" ".parse::<Hook>().is_err());
+        assert_eq!("''".parse::<Hook>().unwrap().0, [""]);
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+// An https:// endpoint, or an http:// one
+#[derive(Debug, Clone)]
+pub struct HttpUrl {
+    pub tls: bool,
+    // As sent in the Host header, without the default port
+    pub authority: String,
+    pub path: String,
+}
+
+impl std::str::FromStr for HttpUrl {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (tls, rest) = match (
+            value.strip_prefix("https://"),
+            value.strip_prefix("http://"),
+        ) {
+            (Some(rest), _) => (true, rest),
+            (None, Some(rest)) => (false, rest),
+            (None, None) => return Err("only https:// and http:// URLs are supported".into()),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let default_port = if tls { ":443" } else { ":80" };
+        let authority = authority.strip_suffix(default_port).unwrap_or(authority);
+        if authority.is_empty() || authority.starts_with(':') {
+            return Err("no host".into());
+        }
+        if let Some((_, port)) = authority.rsplit_once(':') {
+            port.parse::<u16>()
+                .map_err(|_| format!("invalid port {}", port))?;
+        }
+        Ok(HttpUrl {
+            tls,
+            authority: authority.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+impl HttpUrl {
+    // The scheme and the authority, the paths of the requests go after it
+    pub fn base(&self) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{}://{}", scheme, self.authority)
+    }
+}
+
+// The client of the uploads and the notifications, with the TLS roots of webpki
+pub fn agent(timeout: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(timeout).build()
+}
+
+// Only the status line of the response matters
+pub fn check_response(result: Result<ureq::Response, ureq::Error>) -> Result<(), String> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(status, response)) => Err(format!(
+            "{}: {} {}",
+            response.get_url(),
+            status,
+            response.status_text()
+        )),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_urls_without_their_default_port() {
+        let url: HttpUrl = "https://logs.example.com:443/field".parse().unwrap();
+        assert!(url.tls);
+        assert_eq!(url.authority, "logs.example.com");
+        assert_eq!(url.path, "/field");
+        let url: HttpUrl = "http://127.0.0.1:8080".parse().unwrap();
+        assert_eq!(url.base(), "http://127.0.0.1:8080");
+        assert_eq!(url.path, "/");
+        assert!("ftp://host".parse::<HttpUrl>().is_err());
+        assert!("http://:80/".parse::<HttpUrl>().is_err());
+        assert!("http://host:port/".parse::<HttpUrl>().is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Round trip of every request, from sending it to the first reply
+struct LatencyStats {
+    sent_at: Option<Instant>,
+    samples: Vec<Duration>,
+}
+
+static STATS: Mutex<Option<LatencyStats>> = Mutex::new(None);
+
+pub fn enable() {
+    *STATS.lock().unwrap() = Some(LatencyStats {
+        sent_at: None,
+        samples: Vec::new(),
+    });
+}
+
+pub fn request_sent() {
+    if let Some(stats) = STATS.lock().unwrap().as_mut() {
+        stats.sent_at = Some(Instant::now());
+    }
+}
+
+// Only the first reply counts, the rest of a burst doesn't wait for the link
+pub fn reply_received() {
+    if let Some(stats) = STATS.lock().unwrap().as_mut() {
+        if let Some(sent_at) = stats.sent_at.take() {
+            stats.samples.push(sent_at.elapsed());
+        }
+    }
+}
+
+pub fn print() {
+    let Some(stats) = STATS.lock().unwrap().take() else {
+        return;
+    };
+    let mut samples = stats.samples;
+    if samples.is_empty() {
+        println!("latency: no replies");
+        return;
+    }
+    samples.sort();
+
+    let percentile = |p: usize| samples[(samples.len() - 1) * p / 100];
+    let mean = samples.iter().sum::<Duration>() / samples.len() as u32;
+    println!(
+        "latency: {} requests, min {}, mean {}, p50 {}, p90 {}, p99 {}, max {}",
+        samples.len(),
+        format_duration(samples[0]),
+        format_duration(mean),
+        format_duration(percentile(50)),
+        format_duration(percentile(90)),
+        format_duration(percentile(99)),
+        format_duration(samples[samples.len() - 1]),
+    );
+
+    // Buckets double in size: < 1 ms, 1-2 ms, 2-4 ms...
+    let bucket = |sample: &Duration| {
+        let millis = sample.as_millis() as u64;
+        if millis == 0 {
+            0
+        } else {
+            64 - millis.leading_zeros() as usize
+        }
+    };
+    let mut counts = vec![0usize; bucket(&samples[samples.len() - 1]) + 1];
+    for sample in &samples {
+        counts[bucket(sample)] += 1;
+    }
+
+    let largest = *counts.iter().max().unwrap();
+    let first = bucket(&samples[0]);
+    for (index, count) in counts.iter().enumerate().skip(first) {
+        let range = match index {
+            0 => "< 1 ms".to_string(),
+            _ => format!("{}-{} ms", 1u64 << (index - 1), 1u64 << index),
+        };
+        println!(
+            "{:>14} {:<40} {}",
+            range,
+            "#".repeat((count * 40).div_ceil(largest)),
+            count
+        );
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.1} ms", duration.as_secs_f64() * 1000.0)
+}
@@ -0,0 +1,357 @@
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use num_traits::FromPrimitive;
+
+pub type Connection = Arc<Box<dyn mavlink::MavConnection<Message> + Sync + Send>>;
+
+// A message and the link it came through, or why that link broke
+pub type Received = (usize, Result<(mavlink::MavHeader, Message), std::io::Error>);
+
+const COMMAND_LONG_ID: u32 = 76;
+const COMMAND_ACK_ID: u32 = 77;
+// Where the command is in their payloads
+const COMMAND_LONG_COMMAND: usize = 28;
+const COMMAND_ACK_COMMAND: usize = 0;
+
+// The messages of the links are those of common, with the RADIO of the ardupilotmega dialect taken
+// as RADIO_STATUS. The COMMAND_LONG and COMMAND_ACK of mavlink 0.10 only take the commands of
+// common, so the ones of the dialect, as MAV_CMD_SCRIPTING, have variants of their own
+// Nearly all of them are common ones, boxing those would only add an allocation to each
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Common(mavlink::common::MavMessage),
+    // The command of the COMMAND_LONG is left at its default, the one of the dialect replaces it
+    Command(
+        mavlink::ardupilotmega::MavCmd,
+        mavlink::common::COMMAND_LONG_DATA,
+    ),
+    CommandAck(mavlink::ardupilotmega::MavCmd, mavlink::common::MavResult),
+}
+
+impl From<mavlink::common::MavMessage> for Message {
+    fn from(message: mavlink::common::MavMessage) -> Self {
+        Message::Common(message)
+    }
+}
+
+impl mavlink::Message for Message {
+    fn message_id(&self) -> u32 {
+        match self {
+            Message::Common(message) => message.message_id(),
+            Message::Command(..) => COMMAND_LONG_ID,
+            Message::CommandAck(..) => COMMAND_ACK_ID,
+        }
+    }
+
+    fn message_name(&self) -> &'static str {
+        match self {
+            Message::Common(message) => message.message_name(),
+            Message::Command(..) => "COMMAND_LONG",
+            Message::CommandAck(..) => "COMMAND_ACK",
+        }
+    }
+
+    fn ser(&self) -> Vec<u8> {
+        let (mut payload, command, offset) = match self {
+            Message::Common(message) => return message.ser(),
+            Message::Command(command, data) => (data.ser(), command, COMMAND_LONG_COMMAND),
+            Message::CommandAck(command, result) => {
+                let ack = mavlink::common::COMMAND_ACK_DATA {
+                    result: *result,
+                    ..Default::default()
+                };
+                (ack.ser(), command, COMMAND_ACK_COMMAND)
+            }
+        };
+        payload[offset..offset + 2].copy_from_slice(&(*command as u16).to_le_bytes());
+        payload
+    }
+
+    fn parse(
+        version: mavlink::MavlinkVersion,
+        id: u32,
+        payload: &[u8],
+    ) -> Result<Self, mavlink::error::ParserError> {
+        let message = mavlink::ardupilotmega::MavMessage::parse(version, id, payload);
+        if let Some(message) = message.ok().and_then(common_message) {
+            return Ok(Message::Common(message));
+        }
+        if id == COMMAND_ACK_ID {
+            if let Some(ack) = dialect_ack(payload) {
+                return Ok(ack);
+            }
+        }
+        Err(mavlink::error::ParserError::UnknownMessage { id })
+    }
+
+    fn message_id_from_name(name: &str) -> Result<u32, &'static str> {
+        mavlink::common::MavMessage::message_id_from_name(name)
+    }
+
+    fn default_message_from_id(id: u32) -> Result<Self, &'static str> {
+        mavlink::common::MavMessage::default_message_from_id(id).map(Message::Common)
+    }
+
+    fn extra_crc(id: u32) -> u8 {
+        mavlink::ardupilotmega::MavMessage::extra_crc(id)
+    }
+}
+
+// A COMMAND_ACK common couldn't parse, the trailing zeros of the payload are cut over MAVLink 2
+fn dialect_ack(payload: &[u8]) -> Option<Message> {
+    let mut payload = payload.to_vec();
+    payload.resize(mavlink::common::COMMAND_ACK_DATA::ENCODED_LEN, 0);
+    let command = u16::from_le_bytes([payload[0], payload[1]]);
+    Some(Message::CommandAck(
+        mavlink::ardupilotmega::MavCmd::from_u16(command)?,
+        mavlink::common::MavResult::from_u8(payload[2])?,
+    ))
+}
+
+// Heartbeats come every second, and a stalled request is only given up after a few more
+const LINK_TIMEOUT: Duration = Duration::from_secs(3);
+
+struct Link {
+    url: String,
+    connection: Connection,
+    // The last message of the vehicle on this link
+    last_heard: Option<Instant>,
+    lost: bool,
+}
+
+// Redundant connections to the same vehicle, the FTP requests go through one of them at a time
+pub struct Links {
+    links: Vec<Link>,
+    active: usize,
+}
+
+impl Links {
+    // Links that can't be opened are left out, None when none could
+    pub fn connect(urls: &[String]) -> Option<Self> {
+        let mut links = Vec::new();
+        for url in urls {
+            match mavlink::connect(url) {
+                Ok(mut connection) => {
+                    connection.set_protocol_version(mavlink::MavlinkVersion::V2);
+                    links.push(Link {
+                        url: url.clone(),
+                        connection: Arc::new(connection),
+                        last_heard: None,
+                        lost: false,
+                    });
+                }
+                Err(error) => println!("connection failed: {}: {}", url, error),
+            }
+        }
+        (!links.is_empty()).then_some(Self { links, active: 0 })
+    }
+
+    pub fn active(&self) -> &Connection {
+        &self.links[self.active].connection
+    }
+
+    pub fn active_link(&self) -> usize {
+        self.active
+    }
+
+    pub fn connections(&self) -> Vec<Connection> {
+        self.links
+            .iter()
+            .map(|link| link.connection.clone())
+            .collect()
+    }
+
+    pub fn is_active(&self, link: usize) -> bool {
+        link == self.active
+    }
+
+    // The link the first heartbeat came through is used until it goes quiet
+    pub fn select(&mut self, link: usize) {
+        self.active = link;
+    }
+
+    pub fn heard(&mut self, link: usize) {
+        self.links[link].last_heard = Some(Instant::now());
+    }
+
+    // Messages of every link arrive through the same channel
+    pub fn spawn_receivers(&self, messages: mpsc::Sender<Received>) {
+        for (index, link) in self.links.iter().enumerate() {
+            let connection = link.connection.clone();
+            let messages = messages.clone();
+            thread::spawn(move || receive(index, connection, messages));
+        }
+    }
+
+    // Moves away from an active link that went quiet, to one the vehicle is still heard on
+    pub fn check(&mut self) -> Option<String> {
+        let quiet = |link: &Link| {
+            link.last_heard
+                .is_none_or(|heard| heard.elapsed() >= LINK_TIMEOUT)
+        };
+        if !quiet(&self.links[self.active]) {
+            return None;
+        }
+        let next = self.others().find(|&link| !quiet(&self.links[link]))?;
+        let line = format!(
+            "[link] nothing from the vehicle on {} for {} s, switching to {}",
+            self.links[self.active].url,
+            LINK_TIMEOUT.as_secs(),
+            self.links[next].url
+        );
+        self.active = next;
+        Some(line)
+    }
+
+    // Returns the line telling what happened, None when no link is left
+    pub fn lose(&mut self, link: usize, error: &std::io::Error) -> Option<String> {
+        self.links[link].lost = true;
+        let lost = format!("[link] {} lost: {}", self.links[link].url, error);
+        if link != self.active {
+            return Some(lost);
+        }
+        // Any link left is better than a broken one, even if nothing came through it lately
+        let next = self.others().next()?;
+        self.active = next;
+        Some(format!("{}, switching to {}", lost, self.links[next].url))
+    }
+
+    // The links still working, in order after the active one
+    fn others(&self) -> impl Iterator<Item = usize> + '_ {
+        (1..self.links.len())
+            .map(|step| (self.active + step) % self.links.len())
+            .filter(|&link| !self.links[link].lost)
+    }
+}
+
+pub fn send(
+    connection: &Connection,
+    header: &mavlink::MavHeader,
+    message: &Message,
+) -> std::io::Result<usize> {
+    connection
+        .send(header, message)
+        .map_err(|mavlink::error::MessageWriteError::Io(error)| error)
+}
+
+// Older SiK radios send RADIO, which has the fields of RADIO_STATUS, the other messages of the
+// dialect aren't used
+fn common_message(
+    message: mavlink::ardupilotmega::MavMessage,
+) -> Option<mavlink::common::MavMessage> {
+    match message {
+        mavlink::ardupilotmega::MavMessage::common(message) => Some(message),
+        mavlink::ardupilotmega::MavMessage::RADIO(radio) => Some(
+            mavlink::common::MavMessage::RADIO_STATUS(mavlink::common::RADIO_STATUS_DATA {
+                rxerrors: radio.rxerrors,
+                fixed: radio.fixed,
+                rssi: radio.rssi,
+                remrssi: radio.remrssi,
+                txbuf: radio.txbuf,
+                noise: radio.noise,
+                remnoise: radio.remnoise,
+            }),
+        ),
+        _ => None,
+    }
+}
+
+fn receive(index: usize, connection: Connection, messages: mpsc::Sender<Received>) {
+    loop {
+        let message = match connection.recv() {
+            Ok(message) => Ok(message),
+            // The TCP connection reads with a timeout, nothing arrived in the meantime
+            Err(mavlink::error::MessageReadError::Io(error))
+                if matches!(
+                    error.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(mavlink::error::MessageReadError::Io(error)) => Err(error),
+            // Also the messages of the dialect that aren't used
+            Err(mavlink::error::MessageReadError::Parse(_)) => continue,
+        };
+        let lost = message.is_err();
+        if messages.send((index, message)).is_err() || lost {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_radio_as_radio_status() {
+        let radio = mavlink::ardupilotmega::MavMessage::RADIO(mavlink::ardupilotmega::RADIO_DATA {
+            rxerrors: 1,
+            fixed: 2,
+            rssi: 3,
+            remrssi: 4,
+            txbuf: 5,
+            noise: 6,
+            remnoise: 7,
+        });
+        let Some(mavlink::common::MavMessage::RADIO_STATUS(status)) = common_message(radio) else {
+            panic!("RADIO was not taken as RADIO_STATUS");
+        };
+        assert_eq!(
+            (status.rxerrors, status.fixed, status.rssi, status.remrssi),
+            (1, 2, 3, 4)
+        );
+        assert_eq!((status.txbuf, status.noise, status.remnoise), (5, 6, 7));
+
+        let heartbeat = mavlink::ardupilotmega::MavMessage::from(crate::heartbeat_message());
+        assert!(matches!(
+            common_message(heartbeat),
+            Some(mavlink::common::MavMessage::HEARTBEAT(_))
+        ));
+        let ahrs = mavlink::ardupilotmega::MavMessage::AHRS(Default::default());
+        assert!(common_message(ahrs).is_none());
+    }
+
+    #[test]
+    fn carries_the_commands_of_the_dialect() {
+        use mavlink::Message as _;
+
+        let command = Message::Command(
+            mavlink::ardupilotmega::MavCmd::MAV_CMD_SCRIPTING,
+            mavlink::common::COMMAND_LONG_DATA {
+                param1: 3.0,
+                target_system: 1,
+                target_component: 1,
+                ..Default::default()
+            },
+        );
+        let payload = command.ser();
+        assert_eq!(command.message_id(), COMMAND_LONG_ID);
+        assert_eq!(payload[..4], 3.0f32.to_le_bytes());
+        assert_eq!(payload[28..32], [0xcd, 0xa6, 1, 1]);
+
+        let ack = Message::CommandAck(
+            mavlink::ardupilotmega::MavCmd::MAV_CMD_SCRIPTING,
+            mavlink::common::MavResult::MAV_RESULT_DENIED,
+        );
+        let version = mavlink::MavlinkVersion::V2;
+        // Over MAVLink 2 the zeros at the end of the payload are cut
+        let payload = ack.ser();
+        let payload = &payload[..3];
+        assert_eq!(
+            Message::parse(version, COMMAND_ACK_ID, payload).unwrap(),
+            ack
+        );
+
+        let heartbeat = crate::heartbeat_message();
+        let payload = heartbeat.ser();
+        assert_eq!(
+            Message::parse(version, heartbeat.message_id(), &payload).unwrap(),
+            Message::Common(heartbeat)
+        );
+    }
+}
@@ -1,78 +1,654 @@
 mod mavftp;
 
+mod conformance;
+
 mod controller;
 use controller::*;
 
-use structopt::StructOpt;
+mod daemon;
+
+mod disk;
+
+mod fleet;
+
+mod grep;
+mod hexdump;
+mod hook;
+mod http;
+mod json;
+mod latency;
+
+mod link;
+use link::{Links, Message};
+
+mod metrics;
+mod notify;
+mod operations;
+use operations::Operations;
+mod params;
 
 mod cli;
+mod cloud;
 use cli::*;
 
-use std::{sync::Arc, thread, time::Duration};
+mod radio;
+use radio::*;
 
-fn main() {
-    let target_system = 1;
-    let target_component = 0;
+mod queue;
 
-    let mut header = mavlink::MavHeader::default();
-    header.system_id = 1;
-    header.component_id = 0;
+mod receipt;
 
-    let args = cli::Opt::from_args();
+mod reboot;
+mod sidecar;
+use reboot::*;
 
-    let url = args.connection;
+mod script;
 
-    let mut vehicle = mavlink::connect(&url).unwrap();
-    vehicle.set_protocol_version(mavlink::MavlinkVersion::V2);
+mod sha256;
 
-    let receiver = Arc::new(vehicle);
-    let sender = receiver.clone();
+mod statustext;
+use statustext::*;
 
+mod template;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+use std::{
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+fn main() {
+    let (args, queued) = cli::parse_args();
+    let max_duration = args.max_duration;
+    let deadline = max_duration.and_then(|max_duration| Instant::now().checked_add(max_duration));
+    // Nothing to do with the vehicle, only printed from the options
+    if cli::generate(&args.command) {
+        return;
+    }
+    if fleet::vehicle().is_none() {
+        let targets = match (args.targets, &args.targets_file) {
+            (Some(targets), _) => Some(targets.0),
+            (None, Some(path)) => match fleet::read_targets_file(path) {
+                Ok(targets) => Some(targets),
+                Err(error) => {
+                    println!("targets failed: {}", error);
+                    exit(1);
+                }
+            },
+            (None, None) => None,
+        };
+        if let Some(targets) = targets {
+            fleet::run(&targets, args.parallel);
+        }
+    }
+    // The passes connect on their own
+    if let MavlinkFTPCommand::Daemon {
+        sync,
+        every,
+        compare_crc,
+        state,
+    } = args.command
+    {
+        daemon::run(daemon::SyncOptions {
+            remote: sync[0].clone(),
+            local: sync[1].clone().into(),
+            every,
+            compare_crc,
+            state,
+        });
+    }
+    let target_system = args.target_system;
+    let target_component = args.target_component;
+    let target_network = args.target_network;
+
+    let header = mavlink::MavHeader {
+        system_id: args.source_system,
+        component_id: args.source_component,
+        ..Default::default()
+    };
+
+    let urls = match args.links.is_empty() {
+        true => vec![args.connection],
+        false => args.links,
+    };
+    let Some(mut links) = Links::connect(&urls) else {
+        exit(1);
+    };
+
+    // Every link gets the heartbeats, so the vehicle keeps talking on the ones not in use
     thread::spawn({
-        let vehicle = sender.clone();
+        let connections = links.connections();
         move || loop {
-            let res = vehicle.send_default(&heartbeat_message());
-            if res.is_ok() {
+            let mut sent = false;
+            for connection in &connections {
+                sent |= link::send(connection, &header, &heartbeat_message().into()).is_ok();
+            }
+            if sent {
                 thread::sleep(Duration::from_secs(1));
             }
             thread::sleep(Duration::from_secs(1));
         }
     });
 
-    let mut controller = Controller::new();
-    match args.command {
-        MavlinkFTPCommand::ListDirectory { path } => controller.list_directory(path),
-        MavlinkFTPCommand::ReadFile { path } => controller.read_file(path),
-        MavlinkFTPCommand::Reset => controller.reset(),
-        MavlinkFTPCommand::CalcFileCRC32 { path } => controller.crc(path),
-        _ => panic!("Unsupported command!"),
+    let receipt = fleet::receipt(args.receipt);
+    let size_units = match (args.si, args.binary, args.bytes) {
+        (true, _, _) => SizeUnits::Si,
+        (_, false, true) => SizeUnits::Bytes,
+        _ => SizeUnits::Binary,
+    };
+    let color = !args.no_color && std::env::var_os("NO_COLOR").is_none();
+    let mut operations = Operations::new(
+        args.concurrent,
+        Box::new(move || {
+            let mut controller = Controller::new(target_system, target_component);
+            controller.set_target_network(target_network);
+            controller.set_chunk_size(args.chunk_size);
+            controller.set_receipt(receipt.clone());
+            controller.set_exec_after(args.exec_after.clone());
+            controller.set_verify(args.verify);
+            controller.set_write_checksums(args.write_checksums);
+            controller.set_sha256(args.sha256);
+            controller.set_size_units(size_units);
+            controller.set_color(color);
+            controller
+        }),
+    );
+    if args.latency_stats {
+        latency::enable();
+    }
+    if let Some(path) = &args.metrics_csv {
+        if let Err(error) = metrics::enable(path) {
+            println!("metrics failed: {}: {}", path.display(), error);
+            exit(1);
+        }
+    }
+    if let Some(url) = args.notify_url {
+        notify::enable(url, target_system);
+    }
+    if let Some(target) = args.upload_to {
+        if let Err(error) = cloud::enable(target, args.remove_uploaded, args.allow_http_upload) {
+            println!("upload failed: {}", error);
+            exit(1);
+        }
     }
+    // A script gives its commands one at a time, once the one before is done
+    let mut queued = std::iter::once(args.command)
+        .chain(queued)
+        .flat_map(|command| -> Box<dyn Iterator<Item = _>> {
+            match command {
+                MavlinkFTPCommand::Script { path } => Box::new(script::start(path)),
+                command => Box::new(std::iter::once((command, None))),
+            }
+        })
+        .peekable();
+    start_next(&mut operations, &mut queued);
+
+    let mut reboot_detector = RebootDetector::new(target_system);
+    let mut status_text_printer = StatusTextPrinter::new(target_system);
+    let mut radio_monitor = RadioMonitor::new(!args.no_flow_control);
+
+    // Messages arrive through a channel, so waiting for them can be bounded by the controller deadlines
+    let (messages, incoming) = mpsc::channel();
+    links.spawn_receivers(messages);
+
+    // Nothing is sent to the vehicle before its first heartbeat arrives
+    let connect_timeout = Duration::from_secs(args.connect_timeout);
+    let connect_deadline = Instant::now() + connect_timeout;
+    let mut connected = false;
+    // Other vehicles heard while waiting, a hint when the target is wrong
+    let mut heard = std::collections::BTreeSet::new();
 
     loop {
-        while let Ok((_header, message)) = receiver.recv() {
-            if let Some(payload) = controller.run() {
-                sender
-                    .send(
-                        &header,
-                        &mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(
-                            mavlink::common::FILE_TRANSFER_PROTOCOL_DATA {
-                                target_network: 0,
-                                target_system,
-                                target_component,
-                                payload: payload.to_bytes(),
-                            },
-                        ),
-                    )
-                    .expect("Failed to send message");
+        if let (Some(deadline), Some(max_duration)) = (deadline, max_duration) {
+            if Instant::now() >= deadline {
+                let messages = operations.abort();
+                send_all(&mut links, &mut operations, &header, messages);
+                println!(
+                    "stopped after the maximum duration of {} s",
+                    max_duration.as_secs()
+                );
+                exit(1);
+            }
+        }
+
+        let timeout = match connected {
+            true => operations.timeout(),
+            false if connect_timeout.is_zero() => None,
+            false => Some(connect_deadline.saturating_duration_since(Instant::now())),
+        };
+        let timeout = match (timeout, deadline) {
+            (timeout, None) => timeout,
+            (None, Some(deadline)) => Some(deadline.saturating_duration_since(Instant::now())),
+            (Some(timeout), Some(deadline)) => {
+                Some(timeout.min(deadline.saturating_duration_since(Instant::now())))
+            }
+        };
+        let message = match timeout {
+            Some(timeout) => match incoming.recv_timeout(timeout) {
+                Ok(message) => Some(message),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            },
+            None => match incoming.recv() {
+                Ok(message) => Some(message),
+                Err(mpsc::RecvError) => break,
+            },
+        };
+
+        let (link, (message_header, message)) = match message {
+            Some((link, Ok(message))) => (link, message),
+            Some((link, Err(error))) => {
+                lose_link(&mut links, &mut operations, &header, link, &error);
+                continue;
+            }
+            // A deadline passed without any message
+            None if !connected => {
+                println!(
+                    "no heartbeat from system {} component {} in {} s, is the vehicle connected?",
+                    target_system,
+                    target_component,
+                    connect_timeout.as_secs()
+                );
+                for (system, component) in &heard {
+                    println!(
+                        "heard system {} component {}, use --target-system {} --target-component {} for it",
+                        system, component, system, component
+                    );
+                }
+                exit(1);
+            }
+            None => {
+                if let Some(line) = links.check() {
+                    operations.println(&line);
+                    let messages = operations.link_changed();
+                    send_all(&mut links, &mut operations, &header, messages);
+                }
+                let messages = operations.run();
+                send_all(&mut links, &mut operations, &header, messages);
+                if start_next(&mut operations, &mut queued) {
+                    let messages = operations.run();
+                    send_all(&mut links, &mut operations, &header, messages);
+                }
+                continue;
             }
+        };
 
-            if let mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(msg) = message {
-                if let Some(msg) = controller.parse_mavlink_message(&msg) {
-                    sender.send(&header, &msg).expect("Failed to send message");
+        // The acks of the commands of the dialect only matter to the operation waiting for one
+        let message = match message {
+            Message::Common(message) => message,
+            message => {
+                let addressed = message_header.system_id == target_system
+                    && message_header.component_id == target_component;
+                if connected && links.is_active(link) && addressed {
+                    let messages = operations.parse_mavlink_message(&message);
+                    send_all(&mut links, &mut operations, &header, messages);
                 }
+                continue;
             }
+        };
+
+        if !connected {
+            connected = message_header.system_id == target_system
+                && message_header.component_id == target_component
+                && matches!(message, mavlink::common::MavMessage::HEARTBEAT(_));
+            if !connected {
+                if let mavlink::common::MavMessage::HEARTBEAT(_) = message {
+                    heard.insert((message_header.system_id, message_header.component_id));
+                }
+                continue;
+            }
+            links.select(link);
+        }
+
+        if message_header.system_id == target_system {
+            links.heard(link);
+        }
+
+        // Heard on any link the vehicle is still there, a quiet link alone isn't a reboot
+        if reboot_detector.check(&message_header, &message) {
+            operations.vehicle_rebooted();
         }
+
+        // The same messages arrive on the other links, only the active one is listened to
+        if !links.is_active(link) {
+            if let Some(line) = links.check() {
+                operations.println(&line);
+                let messages = operations.link_changed();
+                send_all(&mut links, &mut operations, &header, messages);
+            }
+            continue;
+        }
+
+        if let Some(line) = status_text_printer.format(&message_header, &message) {
+            operations.println(&line);
+        }
+
+        if let Some(line) = radio_monitor.check(&message) {
+            operations.println(&line);
+        }
+
+        let addressed = is_addressed_to_us(
+            &header,
+            target_network,
+            target_system,
+            target_component,
+            &message_header,
+            &message,
+        );
+        if addressed {
+            if let mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(_) = message {
+                latency::reply_received();
+            }
+        }
+
+        let messages = operations.run();
+        send_all(&mut links, &mut operations, &header, messages);
+
+        if addressed {
+            let messages = operations.parse_mavlink_message(&Message::Common(message));
+            send_all(&mut links, &mut operations, &header, messages);
+        }
+
+        // The next command goes out right away, without waiting for another message
+        if start_next(&mut operations, &mut queued) {
+            let messages = operations.run();
+            send_all(&mut links, &mut operations, &header, messages);
+        }
+    }
+    exit(1);
+}
+
+fn start(controller: &mut Controller, command: MavlinkFTPCommand) {
+    let (operation, path) = command.operation();
+    metrics::start(operation, path);
+    match command {
+        MavlinkFTPCommand::ListDirectory {
+            path,
+            full_path,
+            json,
+            stream,
+            format,
+        } => controller.list_directory(
+            path,
+            ListOptions {
+                full_path,
+                json: json || matches!(format, Some(ListFormat::Json | ListFormat::Ndjson)),
+                stream: stream || matches!(format, Some(ListFormat::Ndjson)),
+                template: match format {
+                    Some(ListFormat::Template(template)) => Some(template),
+                    _ => None,
+                },
+            },
+        ),
+        MavlinkFTPCommand::ReadFile {
+            mut paths,
+            output_dir,
+            mmap,
+            fsync_every,
+            force,
+            recursive,
+            continue_tree,
+            skip_existing,
+            compare_crc,
+        } => {
+            controller.set_force(force);
+            let options = ReadOptions {
+                output_dir: fleet::output_dir(output_dir),
+                mmap,
+                fsync_every,
+                skip_existing,
+                compare_crc,
+            };
+            // Split into one read per tree when the arguments are parsed
+            match (recursive, continue_tree) {
+                (_, true) => controller.continue_read(paths, options, recursive),
+                (true, false) => controller.read_tree(paths.remove(0), options),
+                (false, false) => controller.read_files(paths, options),
+            }
+        }
+        MavlinkFTPCommand::Reset => controller.reset(),
+        MavlinkFTPCommand::CalcFileCRC32 { path } => controller.crc(path),
+        MavlinkFTPCommand::Info => controller.info(),
+        MavlinkFTPCommand::Doctor => controller.doctor(),
+        MavlinkFTPCommand::Stat { path, json } => controller.stat(path, json),
+        MavlinkFTPCommand::WaitFor {
+            path,
+            timeout,
+            min_size,
+        } => controller.wait_for(path, timeout, min_size.unwrap_or_default()),
+        MavlinkFTPCommand::Test {
+            path,
+            file,
+            directory,
+        } => {
+            let test = match (file, directory) {
+                (true, _) => PathTest::File,
+                (_, true) => PathTest::Directory,
+                _ => PathTest::Exists,
+            };
+            controller.test(path, test)
+        }
+        MavlinkFTPCommand::Conformance { path } => controller.conformance(path),
+        command @ (MavlinkFTPCommand::Completions { .. } | MavlinkFTPCommand::Manpage) => {
+            cli::generate(&command);
+            exit(0);
+        }
+        MavlinkFTPCommand::WriteFile {
+            mut paths,
+            force,
+            reboot,
+            yes,
+            restart_scripts,
+        } => {
+            controller.set_force(force);
+            controller.set_after_upload(match (reboot, restart_scripts) {
+                (true, _) => Some(AfterUpload::Reboot(yes)),
+                (false, true) => Some(AfterUpload::RestartScripts),
+                (false, false) => None,
+            });
+            let destination = paths.pop().unwrap();
+            controller.upload(paths.into_iter().map(Into::into).collect(), destination)
+        }
+        MavlinkFTPCommand::RemoveFile {
+            path,
+            recursive,
+            yes,
+        } => controller.remove(path, recursive, yes),
+        MavlinkFTPCommand::RemoveDirectory { path, yes } => controller.remove_directory(path, yes),
+        MavlinkFTPCommand::CreateDirectory { path, parents } => {
+            controller.create_directory(path, parents)
+        }
+        MavlinkFTPCommand::Copy {
+            source,
+            destination,
+        } => controller.copy(source, destination),
+        MavlinkFTPCommand::Grep { pattern, path } => controller.grep(pattern, path),
+        MavlinkFTPCommand::Hexdump {
+            path,
+            offset,
+            length,
+        } => controller.hexdump(path, offset, length),
+        MavlinkFTPCommand::Logs(LogsCommand::Get {
+            output_dir,
+            force,
+            directory,
+            log,
+        }) => {
+            controller.set_force(force);
+            let options = ReadOptions {
+                output_dir: fleet::output_dir(output_dir),
+                ..Default::default()
+            };
+            let number = match log {
+                LogChoice::Latest => None,
+                LogChoice::Number(number) => Some(number),
+            };
+            controller.get_log(directory, number, options);
+        }
+        MavlinkFTPCommand::Params(ParamsCommand::Diff { file }) => {
+            match params::read_param_file(&file) {
+                Ok(saved) => controller.params_diff(saved),
+                Err(error) => {
+                    println!("params failed: {}: {}", file.display(), error);
+                    exit(1);
+                }
+            }
+        }
+        MavlinkFTPCommand::Raw {
+            opcode,
+            session,
+            offset,
+            size,
+            data,
+            data_hex,
+        } => {
+            let data = match (data, data_hex) {
+                (Some(text), _) => text.into_bytes(),
+                (None, Some(bytes)) => bytes.0,
+                (None, None) => Vec::new(),
+            };
+            controller.raw(opcode, session, offset, size, data)
+        }
+        // Daemon runs before the connection and a script is split in its commands, create has no
+        // implementation
+        command @ (MavlinkFTPCommand::CreateFile { .. }
+        | MavlinkFTPCommand::Daemon { .. }
+        | MavlinkFTPCommand::Script { .. }) => {
+            println!("{} failed: not supported here", command.operation().0);
+            exit(1);
+        }
+    }
+}
+
+// Starts the queued commands that are due, true if one of them has a request to send
+fn start_next(
+    operations: &mut Operations,
+    queued: &mut std::iter::Peekable<
+        impl Iterator<Item = (MavlinkFTPCommand, Option<script::Reply>)>,
+    >,
+) -> bool {
+    let mut started = false;
+    // In order, a command waiting for the session holds back the ones after it
+    loop {
+        operations.remove_finished();
+        // The script waits for its command before it gives the next one
+        if operations.script_waiting() {
+            break;
+        }
+        let Some((command, reply)) =
+            queued.next_if(|(command, _)| operations.has_room(command.needs_session()))
+        else {
+            break;
+        };
+        let controller = operations.add(command.needs_session());
+        controller.set_script_reply(reply);
+        start(controller, command);
+        started = true;
+    }
+    if operations.is_empty() {
+        exit(0);
+    }
+    started
+}
+
+fn send(
+    vehicle: &link::Connection,
+    header: &mavlink::MavHeader,
+    message: &Message,
+) -> std::io::Result<()> {
+    let ftp = matches!(
+        message,
+        Message::Common(mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(_))
+    );
+    if ftp {
+        radio::pace();
+    }
+    link::send(vehicle, header, message)?;
+    if ftp {
+        latency::request_sent();
+    }
+    Ok(())
+}
+
+// Once the active link fails to send, what is after it in the batch is dropped: the next link is
+// asked again for whatever is waiting for a reply
+fn send_all(
+    links: &mut Links,
+    operations: &mut Operations,
+    header: &mavlink::MavHeader,
+    messages: Vec<Message>,
+) {
+    for message in messages {
+        if let Err(error) = send(links.active(), header, &message) {
+            let link = links.active_link();
+            lose_link(links, operations, header, link, &error);
+            return;
+        }
+    }
+}
+
+// A link that broke, reading or sending, is left for the next one, the invocation fails when none
+// is left
+fn lose_link(
+    links: &mut Links,
+    operations: &mut Operations,
+    header: &mavlink::MavHeader,
+    link: usize,
+    error: &std::io::Error,
+) {
+    let active = links.is_active(link);
+    match links.lose(link, error) {
+        Some(line) => operations.println(&line),
+        None => {
+            operations.println(&format!("connection lost: {}", error));
+            exit(1);
+        }
+    }
+    if active {
+        let messages = operations.link_changed();
+        send_all(links, operations, header, messages);
+    }
+}
+
+// Other ground stations on a shared link have FTP conversations of their own,
+// only the replies of the selected vehicle to us can move the controller
+fn is_addressed_to_us(
+    own: &mavlink::MavHeader,
+    target_network: u8,
+    target_system: u8,
+    target_component: u8,
+    header: &mavlink::MavHeader,
+    message: &mavlink::common::MavMessage,
+) -> bool {
+    if header.system_id != target_system || header.component_id != target_component {
+        return false;
+    }
+    match message {
+        // Zero is a broadcast
+        mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(ftp) => {
+            (ftp.target_network == 0 || ftp.target_network == target_network)
+                && (ftp.target_system == 0 || ftp.target_system == own.system_id)
+                && (ftp.target_component == 0 || ftp.target_component == own.component_id)
+        }
+        _ => true,
+    }
+}
+
+// Every command finishes here, so the summaries are printed before leaving
+pub fn exit(code: i32) -> ! {
+    // A failed upload fails the invocation
+    let code = if cloud::finish() { code } else { code.max(1) };
+    latency::print();
+    metrics::finish(code);
+    notify::finish(code);
+    // A failed operation fails the test running it instead of the whole test binary
+    if cfg!(test) {
+        panic!("exited with code {}", code);
     }
+    std::process::exit(code)
 }
 
 pub fn heartbeat_message() -> mavlink::common::MavMessage {
@@ -1,6 +1,10 @@
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use strum_macros::{EnumIter, EnumString};
+use strum_macros::{Display, EnumIter, EnumString};
+
+// FILE_TRANSFER_PROTOCOL payload has 251 bytes, 12 of them are used by the header
+pub const MAX_DATA_SIZE: usize = 239;
+pub const HEADER_SIZE: usize = 12;
 
 #[derive(Debug, Copy, Clone, PartialEq, EnumIter, FromPrimitive)]
 pub enum MavlinkFtpOpcode {
@@ -24,7 +28,7 @@ pub enum MavlinkFtpOpcode {
     Nak = 129,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, EnumIter, EnumString, FromPrimitive)]
+#[derive(Debug, Copy, Clone, PartialEq, Display, EnumIter, EnumString, FromPrimitive)]
 pub enum MavlinkFtpNak {
     #[strum(serialize = "No error")]
     None = 0,
@@ -39,7 +43,7 @@ pub enum MavlinkFtpNak {
     #[strum(serialize = "All available sessions are already in use")]
     NoSessionsAvailable = 5,
     #[strum(serialize = "Offset past end of file for ListDirectory and ReadFile commands")]
-    EOF = 6,
+    Eof = 6,
     #[strum(serialize = "Unknown command / opcode")]
     UnknownCommand = 7,
     #[strum(serialize = "File/directory already exists")]
@@ -50,6 +54,7 @@ pub enum MavlinkFtpNak {
     FileNotFound = 10,
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum MavlinkFtpResponse {
     None,
@@ -78,38 +83,113 @@ pub enum MavlinkFtpResponse {
 #[derive(Debug)]
 pub struct EntryInfo {
     pub entry_type: EntryType,
+    // Invalid UTF-8 is replaced, the bytes sent by the vehicle are in raw_name
     pub name: String,
+    pub raw_name: Vec<u8>,
     pub size: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum EntryType {
     File,
     Directory,
     Skip,
 }
 
-pub fn parse_directory_entry(entry: &str) -> Result<EntryInfo, &'static str> {
-    let mut parts = entry.split('\t');
-    let temp_filename = parts.next().unwrap();
-    let file_type = temp_filename.chars().next();
-    let name: String = temp_filename.chars().skip(1).collect();
-    let size = parts.next().map(|s| s.parse().unwrap()).unwrap_or(0);
-
-    let entry_type = match file_type {
-        Some('F') => EntryType::File,
-        Some('D') => EntryType::Directory,
-        Some('S') => EntryType::Skip,
-        _ => return Err("Invalid entry type"),
+#[derive(Debug)]
+pub enum DirectoryEntryError {
+    InvalidType(String),
+    EmptyName(String),
+    MissingSize(String),
+    InvalidSize(String),
+}
+
+impl std::fmt::Display for DirectoryEntryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DirectoryEntryError::InvalidType(entry) => write!(f, "invalid entry type in {}", entry),
+            DirectoryEntryError::EmptyName(entry) => write!(f, "empty name in {}", entry),
+            DirectoryEntryError::MissingSize(entry) => write!(f, "no size in {}", entry),
+            DirectoryEntryError::InvalidSize(entry) => write!(f, "invalid size in {}", entry),
+        }
+    }
+}
+
+// Entries are "<type><name>" or "<type><name>\t<size>", the type being F, D or S; files always
+// have their size
+pub fn parse_directory_entry(entry: &[u8]) -> Result<EntryInfo, DirectoryEntryError> {
+    // Escaped, so odd bytes in the error are still readable
+    let quoted = || format!("{:?}", String::from_utf8_lossy(entry));
+
+    let entry_type = match entry.first() {
+        Some(b'F') => EntryType::File,
+        Some(b'D') => EntryType::Directory,
+        Some(b'S') => EntryType::Skip,
+        _ => return Err(DirectoryEntryError::InvalidType(quoted())),
+    };
+
+    let mut parts = entry[1..].splitn(2, |&byte| byte == b'\t');
+    let name = parts.next().unwrap_or_default();
+    if name.is_empty() {
+        return Err(DirectoryEntryError::EmptyName(quoted()));
+    }
+    let raw_name = name.to_vec();
+    let name = String::from_utf8_lossy(name).to_string();
+
+    let size = match parts.next() {
+        Some(size) => std::str::from_utf8(size)
+            .ok()
+            .and_then(|size| size.trim().parse().ok())
+            .ok_or_else(|| DirectoryEntryError::InvalidSize(quoted()))?,
+        None if matches!(entry_type, EntryType::File) => {
+            return Err(DirectoryEntryError::MissingSize(quoted()))
+        }
+        None => 0,
     };
 
     Ok(EntryInfo {
         entry_type,
         name,
+        raw_name,
         size,
     })
 }
 
+#[derive(Debug)]
+pub enum PayloadError {
+    TooShort(usize),
+    InvalidSize(usize),
+    InvalidOpcode(u8),
+    EmptyPath,
+    DataTooLong(usize),
+    InvalidOffset(u32),
+}
+
+impl std::fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PayloadError::TooShort(length) => {
+                write!(f, "{} bytes is shorter than the header", length)
+            }
+            PayloadError::InvalidSize(size) => {
+                write!(f, "data size {} does not fit in the payload", size)
+            }
+            PayloadError::InvalidOpcode(opcode) => write!(f, "unknown opcode {}", opcode),
+            PayloadError::EmptyPath => write!(f, "path is empty"),
+            PayloadError::DataTooLong(size) => {
+                write!(
+                    f,
+                    "{} bytes do not fit in {} data bytes",
+                    size, MAX_DATA_SIZE
+                )
+            }
+            PayloadError::InvalidOffset(offset) => write!(f, "offset {} is past 4 GiB", offset),
+        }
+    }
+}
+
+impl std::error::Error for PayloadError {}
+
 #[derive(Debug, Clone)]
 pub struct MavlinkFtpPayload {
     // Sequence number for message (0 - 65535)
@@ -191,12 +271,97 @@ impl MavlinkFtpPayload {
         }
     }
 
+    pub fn new_create_file(seq_number: u16, session: u8, path: &str) -> Self {
+        Self {
+            seq_number,
+            session,
+            opcode: MavlinkFtpOpcode::CreateFile,
+            size: path.len(),
+            req_opcode: MavlinkFtpOpcode::None,
+            burst_complete: 0,
+            padding: 0,
+            offset: 0,
+            data: path.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn new_open_file_wo(seq_number: u16, session: u8, path: &str) -> Self {
+        Self {
+            seq_number,
+            session,
+            opcode: MavlinkFtpOpcode::OpenFileWO,
+            size: path.len(),
+            req_opcode: MavlinkFtpOpcode::None,
+            burst_complete: 0,
+            padding: 0,
+            offset: 0,
+            data: path.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn new_create_directory(seq_number: u16, session: u8, path: &str) -> Self {
+        Self {
+            seq_number,
+            session,
+            opcode: MavlinkFtpOpcode::CreateDirectory,
+            size: path.len(),
+            req_opcode: MavlinkFtpOpcode::None,
+            burst_complete: 0,
+            padding: 0,
+            offset: 0,
+            data: path.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn new_remove_file(seq_number: u16, session: u8, path: &str) -> Self {
+        Self {
+            seq_number,
+            session,
+            opcode: MavlinkFtpOpcode::RemoveFile,
+            size: path.len(),
+            req_opcode: MavlinkFtpOpcode::None,
+            burst_complete: 0,
+            padding: 0,
+            offset: 0,
+            data: path.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn new_remove_directory(seq_number: u16, session: u8, path: &str) -> Self {
+        Self {
+            seq_number,
+            session,
+            opcode: MavlinkFtpOpcode::RemoveDirectory,
+            size: path.len(),
+            req_opcode: MavlinkFtpOpcode::None,
+            burst_complete: 0,
+            padding: 0,
+            offset: 0,
+            data: path.as_bytes().to_vec(),
+        }
+    }
+
+    // A single chunk, without the burst of replies that reads are made of
+    pub fn new_read_file_chunk(seq_number: u16, session: u8, offset: u32, size: usize) -> Self {
+        Self {
+            seq_number,
+            session,
+            opcode: MavlinkFtpOpcode::ReadFile,
+            size: size.clamp(0, MAX_DATA_SIZE),
+            req_opcode: MavlinkFtpOpcode::None,
+            burst_complete: 0,
+            padding: 0,
+            offset,
+            data: vec![],
+        }
+    }
+
     pub fn new_read_file(seq_number: u16, session: u8, offset: u32, size_left: usize) -> Self {
         Self {
             seq_number,
             session,
             opcode: MavlinkFtpOpcode::BurstReadFile,
-            size: size_left.clamp(0, 239), // 239 is the max size on the data field
+            size: size_left.clamp(0, MAX_DATA_SIZE),
             req_opcode: MavlinkFtpOpcode::None,
             burst_complete: 0,
             padding: 0,
@@ -205,6 +370,20 @@ impl MavlinkFtpPayload {
         }
     }
 
+    pub fn new_write_file(seq_number: u16, session: u8, offset: u32, data: &[u8]) -> Self {
+        Self {
+            seq_number,
+            session,
+            opcode: MavlinkFtpOpcode::WriteFile,
+            size: data.len(),
+            req_opcode: MavlinkFtpOpcode::None,
+            burst_complete: 0,
+            padding: 0,
+            offset,
+            data: data.to_vec(),
+        }
+    }
+
     pub fn new_calc_file_crc32(seq_number: u16, session: u8, path: &str) -> Self {
         Self {
             seq_number,
@@ -244,23 +423,83 @@ impl MavlinkFtpPayload {
         bytes
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<MavlinkFtpPayload, Box<dyn std::error::Error>> {
-        if bytes.len() < 12 {
-            return Err("Insufficient bytes in input array".into());
+    pub fn from_bytes(bytes: &[u8]) -> Result<MavlinkFtpPayload, PayloadError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(PayloadError::TooShort(bytes.len()));
+        }
+
+        let size = bytes[4] as usize;
+        if size > MAX_DATA_SIZE || HEADER_SIZE + size > bytes.len() {
+            return Err(PayloadError::InvalidSize(size));
         }
 
         Ok(MavlinkFtpPayload {
             seq_number: u16::from_le_bytes([bytes[0], bytes[1]]),
             session: bytes[2],
-            opcode: MavlinkFtpOpcode::from_u8(bytes[3]).ok_or("Invalid opcode")?,
-            size: bytes[4] as usize,
-            req_opcode: MavlinkFtpOpcode::from_u8(bytes[5]).ok_or("Invalid req_opcode")?,
+            opcode: MavlinkFtpOpcode::from_u8(bytes[3])
+                .ok_or(PayloadError::InvalidOpcode(bytes[3]))?,
+            size,
+            req_opcode: MavlinkFtpOpcode::from_u8(bytes[5])
+                .ok_or(PayloadError::InvalidOpcode(bytes[5]))?,
             burst_complete: bytes[6],
             padding: bytes[7],
             offset: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
-            data: bytes[12..12 + bytes[4] as usize].to_vec(),
+            data: bytes[HEADER_SIZE..HEADER_SIZE + size].to_vec(),
         })
     }
+
+    // Checks a request before sending it, instead of waiting for a NAK from the autopilot
+    pub fn validate(&self) -> Result<(), PayloadError> {
+        use MavlinkFtpOpcode::*;
+
+        // For reads the size is the number of bytes requested, not the data length
+        let read = matches!(self.opcode, ReadFile | BurstReadFile);
+        if self.data.len() > MAX_DATA_SIZE || self.size > MAX_DATA_SIZE {
+            return Err(PayloadError::DataTooLong(self.data.len().max(self.size)));
+        }
+        if !read && self.size != self.data.len() {
+            return Err(PayloadError::InvalidSize(self.size));
+        }
+
+        let has_path = matches!(
+            self.opcode,
+            ListDirectory
+                | OpenFileRO
+                | CreateFile
+                | OpenFileWO
+                | RemoveFile
+                | CreateDirectory
+                | RemoveDirectory
+                | TruncateFile
+                | Rename
+                | CalcFileCRC32
+        );
+        if has_path && self.data.is_empty() {
+            return Err(PayloadError::EmptyPath);
+        }
+
+        if self.opcode == WriteFile && self.offset.checked_add(self.size as u32).is_none() {
+            return Err(PayloadError::InvalidOffset(self.offset));
+        }
+
+        Ok(())
+    }
+
+    // File size of OpenFileRO and CRC of CalcFileCRC32 responses
+    pub fn data_as_u32(&self) -> Option<u32> {
+        let bytes = self.data.get(..4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // NAK code of a Nak response
+    pub fn nak(&self) -> Option<MavlinkFtpNak> {
+        if self.opcode != MavlinkFtpOpcode::Nak {
+            return None;
+        }
+        self.data
+            .first()
+            .and_then(|&code| MavlinkFtpNak::from_u8(code))
+    }
 }
 
 const CRC32_TABLE: [u32; 256] = [
@@ -299,7 +538,11 @@ const CRC32_TABLE: [u32; 256] = [
 ];
 
 pub fn mavlink_crc32(buffer: &[u8]) -> u32 {
-    let mut crc = 0;
+    mavlink_crc32_update(0, buffer)
+}
+
+// Continues the CRC32 of the bytes before the buffer, for data that arrives in chunks
+pub fn mavlink_crc32_update(mut crc: u32, buffer: &[u8]) -> u32 {
     for &b in buffer {
         let index = ((crc ^ (b as u32)) & 0xff) as usize;
         let table_value = CRC32_TABLE[index];
@@ -308,3 +551,77 @@ pub fn mavlink_crc32(buffer: &[u8]) -> u32 {
 
     crc
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_the_size_of_requests() {
+        let mut read = MavlinkFtpPayload::new_read_file(1, 0, 0, 100);
+        assert!(read.validate().is_ok());
+        read.opcode = MavlinkFtpOpcode::BurstReadFile;
+        assert!(read.validate().is_ok());
+
+        let mut open = MavlinkFtpPayload::new_open_file(1, 0, "/APM/LOGS/00000001.BIN");
+        assert!(open.validate().is_ok());
+        open.size += 1;
+        assert!(matches!(
+            open.validate(),
+            Err(PayloadError::InvalidSize(size)) if size == open.data.len() + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_payloads() {
+        let bytes = MavlinkFtpPayload::new_open_file(1, 0, "/APM/LOGS/00000001.BIN").to_bytes();
+        assert!(MavlinkFtpPayload::from_bytes(&bytes).is_ok());
+        assert!(matches!(
+            MavlinkFtpPayload::from_bytes(&bytes[..HEADER_SIZE - 1]),
+            Err(PayloadError::TooShort(length)) if length == HEADER_SIZE - 1
+        ));
+
+        let mut payload = vec![0; HEADER_SIZE + 255];
+        payload[3] = MavlinkFtpOpcode::Ack as u8;
+        payload[4] = MAX_DATA_SIZE as u8 + 1;
+        assert!(matches!(
+            MavlinkFtpPayload::from_bytes(&payload),
+            Err(PayloadError::InvalidSize(size)) if size == MAX_DATA_SIZE + 1
+        ));
+        // The size says more data than the payload holds
+        payload[4] = 10;
+        assert!(matches!(
+            MavlinkFtpPayload::from_bytes(&payload[..HEADER_SIZE + 9]),
+            Err(PayloadError::InvalidSize(10))
+        ));
+        assert!(MavlinkFtpPayload::from_bytes(&payload[..HEADER_SIZE + 10]).is_ok());
+    }
+
+    #[test]
+    fn rejects_malformed_directory_entries() {
+        let entry = parse_directory_entry(b"F00000001.BIN\t1024").unwrap();
+        assert_eq!((entry.name.as_str(), entry.size), ("00000001.BIN", 1024));
+        assert_eq!(parse_directory_entry(b"DLOGS").unwrap().size, 0);
+
+        assert!(matches!(
+            parse_directory_entry(b"F00000001.BIN"),
+            Err(DirectoryEntryError::MissingSize(_))
+        ));
+        assert!(matches!(
+            parse_directory_entry(b"F00000001.BIN\tbig"),
+            Err(DirectoryEntryError::InvalidSize(_))
+        ));
+        assert!(matches!(
+            parse_directory_entry(b"X00000001.BIN\t1024"),
+            Err(DirectoryEntryError::InvalidType(_))
+        ));
+        assert!(matches!(
+            parse_directory_entry(b""),
+            Err(DirectoryEntryError::InvalidType(_))
+        ));
+        assert!(matches!(
+            parse_directory_entry(b"F\t1024"),
+            Err(DirectoryEntryError::EmptyName(_))
+        ));
+    }
+}
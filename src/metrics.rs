@@ -0,0 +1,147 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const HEADER: &str = "timestamp,op,path,bytes,duration,retries,result\n";
+
+// The operation that is running, written as a row once it is done
+struct Operation {
+    started: SystemTime,
+    start: Instant,
+    op: &'static str,
+    path: String,
+    bytes: u64,
+    retries: u32,
+}
+
+struct Metrics {
+    path: PathBuf,
+    operation: Option<Operation>,
+}
+
+static METRICS: Mutex<Option<Metrics>> = Mutex::new(None);
+
+// Rows are appended, the header only goes into a new file
+pub fn enable(path: &Path) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    if file.metadata()?.len() == 0 {
+        file.write_all(HEADER.as_bytes())?;
+    }
+    *METRICS.lock().unwrap() = Some(Metrics {
+        path: path.to_path_buf(),
+        operation: None,
+    });
+    Ok(())
+}
+
+pub fn start(op: &'static str, path: &str) {
+    if let Some(metrics) = METRICS.lock().unwrap().as_mut() {
+        metrics.operation = Some(Operation {
+            started: SystemTime::now(),
+            start: Instant::now(),
+            op,
+            path: path.to_string(),
+            bytes: 0,
+            retries: 0,
+        });
+    }
+}
+
+// Bytes moved by the operation, over all of its files
+pub fn transferred(bytes: u64) {
+    update(|operation| operation.bytes += bytes);
+}
+
+pub fn retry() {
+    update(|operation| operation.retries += 1);
+}
+
+fn update(change: impl FnOnce(&mut Operation)) {
+    if let Some(Metrics {
+        operation: Some(operation),
+        ..
+    }) = METRICS.lock().unwrap().as_mut()
+    {
+        change(operation);
+    }
+}
+
+pub fn finish(code: i32) {
+    let mut metrics = METRICS.lock().unwrap();
+    let Some(metrics) = metrics.as_mut() else {
+        return;
+    };
+    let Some(operation) = metrics.operation.take() else {
+        return;
+    };
+    let result = match code {
+        0 => "ok".to_string(),
+        code => format!("failed with code {}", code),
+    };
+    let row = format!(
+        "{},{},{},{},{:.3},{},{}\n",
+        format_timestamp(operation.started),
+        operation.op,
+        escape(&operation.path),
+        operation.bytes,
+        operation.start.elapsed().as_secs_f64(),
+        operation.retries,
+        result
+    );
+    let written = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&metrics.path)
+        .and_then(|mut file| file.write_all(row.as_bytes()));
+    if let Err(error) = written {
+        eprintln!("metrics failed: {}: {}", metrics.path.display(), error);
+    }
+}
+
+// Quoted only when needed, with the quotes doubled
+fn escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// RFC 3339 in UTC, as 2024-05-01T12:30:00Z
+fn format_timestamp(time: SystemTime) -> String {
+    let ((year, month, day), seconds) = utc(time);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// The date in UTC, with the seconds since its start
+pub fn utc(time: SystemTime) -> ((i64, u32, u32), u64) {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+
+    // Days since 1970-01-01 to a date, counted in 400 year eras starting in March
+    let days = days as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    ((year, month as u32, day as u32), seconds)
+}
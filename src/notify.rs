@@ -0,0 +1,126 @@
+use std::sync::{mpsc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::http::{self, HttpUrl};
+use crate::json;
+use crate::receipt::ReceiptEntry;
+use crate::sha256;
+
+// Progress of a transfer is posted at most this often
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Events are posted from a thread of their own, so a slow endpoint doesn't hold the transfers
+struct Notifier {
+    events: mpsc::Sender<String>,
+    poster: JoinHandle<()>,
+    vehicle: u8,
+    last_progress: Option<Instant>,
+}
+
+static NOTIFIER: Mutex<Option<Notifier>> = Mutex::new(None);
+
+pub fn enable(url: HttpUrl, vehicle: u8) {
+    let (events, received) = mpsc::channel::<String>();
+    let poster = std::thread::spawn(move || {
+        let agent = http::agent(HTTP_TIMEOUT);
+        let mut failed = false;
+        for event in received {
+            // Only the first failure is told, the endpoint is likely down for the rest
+            if let Err(error) = post(&agent, &url, &event) {
+                if !failed {
+                    eprintln!("notify failed: {}", error);
+                }
+                failed = true;
+            }
+        }
+    });
+    *NOTIFIER.lock().unwrap() = Some(Notifier {
+        events,
+        poster,
+        vehicle,
+        last_progress: None,
+    });
+}
+
+fn send(event: &str, fields: &str) {
+    if let Some(notifier) = NOTIFIER.lock().unwrap().as_ref() {
+        let _ = notifier.events.send(format!(
+            "{{\"event\": \"{}\", \"vehicle\": {}{}}}",
+            event, notifier.vehicle, fields
+        ));
+    }
+}
+
+pub fn progress(operation: &str, path: &str, bytes: u64, total: u64) {
+    {
+        let mut notifier = NOTIFIER.lock().unwrap();
+        let Some(notifier) = notifier.as_mut() else {
+            return;
+        };
+        if bytes < total
+            && notifier
+                .last_progress
+                .is_some_and(|last| last.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        notifier.last_progress = Some(Instant::now());
+    }
+    send(
+        "progress",
+        &format!(
+            ", \"operation\": \"{}\", \"path\": \"{}\", \"bytes\": {}, \"total\": {}",
+            operation,
+            json::escape(path),
+            bytes,
+            total
+        ),
+    );
+}
+
+pub fn transferred(entry: &ReceiptEntry) {
+    let mut fields = format!(
+        ", \"path\": \"{}\", \"local_path\": \"{}\", \"size\": {}, \"crc32\": {}, \"result\": \"{}\"",
+        json::escape(&entry.remote_path),
+        json::escape(&entry.local_path.to_string_lossy()),
+        entry.size,
+        entry.local_crc,
+        entry.result()
+    );
+    if let Some(crc) = entry.remote_crc {
+        fields.push_str(&format!(", \"remote_crc32\": {}", crc));
+    }
+    if let Some(sha256) = &entry.sha256 {
+        fields.push_str(&format!(", \"sha256\": \"{}\"", sha256::hex(sha256)));
+    }
+    send("transferred", &fields);
+}
+
+pub fn uploaded(path: &str, destination: &str) {
+    send(
+        "uploaded",
+        &format!(
+            ", \"path\": \"{}\", \"destination\": \"{}\"",
+            json::escape(path),
+            json::escape(destination)
+        ),
+    );
+}
+
+// The last event, posted before the process exits
+pub fn finish(code: i32) {
+    send("exit", &format!(", \"code\": {}", code));
+    if let Some(notifier) = NOTIFIER.lock().unwrap().take() {
+        drop(notifier.events);
+        let _ = notifier.poster.join();
+    }
+}
+
+fn post(agent: &ureq::Agent, url: &HttpUrl, body: &str) -> Result<(), String> {
+    let request = agent
+        .post(&format!("{}{}", url.base(), url.path))
+        .set("Content-Type", "application/json");
+    http::check_response(request.send_string(body))
+}
@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::controller::{slot_of, Controller, MAX_SLOTS};
+use crate::link::Message;
+use crate::mavftp::MavlinkFtpOpcode;
+use crate::receipt::ReceiptEntry;
+
+// The operations running on the link, each with a controller of its own, keyed by the slot
+// tagged in the sequence numbers of its requests so the replies find their way back
+pub struct Operations {
+    slots: BTreeMap<u8, Controller>,
+    // The one that may open files
+    session_slot: Option<u8>,
+    max_slots: usize,
+    new_controller: Box<dyn Fn() -> Controller>,
+    // Every transfer of the invocation ends up in the same receipt, whichever operation did it
+    verified: Arc<Mutex<Vec<ReceiptEntry>>>,
+}
+
+impl Operations {
+    // One at a time unless concurrent, a failure of any of them still ends the invocation
+    pub fn new(concurrent: bool, new_controller: Box<dyn Fn() -> Controller>) -> Self {
+        Self {
+            slots: BTreeMap::new(),
+            session_slot: None,
+            max_slots: if concurrent { MAX_SLOTS } else { 1 },
+            new_controller,
+            verified: Arc::default(),
+        }
+    }
+
+    pub fn remove_finished(&mut self) {
+        self.slots
+            .retain(|_, controller| !controller.take_finished());
+        if self
+            .session_slot
+            .is_some_and(|slot| !self.slots.contains_key(&slot))
+        {
+            self.session_slot = None;
+        }
+    }
+
+    pub fn has_room(&self, needs_session: bool) -> bool {
+        self.slots.len() < self.max_slots && !(needs_session && self.session_slot.is_some())
+    }
+
+    pub fn script_waiting(&self) -> bool {
+        self.slots.values().any(Controller::script_waiting)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    // The controller of a new operation, in the first free slot
+    pub fn add(&mut self, needs_session: bool) -> &mut Controller {
+        let slot = (0..MAX_SLOTS as u8)
+            .find(|slot| !self.slots.contains_key(slot))
+            .expect("no free slot");
+        if needs_session {
+            self.session_slot = Some(slot);
+        }
+        let mut controller = (self.new_controller)();
+        controller.set_slot(slot);
+        controller.set_verified(self.verified.clone());
+        self.slots.entry(slot).or_insert(controller)
+    }
+
+    pub fn run(&mut self) -> Vec<Message> {
+        self.slots.values_mut().flat_map(Controller::run).collect()
+    }
+
+    // FTP replies go to the operation that sent the request, anything else to all of them
+    pub fn parse_mavlink_message(&mut self, message: &Message) -> Vec<Message> {
+        let Message::Common(mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(ftp)) = message
+        else {
+            return self
+                .slots
+                .values_mut()
+                .filter_map(|controller| controller.parse_mavlink_message(message))
+                .collect();
+        };
+        let Some(&[low, high]) = ftp.payload.get(..2) else {
+            return Vec::new();
+        };
+        let burst = ftp.payload.get(5) == Some(&(MavlinkFtpOpcode::BurstReadFile as u8));
+        let slot = self
+            .slots
+            .iter()
+            .find(|(_, controller)| burst && controller.bursting())
+            .map_or(slot_of(u16::from_le_bytes([low, high])), |(slot, _)| *slot);
+        let controller = self.slots.get_mut(&slot);
+        controller
+            .and_then(|controller| controller.parse_mavlink_message(message))
+            .into_iter()
+            .collect()
+    }
+
+    // The earliest deadline of them all
+    pub fn timeout(&self) -> Option<Duration> {
+        self.slots.values().filter_map(Controller::timeout).min()
+    }
+
+    pub fn println(&self, message: &str) {
+        match self.slots.values().next() {
+            Some(controller) => controller.println(message),
+            None => println!("{}", message),
+        }
+    }
+
+    pub fn vehicle_rebooted(&mut self) {
+        self.slots
+            .values_mut()
+            .for_each(Controller::vehicle_rebooted);
+    }
+
+    pub fn link_changed(&mut self) -> Vec<Message> {
+        self.slots
+            .values_mut()
+            .filter_map(Controller::link_changed)
+            .collect()
+    }
+
+    pub fn abort(&mut self) -> Vec<Message> {
+        self.slots
+            .values_mut()
+            .filter_map(Controller::abort)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{ListOptions, ReadOptions};
+    use crate::testing::{temp_dir, MockServer};
+
+    fn seq_number(message: &Message) -> u16 {
+        match message {
+            Message::Common(mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(ftp)) => {
+                u16::from_le_bytes([ftp.payload[0], ftp.payload[1]])
+            }
+            message => panic!("expected an FTP message, got {:?}", message),
+        }
+    }
+
+    fn set_seq_number(message: &mut Message, seq_number: u16) {
+        if let Message::Common(mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(ftp)) = message {
+            ftp.payload[..2].copy_from_slice(&seq_number.to_le_bytes());
+        }
+    }
+
+    // Until every operation is done, the requests of each round answered in the reverse order
+    fn serve(server: &mut MockServer, operations: &mut Operations, mut requests: Vec<Message>) {
+        while !operations.is_empty() {
+            assert!(!requests.is_empty(), "the operations stalled");
+            for request in std::mem::take(&mut requests).into_iter().rev() {
+                for reply in server.reply(request) {
+                    requests.extend(operations.parse_mavlink_message(&reply));
+                }
+            }
+            operations.remove_finished();
+            requests.extend(operations.run());
+        }
+    }
+
+    // A read of a file in the first slot and a listing in the second
+    fn read_and_list(output_dir: &std::path::Path) -> Operations {
+        let mut operations = Operations::new(true, Box::new(|| Controller::new(1, 1)));
+        operations.add(true).read_files(
+            vec!["/APM/LOGS/1.BIN".into()],
+            ReadOptions {
+                output_dir: Some(output_dir.to_path_buf()),
+                ..Default::default()
+            },
+        );
+        operations
+            .add(false)
+            .list_directory("/APM".into(), ListOptions::default());
+        operations
+    }
+
+    #[test]
+    fn gives_each_reply_to_the_operation_that_asked() {
+        let content: Vec<u8> = (0..3000).map(|index| index as u8).collect();
+        let mut server = MockServer::default()
+            .with_file("/APM/LOGS/1.BIN", &content)
+            .with_file("/APM/a.txt", b"a");
+        let output_dir = temp_dir("operations-slots");
+        let mut operations = read_and_list(&output_dir);
+
+        let requests = operations.run();
+        let slots: Vec<_> = requests
+            .iter()
+            .map(|request| slot_of(seq_number(request)))
+            .collect();
+        assert_eq!(slots, [0, 1]);
+        // Answered in the reverse order, each reply still finds its operation
+        serve(&mut server, &mut operations, requests);
+
+        assert_eq!(std::fs::read(output_dir.join("1.BIN")).unwrap(), content);
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    fn gives_the_burst_replies_to_the_operation_reading_one() {
+        let mut server = MockServer::default().with_file("/APM/LOGS/1.BIN", &[1; 3000]);
+        let output_dir = temp_dir("operations-burst");
+        let mut operations = read_and_list(&output_dir);
+
+        let requests = operations.run();
+        let mut burst = Vec::new();
+        for reply in server.reply(requests[0].clone()) {
+            burst.extend(operations.parse_mavlink_message(&reply));
+        }
+        burst.extend(operations.run());
+        let burst = burst.pop().unwrap();
+        assert!(operations.slots[&0].bursting());
+
+        // The vehicle numbers the replies of a burst on its own, they may carry any slot
+        let mut replies = server.reply(burst);
+        for reply in &mut replies {
+            set_seq_number(reply, seq_number(reply) | 1 << 14);
+        }
+        let mut next = Vec::new();
+        for reply in &replies {
+            next.extend(operations.parse_mavlink_message(reply));
+        }
+        assert_eq!(next.len(), 1);
+        assert_eq!(slot_of(seq_number(&next[0])), 0);
+        // The listing is still waiting for its own reply
+        operations.remove_finished();
+        assert_eq!(operations.slots.keys().collect::<Vec<_>>(), [&0, &1]);
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_every_chained_read_in_the_receipt() {
+        let mut server = MockServer::default()
+            .with_file("/APM/a.txt", b"a")
+            .with_file("/APM/b.txt", b"b");
+        let output_dir = temp_dir("operations-receipt");
+        let receipt = output_dir.join("receipt.tsv");
+        let path = receipt.clone();
+        let mut operations = Operations::new(
+            false,
+            Box::new(move || {
+                let mut controller = Controller::new(1, 1);
+                controller.set_receipt(Some(path.clone()));
+                controller
+            }),
+        );
+
+        // One command after the other, as read /APM/a.txt read /APM/b.txt
+        for remote in ["/APM/a.txt", "/APM/b.txt"] {
+            operations.add(true).read_files(
+                vec![remote.into()],
+                ReadOptions {
+                    output_dir: Some(output_dir.clone()),
+                    ..Default::default()
+                },
+            );
+            let requests = operations.run();
+            serve(&mut server, &mut operations, requests);
+        }
+
+        let receipt = std::fs::read_to_string(receipt).unwrap();
+        let entries: Vec<_> = receipt
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.split('\t').next().unwrap())
+            .collect();
+        assert_eq!(entries, ["/APM/a.txt", "/APM/b.txt"]);
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+}
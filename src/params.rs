@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+// ArduPilot packs every parameter in this file, so they are read in one transfer
+pub const PARAM_PATH: &str = "@PARAM/param.pck";
+
+// The second one also carries the default values, after the value of the parameters that have one
+const MAGIC: u16 = 0x671b;
+const MAGIC_WITH_DEFAULTS: u16 = 0x671c;
+
+const HEADER_SIZE: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamValue {
+    Integer(i32),
+    Float(f32),
+}
+
+impl ParamValue {
+    // Saved files round the values, a difference below the f32 precision of the vehicle isn't one
+    fn matches(self, saved: f64) -> bool {
+        match self {
+            ParamValue::Integer(value) => value as f64 == saved.round(),
+            ParamValue::Float(value) => {
+                let value = value as f64;
+                (value - saved).abs() <= 1e-6 * value.abs().max(saved.abs())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ParamValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParamValue::Integer(value) => write!(f, "{}", value),
+            ParamValue::Float(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+// A parameter of the saved file whose value on the vehicle is another, or that it doesn't have
+pub struct ParamDifference {
+    pub name: String,
+    pub vehicle: Option<ParamValue>,
+    // As written in the file
+    pub saved: String,
+}
+
+// Keeps @PARAM/param.pck as it arrives, to compare it with a saved file once complete
+pub struct ParamDiff {
+    saved: Vec<(String, String)>,
+    data: Vec<u8>,
+}
+
+impl ParamDiff {
+    pub fn new(saved: Vec<(String, String)>) -> Self {
+        Self {
+            saved,
+            data: Vec::new(),
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    // Only the parameters of the file are compared, it may hold a few of them only
+    pub fn finish(&self) -> Result<(usize, Vec<ParamDifference>), String> {
+        let vehicle: HashMap<String, ParamValue> = decode_pck(&self.data)?.into_iter().collect();
+        let differences = self
+            .saved
+            .iter()
+            .filter_map(|(name, saved)| {
+                let value = vehicle.get(name).copied();
+                // Checked when the file was read
+                let number = saved.parse().unwrap_or(f64::NAN);
+                match value {
+                    Some(value) if value.matches(number) => None,
+                    _ => Some(ParamDifference {
+                        name: name.clone(),
+                        vehicle: value,
+                        saved: saved.clone(),
+                    }),
+                }
+            })
+            .collect();
+        Ok((self.saved.len(), differences))
+    }
+}
+
+// Entries are a type byte, the type in the low bits and flags in the high ones, then a byte with
+// the length of the name shared with the previous entry and the length of the rest minus one,
+// the rest of the name and the value, little endian
+pub fn decode_pck(data: &[u8]) -> Result<Vec<(String, ParamValue)>, String> {
+    if data.len() < HEADER_SIZE {
+        return Err("too short for a header".into());
+    }
+    let magic = u16::from_le_bytes([data[0], data[1]]);
+    let count = u16::from_le_bytes([data[2], data[3]]) as usize;
+    let with_defaults = match magic {
+        MAGIC => false,
+        MAGIC_WITH_DEFAULTS => true,
+        _ => return Err(format!("unknown format 0x{:04x}", magic)),
+    };
+
+    let mut params = Vec::with_capacity(count);
+    let mut name = Vec::new();
+    let mut offset = HEADER_SIZE;
+    while offset < data.len() {
+        // Zeros pad the entries, so none of them crosses a block of the file
+        if data[offset] == 0 {
+            offset += 1;
+            continue;
+        }
+        let kind = data[offset];
+        let size = match kind & 0x0f {
+            1 => 1,
+            2 => 2,
+            3 | 4 => 4,
+            other => return Err(format!("unknown type {} at byte {}", other, offset)),
+        };
+        let lengths = *data
+            .get(offset + 1)
+            .ok_or_else(|| format!("truncated entry at byte {}", offset))?;
+        let common = (lengths & 0x0f) as usize;
+        let rest = (lengths >> 4) as usize + 1;
+        let default_size = match with_defaults && kind & 0x10 != 0 {
+            true => size,
+            false => 0,
+        };
+        let start = offset + 2;
+        let entry = data
+            .get(start..start + rest + size + default_size)
+            .ok_or_else(|| format!("truncated entry at byte {}", offset))?;
+        if common > name.len() {
+            return Err(format!(
+                "name at byte {} shares more than the last one",
+                offset
+            ));
+        }
+        name.truncate(common);
+        name.extend_from_slice(&entry[..rest]);
+
+        let value = &entry[rest..rest + size];
+        let value = match kind & 0x0f {
+            1 => ParamValue::Integer(value[0] as i8 as i32),
+            2 => ParamValue::Integer(i16::from_le_bytes([value[0], value[1]]) as i32),
+            3 => ParamValue::Integer(i32::from_le_bytes(value.try_into().unwrap())),
+            _ => ParamValue::Float(f32::from_le_bytes(value.try_into().unwrap())),
+        };
+        params.push((String::from_utf8_lossy(&name).into_owned(), value));
+        offset = start + entry.len();
+    }
+    if params.len() != count {
+        return Err(format!(
+            "{} parameters announced, {} found",
+            count,
+            params.len()
+        ));
+    }
+    Ok(params)
+}
+
+// Mission Planner and MAVProxy save NAME,VALUE or NAME VALUE lines, QGroundControl
+// SYSTEM COMPONENT NAME VALUE TYPE ones, comments start with #
+pub fn read_param_file(path: &Path) -> Result<Vec<(String, String)>, String> {
+    let content = std::fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let mut params = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let fields: Vec<&str> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|field| !field.is_empty())
+            .collect();
+        let (name, value) = match fields[..] {
+            [] => continue,
+            [name, value] | [_, _, name, value, _] => (name, value),
+            _ => return Err(format!("line {}: expected a name and a value", index + 1)),
+        };
+        if value.parse::<f64>().is_err() {
+            return Err(format!("line {}: invalid value {}", index + 1, value));
+        }
+        params.push((name.to_string(), value.to_string()));
+    }
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_param_pck() {
+        // BATT_CAPACITY, then BATT_MONITOR sharing "BATT_" with a default, padding in between
+        let mut data = vec![0x1c, 0x67, 2, 0, 2, 0];
+        data.extend([0x03, 0xc0]);
+        data.extend(b"BATT_CAPACITY");
+        data.extend(5200i32.to_le_bytes());
+        data.extend([0, 0]);
+        data.extend([0x11, 0x60 | 5]);
+        data.extend(b"MONITOR");
+        data.extend([4, 0]);
+
+        assert_eq!(
+            decode_pck(&data).unwrap(),
+            vec![
+                ("BATT_CAPACITY".to_string(), ParamValue::Integer(5200)),
+                ("BATT_MONITOR".to_string(), ParamValue::Integer(4)),
+            ]
+        );
+        assert!(decode_pck(&data[..data.len() - 1]).is_err());
+    }
+}
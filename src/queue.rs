@@ -0,0 +1,125 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// In the output directory, a single interrupted tree is kept there
+const QUEUE_NAME: &str = ".mavftp-queue";
+
+// The offset of the file being read is saved this often, at most this much is read again
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+// A file stopped in the middle
+pub struct Partial {
+    pub remote_path: String,
+    pub local_path: PathBuf,
+    pub size: u32,
+    pub offset: u32,
+}
+
+// What is left to read of a tree, in order
+pub struct Queue {
+    pub root: String,
+    pub current: Option<Partial>,
+    pub pending: Vec<(String, PathBuf)>,
+}
+
+// The queue file of a tree being read, removed once the tree is complete
+pub struct QueueFile {
+    pub path: PathBuf,
+    root: String,
+    saved: Option<Instant>,
+}
+
+impl QueueFile {
+    pub fn new(output_dir: Option<&Path>, root: &str) -> Self {
+        Self {
+            path: output_dir.unwrap_or(Path::new("")).join(QUEUE_NAME),
+            root: root.to_string(),
+            saved: None,
+        }
+    }
+
+    // True when the offset saved for the current file is old enough to be saved again, the queue
+    // is saved first once the tree is listed
+    pub fn due(&self) -> bool {
+        self.saved
+            .is_some_and(|saved| saved.elapsed() >= SAVE_INTERVAL)
+    }
+
+    // Written to a temporary file first, so an interruption while saving leaves the last queue
+    pub fn save<'a>(
+        &mut self,
+        current: Option<&Partial>,
+        pending: impl Iterator<Item = (&'a str, &'a Path)>,
+    ) -> std::io::Result<()> {
+        self.saved = Some(Instant::now());
+        let mut content = String::new();
+        content.push_str("# mavftp-cli queue of an interrupted read, continued by --continue\n");
+        content.push_str(&format!("root\t{}\n", self.root));
+        if let Some(current) = current {
+            content.push_str(&format!(
+                "current\t{}\t{}\t{}\t{}\n",
+                current.remote_path,
+                current.local_path.display(),
+                current.size,
+                current.offset
+            ));
+        }
+        for (remote_path, local_path) in pending {
+            content.push_str(&format!(
+                "pending\t{}\t{}\n",
+                remote_path,
+                local_path.display()
+            ));
+        }
+        let temporary = self.path.with_extension("tmp");
+        std::fs::write(&temporary, content)?;
+        std::fs::rename(&temporary, &self.path)
+    }
+
+    pub fn remove(&self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+
+    // None when there is nothing to continue
+    pub fn load(&self) -> Result<Option<Queue>, String> {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.to_string()),
+        };
+        let mut queue = Queue {
+            root: String::new(),
+            current: None,
+            pending: Vec::new(),
+        };
+        for (index, line) in content.lines().enumerate() {
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+            let invalid = || format!("line {}: invalid entry", index + 1);
+            let fields: Vec<&str> = line.split('\t').collect();
+            match fields[..] {
+                ["root", root] => queue.root = root.to_string(),
+                ["current", remote_path, local_path, size, offset] => {
+                    queue.current = Some(Partial {
+                        remote_path: remote_path.to_string(),
+                        local_path: local_path.into(),
+                        size: size.parse().map_err(|_| invalid())?,
+                        offset: offset.parse().map_err(|_| invalid())?,
+                    })
+                }
+                ["pending", remote_path, local_path] => queue
+                    .pending
+                    .push((remote_path.to_string(), local_path.into())),
+                _ => return Err(invalid()),
+            }
+        }
+        if queue.root != self.root {
+            return Err(format!(
+                "it holds the read of {}, not of {}",
+                queue.root, self.root
+            ));
+        }
+        Ok(Some(queue))
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+// A warning that still holds is not printed again before this period
+const REPEAT_PERIOD: Duration = Duration::from_secs(10);
+
+// Below this the radio is about to drop what it is given to send
+const LOW_TXBUF_PERCENT: u8 = 20;
+
+// Signal over noise, closer than this and packets start to get lost
+const LOW_FADE_MARGIN_DB: f32 = 10.0;
+
+// SiK radios report RSSI and noise in steps of about 1/1.9 dB
+const RSSI_STEPS_PER_DB: f32 = 1.9;
+
+// The longest a request waits for the radio to drain, well below the time its reply is waited for
+const MAX_SLOWDOWN: Duration = Duration::from_millis(300);
+
+// Time kept between two FTP requests, grown while the radio buffer fills up
+struct Pacing {
+    slowdown: Duration,
+    last_sent: Option<Instant>,
+}
+
+static PACING: Mutex<Pacing> = Mutex::new(Pacing {
+    slowdown: Duration::ZERO,
+    last_sent: None,
+});
+
+// RADIO_STATUS comes from the radio itself, so it is checked whatever system sent it, the RADIO
+// of older radios arrives as one
+pub struct RadioMonitor {
+    flow_control: bool,
+    last_warned: Option<SystemTime>,
+}
+
+impl RadioMonitor {
+    pub fn new(flow_control: bool) -> Self {
+        Self {
+            flow_control,
+            last_warned: None,
+        }
+    }
+
+    // Returns the line to be printed when the link looks saturated or weak, or when it recovers
+    pub fn check(&mut self, message: &mavlink::common::MavMessage) -> Option<String> {
+        let mavlink::common::MavMessage::RADIO_STATUS(status) = message else {
+            return None;
+        };
+
+        if self.flow_control {
+            adjust_slowdown(status.txbuf);
+        }
+
+        let problems = problems(status);
+        if problems.is_empty() {
+            return self
+                .last_warned
+                .take()
+                .map(|_| "[radio] link recovered".to_string());
+        }
+
+        let now = SystemTime::now();
+        if let Some(last) = self.last_warned {
+            if now.duration_since(last).unwrap_or_default() < REPEAT_PERIOD {
+                return None;
+            }
+        }
+        self.last_warned = Some(now);
+        Some(format!(
+            "[radio] {}, transfers may slow down or time out",
+            problems.join(", ")
+        ))
+    }
+}
+
+// The same steps ArduPilot takes for its streams: slow down fast while the buffer is nearly full,
+// speed up slowly once it has drained
+fn adjust_slowdown(txbuf: u8) {
+    let mut pacing = PACING.lock().unwrap();
+    let step = Duration::from_millis;
+    pacing.slowdown = match txbuf {
+        0..=19 => (pacing.slowdown + step(60)).min(MAX_SLOWDOWN),
+        20..=49 => (pacing.slowdown + step(20)).min(MAX_SLOWDOWN),
+        91..=95 => pacing.slowdown.saturating_sub(step(20)),
+        96.. => pacing.slowdown.saturating_sub(step(40)),
+        _ => pacing.slowdown,
+    };
+}
+
+// Called before each FTP request goes out, waits until it is due
+pub fn pace() {
+    let mut pacing = PACING.lock().unwrap();
+    if let Some(last_sent) = pacing.last_sent {
+        let wait = pacing.slowdown.saturating_sub(last_sent.elapsed());
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+    pacing.last_sent = Some(Instant::now());
+}
+
+fn problems(status: &mavlink::common::RADIO_STATUS_DATA) -> Vec<String> {
+    let mut problems = Vec::new();
+    if status.txbuf < LOW_TXBUF_PERCENT {
+        problems.push(format!("transmit buffer at {}%", status.txbuf));
+    }
+    for (side, rssi, noise) in [
+        ("local", status.rssi, status.noise),
+        ("remote", status.remrssi, status.remnoise),
+    ] {
+        // Zero and 255 are used by radios that don't measure it
+        if rssi == 0 || rssi == u8::MAX {
+            continue;
+        }
+        let margin = (rssi as f32 - noise as f32) / RSSI_STEPS_PER_DB;
+        if margin < LOW_FADE_MARGIN_DB {
+            problems.push(format!("{} fade margin of {:.0} dB", side, margin));
+        }
+    }
+    problems
+}
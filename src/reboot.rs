@@ -0,0 +1,77 @@
+use std::time::{Duration, SystemTime};
+
+// Heartbeats are sent at 1Hz, missing a few of them means that the vehicle went away
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Messages sent by the autopilots when they finish booting
+const BOOT_MESSAGES: [&str; 2] = ["ArduPilot Ready", "Initialising ArduPilot"];
+
+pub struct RebootDetector {
+    target_system: u8,
+    last_heartbeat: Option<SystemTime>,
+    last_boot_ms: Option<u32>,
+    // Redundant links bring the same boot messages more than once
+    last_reboot: Option<SystemTime>,
+}
+
+impl RebootDetector {
+    pub fn new(target_system: u8) -> Self {
+        Self {
+            target_system,
+            last_heartbeat: None,
+            last_boot_ms: None,
+            last_reboot: None,
+        }
+    }
+
+    // Returns true if the message shows that the vehicle rebooted since the last one
+    pub fn check(
+        &mut self,
+        header: &mavlink::MavHeader,
+        message: &mavlink::common::MavMessage,
+    ) -> bool {
+        if header.system_id != self.target_system {
+            return false;
+        }
+
+        let now = SystemTime::now();
+        let told = self
+            .last_reboot
+            .is_some_and(|last| now.duration_since(last).unwrap_or_default() < HEARTBEAT_TIMEOUT);
+        let rebooted = self.rebooted(message, now);
+        if rebooted && !told {
+            self.last_reboot = Some(now);
+        }
+        rebooted && !told
+    }
+
+    fn rebooted(&mut self, message: &mavlink::common::MavMessage, now: SystemTime) -> bool {
+        match message {
+            mavlink::common::MavMessage::HEARTBEAT(heartbeat) => {
+                if heartbeat.autopilot == mavlink::common::MavAutopilot::MAV_AUTOPILOT_INVALID {
+                    return false;
+                }
+
+                let lost = self.last_heartbeat.is_some_and(|last| {
+                    now.duration_since(last).unwrap_or_default() > HEARTBEAT_TIMEOUT
+                });
+                self.last_heartbeat = Some(now);
+                lost
+            }
+            mavlink::common::MavMessage::SYSTEM_TIME(time) => {
+                let rebooted = self
+                    .last_boot_ms
+                    .is_some_and(|last| time.time_boot_ms < last);
+                self.last_boot_ms = Some(time.time_boot_ms);
+                rebooted
+            }
+            mavlink::common::MavMessage::STATUSTEXT(status) => {
+                let text: String = status.text.iter().take_while(|&&c| c != '\0').collect();
+                BOOT_MESSAGES
+                    .iter()
+                    .any(|message| text.starts_with(message))
+            }
+            _ => false,
+        }
+    }
+}
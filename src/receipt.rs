@@ -0,0 +1,137 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::mavftp::mavlink_crc32;
+use crate::sha256;
+
+// A transferred file, with the CRC32 of both copies and optionally the SHA-256 of the local one
+#[derive(Clone)]
+pub struct ReceiptEntry {
+    pub remote_path: String,
+    pub local_path: PathBuf,
+    pub size: u64,
+    pub local_crc: u32,
+    // None when the vehicle could not calculate it
+    pub remote_crc: Option<u32>,
+    // Only asked for by --verify size
+    pub remote_size: Option<u64>,
+    // Of the local copy, with --sha256
+    pub sha256: Option<[u8; 32]>,
+    // Where --upload-to put it
+    pub uploaded: Option<String>,
+}
+
+// What is asked to the vehicle after a transfer, to know that both copies are the same
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerifyMode {
+    None,
+    Size,
+    Crc32,
+}
+
+impl std::str::FromStr for VerifyMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "none" => Ok(VerifyMode::None),
+            "size" => Ok(VerifyMode::Size),
+            "crc32" => Ok(VerifyMode::Crc32),
+            _ => Err(format!(
+                "unknown verification {}, use none, size or crc32",
+                value
+            )),
+        }
+    }
+}
+
+impl ReceiptEntry {
+    pub fn new(local_path: PathBuf, remote_path: String, sha256: bool) -> std::io::Result<Self> {
+        let content = std::fs::read(&local_path)?;
+        Ok(Self {
+            remote_path,
+            local_path,
+            size: content.len() as u64,
+            local_crc: mavlink_crc32(&content),
+            remote_crc: None,
+            remote_size: None,
+            sha256: sha256.then(|| sha256::digest(&content)),
+            uploaded: None,
+        })
+    }
+
+    // The CRC32 is already known, as for downloads that calculate it while the chunks are written
+    pub fn with_crc(local_path: PathBuf, remote_path: String, size: u64, local_crc: u32) -> Self {
+        Self {
+            remote_path,
+            local_path,
+            size,
+            local_crc,
+            remote_crc: None,
+            remote_size: None,
+            sha256: None,
+            uploaded: None,
+        }
+    }
+
+    pub fn result(&self) -> &'static str {
+        match (self.remote_crc, self.remote_size) {
+            (Some(crc), _) if crc == self.local_crc => "ok",
+            (Some(_), _) => "mismatch",
+            (None, Some(size)) if size == self.size => "size ok",
+            (None, Some(_)) => "size mismatch",
+            (None, None) => "unverified",
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        matches!(self.result(), "ok" | "size ok")
+    }
+}
+
+// The last line holds the CRC32 of everything before it, so edits to the receipt can be noticed
+pub fn write_receipt(
+    path: &Path,
+    target_system: u8,
+    target_component: u8,
+    entries: &[ReceiptEntry],
+) -> std::io::Result<()> {
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let mut content = String::new();
+    content.push_str("# mavftp-cli transfer receipt\n");
+    content.push_str(&format!(
+        "# created: {} (seconds since 1970-01-01 UTC)\n",
+        created
+    ));
+    content.push_str(&format!(
+        "# vehicle: system {}, component {}\n",
+        target_system, target_component
+    ));
+    content.push_str(
+        "# remote path\tlocal path\tsize\tlocal crc32\tremote crc32\tresult\tsha256\tuploaded to\n",
+    );
+    for entry in entries {
+        content.push_str(&format!(
+            "{}\t{}\t{}\t0x{:08x}\t{}\t{}\t{}\t{}\n",
+            entry.remote_path,
+            entry.local_path.display(),
+            entry.size,
+            entry.local_crc,
+            entry
+                .remote_crc
+                .map_or("-".to_string(), |crc| format!("0x{:08x}", crc)),
+            entry.result(),
+            entry.sha256.as_ref().map_or("-".to_string(), sha256::hex),
+            entry.uploaded.as_deref().unwrap_or("-")
+        ));
+    }
+    let crc = mavlink_crc32(content.as_bytes());
+    content.push_str(&format!("# receipt crc32: 0x{:08x}\n", crc));
+
+    std::fs::File::create(path)?.write_all(content.as_bytes())
+}
@@ -0,0 +1,195 @@
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc;
+
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map};
+use structopt::StructOpt;
+
+use crate::cli::MavlinkFTPCommand;
+use crate::mavftp::{EntryInfo, EntryType};
+
+// What a command of the script left once it's done
+pub struct Outcome {
+    pub entries: Vec<EntryInfo>,
+    pub crc: Option<u32>,
+}
+
+pub type Reply = mpsc::Sender<Outcome>;
+
+enum Call {
+    Command(MavlinkFTPCommand, Reply),
+    Done(i32),
+}
+
+// The commands of a running script, each with the channel its outcome goes back through
+pub struct Commands(mpsc::Receiver<Call>);
+
+impl Iterator for Commands {
+    type Item = (MavlinkFTPCommand, Option<Reply>);
+
+    // Waits for the script to call its next primitive
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.0.recv() {
+            Ok(Call::Command(command, reply)) => Some((command, Some(reply))),
+            Ok(Call::Done(0)) | Err(_) => None,
+            Ok(Call::Done(code)) => crate::exit(code),
+        }
+    }
+}
+
+// The script runs on a thread of its own, every primitive it calls is a command run over the
+// connection of the invocation, and it goes on once the command is done. A failed command ends
+// the invocation as in a chain of commands
+pub fn start(path: PathBuf) -> Commands {
+    let (calls, commands) = mpsc::channel();
+    std::thread::spawn(move || {
+        let code = match run(&path, calls.clone()) {
+            Ok(()) => 0,
+            Err(error) => {
+                println!("script failed: {}: {}", path.display(), error);
+                1
+            }
+        };
+        let _ = calls.send(Call::Done(code));
+    });
+    Commands(commands)
+}
+
+fn run(path: &Path, calls: mpsc::Sender<Call>) -> Result<(), Box<EvalAltResult>> {
+    // The words of the command as on the command line, so the primitives get its defaults
+    let call = Rc::new(
+        move |words: &[&str]| -> Result<Outcome, Box<EvalAltResult>> {
+            let command = MavlinkFTPCommand::from_iter_safe(
+                std::iter::once("mavftp-cli").chain(words.iter().copied()),
+            )
+            .map_err(|error| error.message)?;
+            let (reply, outcome) = mpsc::channel();
+            calls
+                .send(Call::Command(command, reply))
+                .map_err(|_| "the connection is closed")?;
+            Ok(outcome.recv().map_err(|_| "the command did not finish")?)
+        },
+    );
+
+    let mut engine = Engine::new();
+    let ls = call.clone();
+    engine.register_fn(
+        "ls",
+        move |path: &str| -> Result<Array, Box<EvalAltResult>> {
+            Ok(ls(&["list", path])?
+                .entries
+                .into_iter()
+                .map(|entry| Dynamic::from_map(entry_map(entry)))
+                .collect())
+        },
+    );
+    let get = call.clone();
+    engine.register_fn("get", move |path: &str| -> Result<(), Box<EvalAltResult>> {
+        get(&["read", path]).map(|_| ())
+    });
+    let get = call.clone();
+    engine.register_fn(
+        "get",
+        move |path: &str, directory: &str| -> Result<(), Box<EvalAltResult>> {
+            get(&["read", "--output-dir", directory, path]).map(|_| ())
+        },
+    );
+    let put = call.clone();
+    engine.register_fn(
+        "put",
+        move |local: &str, remote: &str| -> Result<(), Box<EvalAltResult>> {
+            put(&["write", local, remote]).map(|_| ())
+        },
+    );
+    let crc = call.clone();
+    engine.register_fn(
+        "crc",
+        move |path: &str| -> Result<i64, Box<EvalAltResult>> {
+            crc(&["crc", path])?
+                .crc
+                .map(i64::from)
+                .ok_or_else(|| "no CRC32 in the reply".into())
+        },
+    );
+    let rm = call;
+    engine.register_fn("rm", move |path: &str| -> Result<(), Box<EvalAltResult>> {
+        rm(&["rm", "--yes", path]).map(|_| ())
+    });
+
+    engine.run_file(path.to_path_buf())
+}
+
+// An entry of ls, as #{name: "00000001.BIN", size: 1024, dir: false}
+fn entry_map(entry: EntryInfo) -> Map {
+    let mut map = Map::new();
+    map.insert("name".into(), entry.name.into());
+    map.insert("size".into(), (entry.size as i64).into());
+    map.insert(
+        "dir".into(),
+        matches!(entry.entry_type, EntryType::Directory).into(),
+    );
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_a_command_for_each_primitive() {
+        let path = std::env::temp_dir().join(format!("mavftp-script-{}.rhai", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            for entry in ls("/APM/LOGS") {
+                if !entry.dir && crc(`/APM/LOGS/${entry.name}`) == 0x1234 {
+                    get(`/APM/LOGS/${entry.name}`, "logs");
+                }
+            }
+            "#,
+        )
+        .unwrap();
+        let mut commands = start(path.clone());
+
+        let (command, reply) = commands.next().unwrap();
+        assert!(
+            matches!(command, MavlinkFTPCommand::ListDirectory { path, .. } if path == "/APM/LOGS")
+        );
+        let entry = |name: &str, entry_type| EntryInfo {
+            entry_type,
+            name: name.into(),
+            raw_name: name.into(),
+            size: 10,
+        };
+        let entries = vec![
+            entry("1.BIN", EntryType::File),
+            entry("old", EntryType::Directory),
+        ];
+        reply.unwrap().send(Outcome { entries, crc: None }).unwrap();
+
+        let (command, reply) = commands.next().unwrap();
+        assert!(
+            matches!(command, MavlinkFTPCommand::CalcFileCRC32 { path } if path == "/APM/LOGS/1.BIN")
+        );
+        let outcome = Outcome {
+            entries: Vec::new(),
+            crc: Some(0x1234),
+        };
+        reply.unwrap().send(outcome).unwrap();
+
+        let (command, reply) = commands.next().unwrap();
+        assert!(matches!(
+            command,
+            MavlinkFTPCommand::ReadFile { paths, output_dir: Some(output_dir), .. }
+                if paths == ["/APM/LOGS/1.BIN"] && output_dir == Path::new("logs")
+        ));
+        let outcome = Outcome {
+            entries: Vec::new(),
+            crc: None,
+        };
+        reply.unwrap().send(outcome).unwrap();
+
+        assert!(commands.next().is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
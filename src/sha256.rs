@@ -0,0 +1,67 @@
+use hmac::{Mac, SimpleHmac};
+use sha2::Digest;
+
+// SHA-256, fed with the chunks as they arrive so files aren't read twice
+#[derive(Default)]
+pub struct Sha256(sha2::Sha256);
+
+impl Sha256 {
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> [u8; 32] {
+        self.0.finalize().into()
+    }
+}
+
+pub fn digest(data: &[u8]) -> [u8; 32] {
+    sha2::Sha256::digest(data).into()
+}
+
+// HMAC-SHA256, S3 signs its requests with it
+pub fn hmac(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac =
+        SimpleHmac::<sha2::Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+pub fn hex(digest: &[u8; 32]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mavftp::MAX_DATA_SIZE;
+
+    #[test]
+    fn computes_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            hex(&hmac(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn sha256_matches_whatever_the_chunks() {
+        assert_eq!(
+            hex(&digest(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(&digest(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        // Chunks as long as the ones read, which don't line up with the 64 byte blocks
+        let data: Vec<u8> = (0..1000).map(|byte| byte as u8).collect();
+        let mut chunked = Sha256::default();
+        for chunk in data.chunks(MAX_DATA_SIZE) {
+            chunked.update(chunk);
+        }
+        assert_eq!(chunked.finish(), digest(&data));
+    }
+}
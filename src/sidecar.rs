@@ -0,0 +1,45 @@
+use std::path::{Path, PathBuf};
+
+use crate::mavftp::mavlink_crc32;
+
+// file.bin gets file.bin.crc32, with the CRC32 and the name of the file as in the sum tools
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".crc32");
+    path.with_file_name(name)
+}
+
+pub fn write(path: &Path, crc: u32) -> std::io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    std::fs::write(sidecar_path(path), format!("{:08x}  {}\n", crc, name))
+}
+
+// None when the file has no sidecar
+pub fn read(path: &Path) -> Option<Result<u32, String>> {
+    let sidecar = sidecar_path(path);
+    let content = std::fs::read_to_string(&sidecar).ok()?;
+    let crc = content.split_whitespace().next().unwrap_or_default();
+    Some(
+        u32::from_str_radix(crc.trim_start_matches("0x"), 16)
+            .map_err(|_| format!("{}: invalid CRC32 {}", sidecar.display(), crc)),
+    )
+}
+
+// Checks a local file against its sidecar, Ok when there is none
+pub fn check(path: &Path) -> Result<(), String> {
+    let Some(expected) = read(path) else {
+        return Ok(());
+    };
+    let expected = expected?;
+    let content = std::fs::read(path).map_err(|error| format!("{}: {}", path.display(), error))?;
+    let crc = mavlink_crc32(&content);
+    if crc != expected {
+        return Err(format!(
+            "{} has CRC32 0x{:08x}, its checksum file says 0x{:08x}",
+            path.display(),
+            crc,
+            expected
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+// The same message is not printed again before this period
+const REPEAT_PERIOD: Duration = Duration::from_secs(5);
+
+pub struct StatusTextPrinter {
+    target_system: u8,
+    last_printed: HashMap<String, SystemTime>,
+}
+
+impl StatusTextPrinter {
+    pub fn new(target_system: u8) -> Self {
+        Self {
+            target_system,
+            last_printed: HashMap::new(),
+        }
+    }
+
+    // Returns the line to be printed for a STATUSTEXT message, if it's not throttled
+    pub fn format(
+        &mut self,
+        header: &mavlink::MavHeader,
+        message: &mavlink::common::MavMessage,
+    ) -> Option<String> {
+        if header.system_id != self.target_system {
+            return None;
+        }
+
+        let mavlink::common::MavMessage::STATUSTEXT(status) = message else {
+            return None;
+        };
+
+        let text: String = status.text.iter().take_while(|&&c| c != '\0').collect();
+        let now = SystemTime::now();
+        if let Some(last) = self.last_printed.get(&text) {
+            if now.duration_since(*last).unwrap_or_default() < REPEAT_PERIOD {
+                return None;
+            }
+        }
+        self.last_printed.insert(text.clone(), now);
+
+        let severity = format!("{:?}", status.severity);
+        Some(format!(
+            "[autopilot] {}: {}",
+            severity.trim_start_matches("MAV_SEVERITY_"),
+            text
+        ))
+    }
+}
@@ -0,0 +1,100 @@
+// Text with {field} placeholders, \t and \n escapes, and {{ and }} for the braces themselves
+#[derive(Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug)]
+enum Part {
+    Text(String),
+    Field(&'static str),
+}
+
+impl Template {
+    // Unknown fields are refused here, so a typo doesn't print empty columns
+    pub fn parse(text: &str, fields: &[&'static str]) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('t') => literal.push('\t'),
+                    Some('n') => literal.push('\n'),
+                    Some('\\') => literal.push('\\'),
+                    Some(other) => return Err(format!("unknown escape \\{}", other)),
+                    None => return Err("lone \\ at the end".into()),
+                },
+                '}' => match chars.next() {
+                    Some('}') => literal.push('}'),
+                    _ => return Err("unmatched }, use }} for a brace".into()),
+                },
+                '{' => {
+                    let rest = chars.as_str();
+                    if let Some(rest) = rest.strip_prefix('{') {
+                        literal.push('{');
+                        chars = rest.chars();
+                        continue;
+                    }
+                    let Some(end) = rest.find('}') else {
+                        return Err("unclosed {, use {{ for a brace".into());
+                    };
+                    let name = &rest[..end];
+                    let Some(field) = fields.iter().find(|field| **field == name) else {
+                        return Err(format!(
+                            "unknown placeholder {{{}}}, use {}",
+                            name,
+                            fields
+                                .iter()
+                                .map(|field| format!("{{{}}}", field))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ));
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(field));
+                    chars = rest[end + 1..].chars();
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Text(literal));
+        }
+        Ok(Self { parts })
+    }
+
+    pub fn render(&self, value: impl Fn(&str) -> String) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Field(field) => value(field),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::LIST_FIELDS;
+
+    #[test]
+    fn parses_list_templates() {
+        let template = Template::parse("{type}\\t{{{name}}}\\n", &LIST_FIELDS).unwrap();
+        assert_eq!(
+            template.render(|field| field.to_uppercase()),
+            "TYPE\t{NAME}\n"
+        );
+
+        assert!(Template::parse("{name", &LIST_FIELDS).is_err());
+        assert!(Template::parse("name}", &LIST_FIELDS).is_err());
+        assert_eq!(
+            Template::parse("{nmae}", &LIST_FIELDS).unwrap_err(),
+            "unknown placeholder {nmae}, use {type}, {name}, {path}, {size}, {bytes}"
+        );
+    }
+}
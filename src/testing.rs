@@ -0,0 +1,793 @@
+// The controller and the mock server wired together in-process, for the tests of the crate and,
+// with the testing feature, those of an embedding; nothing of the binary uses it
+#![cfg_attr(not(test), allow(dead_code))]
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::controller::Controller;
+use crate::link::Message;
+use crate::mavftp::*;
+
+// Time an operation gets to finish against the mock server before the test fails
+const RUN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Length of the payload field of FILE_TRANSFER_PROTOCOL
+const PAYLOAD_SIZE: usize = 251;
+
+// Replies of a burst read, as ArduPilot sends
+const BURST_SIZE: usize = 8;
+
+// Faults of a lossy link on the replies, drawn from a seeded generator so a test meets the same
+// ones on every run
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    // Chances, from 0 to 1, of a reply to be lost, sent twice, or held back behind the next one
+    pub drop: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    // Waited by the loopback before the replies to each request
+    pub latency: Duration,
+    pub seed: u64,
+}
+
+// What the faults did to the replies
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FaultCounts {
+    pub dropped: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+}
+
+// An FTP server keeping its files in memory, answering as a vehicle with a single session
+pub struct MockServer {
+    files: BTreeMap<String, Vec<u8>>,
+    directories: BTreeSet<String>,
+    // Path of the open file and if it was opened for writing
+    open: Option<(String, bool)>,
+    session: u8,
+    // As some vehicles, CalcFileCRC32 is refused
+    pub no_crc32: bool,
+    // A file opened again reports a byte more each time, as a log still being written
+    pub growing: bool,
+    // Times each file was opened for reading
+    opened: BTreeMap<String, u32>,
+    // Requests received, by opcode
+    pub requests: Vec<MavlinkFtpOpcode>,
+    pub faults: Faults,
+    pub fault_counts: FaultCounts,
+    // State of the generator of the faults, taken from their seed on the first one
+    random: u64,
+    // Replies held back, sent after the next one
+    held: Vec<Message>,
+}
+
+impl Default for MockServer {
+    fn default() -> Self {
+        Self {
+            files: BTreeMap::new(),
+            directories: BTreeSet::from(["/".to_string()]),
+            open: None,
+            session: 1,
+            no_crc32: false,
+            growing: false,
+            opened: BTreeMap::new(),
+            requests: Vec::new(),
+            faults: Faults::default(),
+            fault_counts: FaultCounts::default(),
+            random: 0,
+            held: Vec::new(),
+        }
+    }
+}
+
+impl MockServer {
+    // The parent directories are created with it
+    pub fn with_file(mut self, path: &str, content: &[u8]) -> Self {
+        let path = normalize(path);
+        let mut directory = parent(&path);
+        while self.directories.insert(directory.clone()) {
+            directory = parent(&directory);
+        }
+        self.files.insert(path, content.to_vec());
+        self
+    }
+
+    pub fn file(&self, path: &str) -> Option<&Vec<u8>> {
+        self.files.get(&normalize(path))
+    }
+
+    pub fn has_directory(&self, path: &str) -> bool {
+        self.directories.contains(&normalize(path))
+    }
+
+    // The replies to a message as the link gives them, nothing for anything but FTP
+    pub fn reply(&mut self, request: Message) -> Vec<Message> {
+        let Message::Common(mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(request)) = request
+        else {
+            return Vec::new();
+        };
+        // The link zero-extends the payload, reads have a size but no data
+        let mut bytes = request.payload;
+        bytes.resize(PAYLOAD_SIZE, 0);
+        let Ok(payload) = MavlinkFtpPayload::from_bytes(&bytes) else {
+            return Vec::new();
+        };
+        let replies = self.handle(&payload).into_iter().map(|reply| {
+            mavlink::common::MavMessage::FILE_TRANSFER_PROTOCOL(
+                mavlink::common::FILE_TRANSFER_PROTOCOL_DATA {
+                    target_network: 0,
+                    target_system: 0,
+                    target_component: 0,
+                    payload: reply.to_bytes(),
+                },
+            )
+            .into()
+        });
+        let mut sent = Vec::new();
+        for reply in replies.collect::<Vec<Message>>() {
+            if self.chance(self.faults.drop) {
+                self.fault_counts.dropped += 1;
+            } else if self.chance(self.faults.reorder) {
+                self.fault_counts.reordered += 1;
+                self.held.push(reply);
+            } else {
+                if self.chance(self.faults.duplicate) {
+                    self.fault_counts.duplicated += 1;
+                    sent.push(reply.clone());
+                }
+                sent.push(reply);
+                sent.append(&mut self.held);
+            }
+        }
+        sent
+    }
+
+    // Xorshift, enough to spread the faults
+    fn chance(&mut self, chance: f64) -> bool {
+        if chance <= 0.0 {
+            return false;
+        }
+        if self.random == 0 {
+            // Zero would stay zero
+            self.random = self.faults.seed | 1;
+        }
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        (self.random >> 11) as f64 / (1u64 << 53) as f64 <= chance
+    }
+
+    // Replies to a request, several for a burst read
+    fn handle(&mut self, request: &MavlinkFtpPayload) -> Vec<MavlinkFtpPayload> {
+        use MavlinkFtpOpcode::{
+            BurstReadFile, CalcFileCRC32, CreateDirectory, CreateFile, ListDirectory, OpenFileRO,
+            OpenFileWO, ReadFile, RemoveDirectory, RemoveFile, ResetSessions, TerminateSession,
+            WriteFile,
+        };
+        self.requests.push(request.opcode);
+        let path = normalize(&String::from_utf8_lossy(&request.data));
+        let reply = match request.opcode {
+            ResetSessions | TerminateSession => {
+                self.open = None;
+                ack(request, Vec::new())
+            }
+            ListDirectory => return vec![self.list(request, &path)],
+            OpenFileRO | OpenFileWO | CreateFile if self.open.is_some() => {
+                nak(request, MavlinkFtpNak::NoSessionsAvailable)
+            }
+            OpenFileRO | OpenFileWO => match self.files.get(&path) {
+                Some(content) => {
+                    let opened = self.opened.entry(path.clone()).or_default();
+                    let grown = if self.growing { *opened } else { 0 };
+                    *opened += 1;
+                    let size = (content.len() as u32 + grown).to_le_bytes().to_vec();
+                    self.open = Some((path, request.opcode == OpenFileWO));
+                    MavlinkFtpPayload {
+                        session: self.session,
+                        ..ack(
+                            request,
+                            if request.opcode == OpenFileRO {
+                                size
+                            } else {
+                                vec![]
+                            },
+                        )
+                    }
+                }
+                None => nak(request, MavlinkFtpNak::FileNotFound),
+            },
+            CreateFile if !self.directories.contains(&parent(&path)) => {
+                nak(request, MavlinkFtpNak::FileNotFound)
+            }
+            CreateFile => {
+                self.files.insert(path.clone(), Vec::new());
+                self.open = Some((path, true));
+                MavlinkFtpPayload {
+                    session: self.session,
+                    ..ack(request, Vec::new())
+                }
+            }
+            ReadFile | BurstReadFile => return self.read(request),
+            WriteFile => match &self.open {
+                Some((path, true)) if request.session == self.session => {
+                    let content = self.files.get_mut(path).unwrap();
+                    let end = request.offset as usize + request.data.len();
+                    if content.len() < end {
+                        content.resize(end, 0);
+                    }
+                    content[request.offset as usize..end].copy_from_slice(&request.data);
+                    ack(request, Vec::new())
+                }
+                _ => nak(request, MavlinkFtpNak::InvalidSession),
+            },
+            RemoveFile => match self.files.remove(&path) {
+                Some(_) => ack(request, Vec::new()),
+                None => nak(request, MavlinkFtpNak::FileNotFound),
+            },
+            CreateDirectory if self.directories.contains(&path) => {
+                nak(request, MavlinkFtpNak::FileExists)
+            }
+            CreateDirectory if !self.directories.contains(&parent(&path)) => {
+                nak(request, MavlinkFtpNak::FileNotFound)
+            }
+            CreateDirectory => {
+                self.directories.insert(path);
+                ack(request, Vec::new())
+            }
+            RemoveDirectory if self.children(&path).next().is_some() => {
+                nak(request, MavlinkFtpNak::Fail)
+            }
+            RemoveDirectory => match self.directories.remove(&path) {
+                true => ack(request, Vec::new()),
+                false => nak(request, MavlinkFtpNak::FileNotFound),
+            },
+            CalcFileCRC32 if self.no_crc32 => nak(request, MavlinkFtpNak::UnknownCommand),
+            CalcFileCRC32 => match self.files.get(&path) {
+                Some(content) => ack(request, mavlink_crc32(content).to_le_bytes().to_vec()),
+                None => nak(request, MavlinkFtpNak::FileNotFound),
+            },
+            _ => nak(request, MavlinkFtpNak::UnknownCommand),
+        };
+        vec![reply]
+    }
+
+    // Names in the directory, files with their size
+    fn children<'a>(&'a self, directory: &'a str) -> impl Iterator<Item = String> + 'a {
+        let directories = self
+            .directories
+            .iter()
+            .filter(move |path| *path != "/" && parent(path) == directory)
+            .map(|path| format!("D{}\0", name(path)));
+        let files = self
+            .files
+            .iter()
+            .filter(move |(path, _)| parent(path) == directory)
+            .map(|(path, content)| format!("F{}\t{}\0", name(path), content.len()));
+        directories.chain(files)
+    }
+
+    fn list(&self, request: &MavlinkFtpPayload, path: &str) -> MavlinkFtpPayload {
+        if !self.directories.contains(path) {
+            return nak(request, MavlinkFtpNak::FileNotFound);
+        }
+        let mut data = Vec::new();
+        for entry in self.children(path).skip(request.offset as usize) {
+            if data.len() + entry.len() > MAX_DATA_SIZE {
+                break;
+            }
+            data.extend_from_slice(entry.as_bytes());
+        }
+        if data.is_empty() {
+            return nak(request, MavlinkFtpNak::Eof);
+        }
+        ack(request, data)
+    }
+
+    fn read(&self, request: &MavlinkFtpPayload) -> Vec<MavlinkFtpPayload> {
+        let content = match &self.open {
+            Some((path, false)) if request.session == self.session => &self.files[path],
+            _ => return vec![nak(request, MavlinkFtpNak::InvalidSession)],
+        };
+        if request.offset as usize >= content.len() {
+            return vec![nak(request, MavlinkFtpNak::Eof)];
+        }
+        let count = match request.opcode {
+            MavlinkFtpOpcode::BurstReadFile => BURST_SIZE,
+            _ => 1,
+        };
+        let size = request.size.clamp(1, MAX_DATA_SIZE);
+        let mut replies: Vec<MavlinkFtpPayload> = (0..count)
+            .map_while(|index| {
+                let start = request.offset as usize + index * size;
+                let chunk = content.get(start..(start + size).min(content.len()))?;
+                (!chunk.is_empty()).then(|| MavlinkFtpPayload {
+                    seq_number: request.seq_number.wrapping_add(1 + index as u16),
+                    offset: start as u32,
+                    ..ack(request, chunk.to_vec())
+                })
+            })
+            .collect();
+        if let Some(last) = replies.last_mut() {
+            last.burst_complete = 1;
+        }
+        replies
+    }
+}
+
+fn ack(request: &MavlinkFtpPayload, data: Vec<u8>) -> MavlinkFtpPayload {
+    MavlinkFtpPayload {
+        seq_number: request.seq_number.wrapping_add(1),
+        session: request.session,
+        opcode: MavlinkFtpOpcode::Ack,
+        size: data.len(),
+        req_opcode: request.opcode,
+        burst_complete: 0,
+        padding: 0,
+        offset: request.offset,
+        data,
+    }
+}
+
+fn nak(request: &MavlinkFtpPayload, code: MavlinkFtpNak) -> MavlinkFtpPayload {
+    MavlinkFtpPayload {
+        opcode: MavlinkFtpOpcode::Nak,
+        ..ack(request, vec![code as u8])
+    }
+}
+
+// Absolute, without a trailing slash or the NUL some paths end with
+fn normalize(path: &str) -> String {
+    let parts: Vec<_> = path
+        .trim_end_matches('\0')
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect();
+    format!("/{}", parts.join("/"))
+}
+
+fn parent(path: &str) -> String {
+    match path.rsplit_once('/') {
+        Some(("", _)) | None => "/".to_string(),
+        Some((parent, _)) => parent.to_string(),
+    }
+}
+
+fn name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+// An empty directory of the test, unique to the process
+pub fn temp_dir(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("mavftp-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
+
+// A controller and a mock server wired over channels instead of a MAVLink link, the server
+// answering on a thread of its own
+pub struct Loopback {
+    pub controller: Controller,
+    server: Arc<Mutex<MockServer>>,
+    requests: mpsc::Sender<Message>,
+    replies: mpsc::Receiver<Message>,
+}
+
+impl Loopback {
+    pub fn new(server: MockServer) -> Self {
+        let server = Arc::new(Mutex::new(server));
+        let (requests, incoming) = mpsc::channel();
+        let (outgoing, replies) = mpsc::channel();
+        std::thread::spawn({
+            let server = server.clone();
+            move || {
+                for request in incoming {
+                    let (replies, latency) = {
+                        let mut server = server.lock().unwrap();
+                        (server.reply(request), server.faults.latency)
+                    };
+                    std::thread::sleep(latency);
+                    for reply in replies {
+                        if outgoing.send(reply).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        Self {
+            controller: Controller::new(1, 1),
+            server,
+            requests,
+            replies,
+        }
+    }
+
+    pub fn server(&self) -> MutexGuard<'_, MockServer> {
+        self.server.lock().unwrap()
+    }
+
+    // Runs the operation started on the controller until it's done, a failure ends the process
+    // as it ends an invocation
+    pub fn run(&mut self) {
+        let deadline = Instant::now() + RUN_TIMEOUT;
+        while !self.controller.take_finished() {
+            assert!(Instant::now() < deadline, "the operation did not finish");
+            for request in self.controller.run() {
+                self.requests.send(request).unwrap();
+            }
+            let wait = self
+                .controller
+                .timeout()
+                .unwrap_or(Duration::from_millis(10))
+                .min(deadline.saturating_duration_since(Instant::now()));
+            match self.replies.recv_timeout(wait) {
+                Ok(reply) => {
+                    if let Some(request) = self.controller.parse_mavlink_message(&reply) {
+                        self.requests.send(request).unwrap();
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => panic!("the mock server stopped"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::{ListOptions, ReadOptions};
+    use crate::receipt::VerifyMode;
+
+    #[test]
+    fn downloads_a_file() {
+        let content: Vec<u8> = (0..5000).map(|index| index as u8).collect();
+        let mut loopback =
+            Loopback::new(MockServer::default().with_file("/APM/LOGS/00000001.BIN", &content));
+        let output_dir = temp_dir("loopback-read");
+        loopback.controller.read_file(
+            "/APM/LOGS/00000001.BIN".into(),
+            ReadOptions {
+                output_dir: Some(output_dir.clone()),
+                ..Default::default()
+            },
+        );
+        loopback.run();
+
+        assert_eq!(
+            std::fs::read(output_dir.join("00000001.BIN")).unwrap(),
+            content
+        );
+        // The download is verified with the CRC32 of the vehicle
+        assert!(loopback
+            .server()
+            .requests
+            .contains(&MavlinkFtpOpcode::CalcFileCRC32));
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    fn downloads_several_files_in_one_read() {
+        let mut loopback = Loopback::new(
+            MockServer::default()
+                .with_file("/APM/LOGS/1.BIN", &[1; 600])
+                .with_file("/APM/LOGS/2.BIN", b"")
+                .with_file("/APM/config.txt", b"config"),
+        );
+        let output_dir = temp_dir("loopback-read-many");
+        loopback.controller.read_files(
+            vec![
+                "/APM/LOGS/1.BIN".into(),
+                "/APM/LOGS/2.BIN".into(),
+                "/APM/config.txt".into(),
+            ],
+            ReadOptions {
+                output_dir: Some(output_dir.clone()),
+                ..Default::default()
+            },
+        );
+        loopback.run();
+
+        assert_eq!(std::fs::read(output_dir.join("1.BIN")).unwrap(), [1; 600]);
+        assert_eq!(std::fs::read(output_dir.join("2.BIN")).unwrap(), b"");
+        assert_eq!(
+            std::fs::read(output_dir.join("config.txt")).unwrap(),
+            b"config"
+        );
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    // The receipt of the read, or the panic of the failed one
+    fn read_without_crc32(
+        verify: Option<VerifyMode>,
+        growing: bool,
+    ) -> (std::path::PathBuf, std::thread::Result<String>) {
+        let server = MockServer {
+            no_crc32: true,
+            growing,
+            ..MockServer::default()
+        };
+        let mut loopback = Loopback::new(
+            server
+                .with_file("/APM/LOGS/1.BIN", &[1; 300])
+                .with_file("/APM/LOGS/2.BIN", &[2; 300]),
+        );
+        let output_dir = temp_dir(&format!("loopback-no-crc32-{:?}-{}", verify, growing));
+        let receipt = output_dir.join("receipt.tsv");
+        loopback.controller.set_receipt(Some(receipt.clone()));
+        loopback.controller.set_verify(verify);
+        loopback.controller.read_files(
+            vec!["/APM/LOGS/1.BIN".into(), "/APM/LOGS/2.BIN".into()],
+            ReadOptions {
+                output_dir: Some(output_dir.clone()),
+                ..Default::default()
+            },
+        );
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| loopback.run()))
+            .map(|()| std::fs::read_to_string(receipt).unwrap());
+        (output_dir, result)
+    }
+
+    fn results(receipt: &str) -> Vec<&str> {
+        receipt
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.split('\t').nth(5).unwrap())
+            .collect()
+    }
+
+    fn panic_message(error: Box<dyn std::any::Any + Send>) -> String {
+        error.downcast::<String>().map(|message| *message).unwrap()
+    }
+
+    #[test]
+    fn checks_the_size_when_the_vehicle_has_no_crc32() {
+        let (output_dir, result) = read_without_crc32(None, false);
+        assert_eq!(results(&result.unwrap()), ["size ok", "size ok"]);
+        assert_eq!(std::fs::read(output_dir.join("1.BIN")).unwrap(), [1; 300]);
+        assert_eq!(std::fs::read(output_dir.join("2.BIN")).unwrap(), [2; 300]);
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    fn fails_when_the_size_on_the_vehicle_changed() {
+        let (output_dir, result) = read_without_crc32(None, true);
+        assert_eq!(panic_message(result.unwrap_err()), "exited with code 1");
+        // The copies are kept, only their check failed
+        assert_eq!(std::fs::read(output_dir.join("1.BIN")).unwrap(), [1; 300]);
+        assert_eq!(std::fs::read(output_dir.join("2.BIN")).unwrap(), [2; 300]);
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    fn fails_without_crc32_when_it_was_asked_for() {
+        let (output_dir, result) = read_without_crc32(Some(VerifyMode::Crc32), false);
+        assert_eq!(panic_message(result.unwrap_err()), "exited with code 1");
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    fn downloads_a_tree_with_an_empty_file() {
+        let mut loopback = Loopback::new(
+            MockServer::default()
+                .with_file("/APM/scripts/empty.lua", b"")
+                .with_file("/APM/scripts/hello.lua", b"print('hello')"),
+        );
+        let output_dir = temp_dir("loopback-tree");
+        loopback.controller.read_tree(
+            "/APM/scripts".into(),
+            ReadOptions {
+                output_dir: Some(output_dir.clone()),
+                ..Default::default()
+            },
+        );
+        loopback.run();
+
+        let scripts = output_dir.join("scripts");
+        assert_eq!(std::fs::read(scripts.join("empty.lua")).unwrap(), b"");
+        assert_eq!(
+            std::fs::read(scripts.join("hello.lua")).unwrap(),
+            b"print('hello')"
+        );
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    fn asks_for_the_crc32_while_the_next_file_is_read() {
+        let mut loopback = Loopback::new(
+            MockServer::default()
+                .with_file("/APM/LOGS/1.BIN", &[1; 2000])
+                .with_file("/APM/LOGS/2.BIN", &[2; 2000])
+                .with_file("/APM/LOGS/3.BIN", &[3; 2000]),
+        );
+        let output_dir = temp_dir("loopback-overlap");
+        loopback.controller.read_tree(
+            "/APM/LOGS".into(),
+            ReadOptions {
+                output_dir: Some(output_dir.clone()),
+                ..Default::default()
+            },
+        );
+        loopback.run();
+
+        // The CRC32 of the first two files went out before the last one was opened
+        let requests = &loopback.server().requests;
+        let last_open = requests
+            .iter()
+            .rposition(|opcode| *opcode == MavlinkFtpOpcode::OpenFileRO)
+            .unwrap();
+        let crcs = |requests: &[MavlinkFtpOpcode]| {
+            requests
+                .iter()
+                .filter(|opcode| **opcode == MavlinkFtpOpcode::CalcFileCRC32)
+                .count()
+        };
+        assert!(crcs(&requests[..last_open]) >= 1);
+        assert_eq!(crcs(requests), 3);
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn runs_the_hook_of_each_uploaded_file() {
+        let output_dir = temp_dir("loopback-write-hook");
+        let log = output_dir.join("hook.log");
+        let locals: Vec<_> = (1..=3)
+            .map(|index| {
+                let local = output_dir.join(format!("{}.lua", index));
+                std::fs::write(&local, vec![index as u8; 600]).unwrap();
+                local
+            })
+            .collect();
+        let mut loopback =
+            Loopback::new(MockServer::default().with_file("/APM/scripts/old.lua", b""));
+        let hook = format!("sh -c 'echo {{remote}} {{crc}} >> {}'", log.display());
+        loopback
+            .controller
+            .set_exec_after(Some(hook.parse().unwrap()));
+        loopback.controller.upload(locals, "/APM/scripts".into());
+        loopback.run();
+
+        let log = std::fs::read_to_string(log).unwrap();
+        let remotes: Vec<_> = log
+            .lines()
+            .map(|line| line.split(' ').next().unwrap())
+            .collect();
+        assert_eq!(
+            remotes,
+            [
+                "/APM/scripts/1.lua",
+                "/APM/scripts/2.lua",
+                "/APM/scripts/3.lua"
+            ]
+        );
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    fn uploads_files() {
+        let output_dir = temp_dir("loopback-write");
+        let local = output_dir.join("hello.lua");
+        std::fs::write(&local, b"print('hello')").unwrap();
+        let mut loopback =
+            Loopback::new(MockServer::default().with_file("/APM/scripts/old.lua", b""));
+        loopback.controller.set_force(true);
+        loopback
+            .controller
+            .upload(vec![local], "/APM/scripts/hello.lua".into());
+        loopback.run();
+
+        assert_eq!(
+            loopback.server().file("/APM/scripts/hello.lua").unwrap(),
+            b"print('hello')"
+        );
+        std::fs::remove_dir_all(output_dir).unwrap();
+    }
+
+    #[test]
+    fn lists_creates_and_removes() {
+        let mut loopback = Loopback::new(MockServer::default().with_file("/APM/a.txt", b"a"));
+        loopback
+            .controller
+            .create_directory("/APM/new/deep".into(), true);
+        loopback.run();
+        assert!(loopback.server().has_directory("/APM/new/deep"));
+
+        // The entries come back as to a script
+        let (reply, outcome) = mpsc::channel();
+        loopback.controller.set_script_reply(Some(reply));
+        loopback
+            .controller
+            .list_directory("/APM".into(), ListOptions::default());
+        loopback.run();
+        let entries: Vec<_> = outcome
+            .recv()
+            .unwrap()
+            .entries
+            .into_iter()
+            .map(|entry| (entry.name, entry.entry_type, entry.size))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("a.txt".to_string(), EntryType::File, 1),
+                ("new".to_string(), EntryType::Directory, 0)
+            ]
+        );
+
+        loopback.controller.remove("/APM/a.txt".into(), false, true);
+        loopback.run();
+        assert!(loopback.server().file("/APM/a.txt").is_none());
+    }
+
+    // A read through the faults of a lossy link comes out whole
+    fn read_through(faults: Faults, name: &str) -> FaultCounts {
+        let content: Vec<u8> = (0..5000).map(|index| (index * 7) as u8).collect();
+        let server = MockServer {
+            faults,
+            ..MockServer::default()
+        };
+        let mut loopback = Loopback::new(server.with_file("/APM/LOGS/1.BIN", &content));
+        let output_dir = temp_dir(name);
+        loopback.controller.read_files(
+            vec!["/APM/LOGS/1.BIN".into()],
+            ReadOptions {
+                output_dir: Some(output_dir.clone()),
+                ..Default::default()
+            },
+        );
+        loopback.run();
+        assert_eq!(std::fs::read(output_dir.join("1.BIN")).unwrap(), content);
+        std::fs::remove_dir_all(output_dir).unwrap();
+        let counts = loopback.server().fault_counts;
+        counts
+    }
+
+    #[test]
+    fn reads_through_lost_replies() {
+        let faults = Faults {
+            drop: 0.1,
+            seed: 1,
+            ..Default::default()
+        };
+        assert!(read_through(faults, "loopback-drop").dropped > 0);
+    }
+
+    #[test]
+    fn reads_through_duplicated_replies() {
+        let faults = Faults {
+            duplicate: 0.3,
+            seed: 2,
+            ..Default::default()
+        };
+        assert!(read_through(faults, "loopback-duplicate").duplicated > 0);
+    }
+
+    #[test]
+    fn reads_through_reordered_replies() {
+        let faults = Faults {
+            reorder: 0.3,
+            seed: 3,
+            ..Default::default()
+        };
+        assert!(read_through(faults, "loopback-reorder").reordered > 0);
+    }
+
+    #[test]
+    fn reads_through_a_slow_lossy_link() {
+        let faults = Faults {
+            drop: 0.05,
+            duplicate: 0.1,
+            reorder: 0.1,
+            latency: Duration::from_millis(20),
+            seed: 4,
+        };
+        let started = Instant::now();
+        let counts = read_through(faults, "loopback-lossy");
+        assert!(counts.dropped + counts.duplicated + counts.reordered > 0);
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+}